@@ -0,0 +1,182 @@
+//! Throughput of the per tx parse and transform path, over the sample
+//! blocks in `tests/fixtures`. Nothing here touches the node or the db.
+//!
+//! Run with `cargo bench`, or `cargo bench -- <filter>` for a subset.
+
+use chain_analyzer::dfiutils::{self, Network, UtxoCache};
+use chain_analyzer::models::{Block, TxType};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::collections::HashSet;
+
+const FIXTURES: [&str; 4] = [
+    include_str!("../tests/fixtures/block_coinbase.json"),
+    include_str!("../tests/fixtures/block_poolswap.json"),
+    include_str!("../tests/fixtures/block_icx_claim.json"),
+    include_str!("../tests/fixtures/block_account_to_account.json"),
+];
+
+fn load_blocks() -> Vec<Block> {
+    FIXTURES
+        .iter()
+        .map(|json| serde_json::from_str(json).unwrap())
+        .collect()
+}
+
+fn bench_block_parse(c: &mut Criterion) {
+    c.bench_function("block_parse", |b| {
+        b.iter(|| {
+            for json in FIXTURES {
+                black_box(serde_json::from_str::<Block>(black_box(json)).unwrap());
+            }
+        })
+    });
+}
+
+fn bench_tx_type(c: &mut Criterion) {
+    let blocks = load_blocks();
+    let types = blocks
+        .iter()
+        .flat_map(|b| b.tx.iter())
+        .filter_map(|tx| tx.vm.as_ref().map(|vm| vm.txtype.clone()))
+        .collect::<Vec<_>>();
+    c.bench_function("tx_type_from", |b| {
+        b.iter(|| {
+            for t in &types {
+                black_box(TxType::from(black_box(&**t)));
+            }
+        })
+    });
+}
+
+fn bench_extract_dfi_addresses(c: &mut Criterion) {
+    let blocks = load_blocks();
+    let msgs = blocks
+        .iter()
+        .flat_map(|b| b.tx.iter())
+        .filter_map(|tx| tx.vm.as_ref().map(|vm| vm.msg.to_string()))
+        .collect::<Vec<_>>();
+    c.bench_function("extract_all_dfi_addresses", |b| {
+        b.iter(|| {
+            for msg in &msgs {
+                black_box(dfiutils::extract_all_dfi_addresses(
+                    black_box(msg),
+                    Network::Mainnet,
+                ));
+            }
+        })
+    });
+}
+
+fn bench_txout_addr_val_list(c: &mut Criterion) {
+    let blocks = load_blocks();
+    let txs = blocks.iter().flat_map(|b| b.tx.iter()).collect::<Vec<_>>();
+    c.bench_function("get_txout_addr_val_list", |b| {
+        b.iter(|| {
+            for tx in &txs {
+                black_box(dfiutils::get_txout_addr_val_list(tx, &tx.vout, None));
+            }
+        })
+    });
+    let cache = UtxoCache::new(1_000_000);
+    c.bench_function("get_txout_addr_val_list_cached", |b| {
+        b.iter(|| {
+            for tx in &txs {
+                black_box(dfiutils::get_txout_addr_val_list(
+                    tx,
+                    &tx.vout,
+                    Some(&cache),
+                ));
+            }
+        })
+    });
+}
+
+fn bench_edge_changeset(c: &mut Criterion) {
+    let blocks = load_blocks();
+    // The prior outputs aren't in the fixtures, so the outs of each tx
+    // stand in for its ins.
+    let inputs = blocks
+        .iter()
+        .flat_map(|b| b.tx.iter())
+        .map(|tx| {
+            let outs = dfiutils::get_txout_addr_val_list(tx, &tx.vout, None);
+            let outs = dfiutils::fold_addr_val_map(&dfiutils::addr_txouts(&outs));
+            let dvm_addrs = match &tx.vm {
+                Some(vm) => {
+                    dfiutils::extract_all_dfi_addresses(&vm.msg.to_string(), Network::Mainnet)
+                }
+                None => HashSet::new(),
+            };
+            let dvm_in_addrs = dvm_addrs
+                .iter()
+                .filter(|x| outs.contains_key(*x))
+                .cloned()
+                .collect::<Vec<_>>();
+            (outs, dvm_in_addrs, dvm_addrs)
+        })
+        .collect::<Vec<_>>();
+    let dvm_tokens = ["dfi".to_owned(), "btc".to_owned()];
+    c.bench_function("edge_changeset", |b| {
+        b.iter(|| {
+            for (outs, dvm_in_addrs, dvm_addrs) in &inputs {
+                black_box(dfiutils::edge_changeset(
+                    outs,
+                    outs,
+                    dvm_in_addrs,
+                    dvm_addrs,
+                    "dfi",
+                    &dvm_tokens,
+                ));
+            }
+        })
+    });
+}
+
+// The json columns of the tx rows, as a `String` each and written into
+// the one reused buffer.
+fn bench_tx_json_cols(c: &mut Criterion) {
+    let blocks = load_blocks();
+    let rows = blocks
+        .iter()
+        .flat_map(|b| b.tx.iter())
+        .map(|tx| {
+            let outs = dfiutils::get_txout_addr_val_list(tx, &tx.vout, None);
+            let types = outs.iter().map(|x| x.2).collect::<Vec<_>>();
+            let outs = dfiutils::fold_addr_val_map(&dfiutils::addr_txouts(&outs));
+            (tx, outs, types)
+        })
+        .collect::<Vec<_>>();
+    c.bench_function("tx_json_cols_to_string", |b| {
+        b.iter(|| {
+            for (tx, outs, types) in &rows {
+                black_box(serde_json::to_string(outs).unwrap());
+                black_box(serde_json::to_string(types).unwrap());
+                black_box(serde_json::to_string(tx).unwrap());
+            }
+        })
+    });
+    let mut cols = dfiutils::JsonCols::default();
+    c.bench_function("tx_json_cols_reused", |b| {
+        b.iter(|| {
+            for (tx, outs, types) in &rows {
+                cols.clear();
+                let outs = cols.push(Some(outs)).unwrap();
+                let types = cols.push(Some(types)).unwrap();
+                let tx = cols.push(Some(tx)).unwrap();
+                black_box((cols.get(outs).unwrap(), cols.get(types).unwrap()));
+                black_box(cols.get(tx).unwrap());
+            }
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_block_parse,
+    bench_tx_type,
+    bench_extract_dfi_addresses,
+    bench_txout_addr_val_list,
+    bench_edge_changeset,
+    bench_tx_json_cols
+);
+criterion_main!(benches);
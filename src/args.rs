@@ -1,7 +1,7 @@
-use crate::lang::Result;
-use clap::{Parser, Subcommand};
+use crate::lang::{OptionExt, Result};
+use clap::{CommandFactory, Parser, Subcommand};
 use std::{io::BufRead, sync::LazyLock};
-use tracing::Level;
+use tracing::level_filters::LevelFilter;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None, propagate_version=true, next_line_help(true))]
@@ -17,18 +17,89 @@ pub struct Args {
     /// Minimum might be pulled higher.
     #[arg(global = true, short, long, action = clap::ArgAction::Count, verbatim_doc_comment)]
     pub verbosity: u8,
+    /// Only log errors, eg. for scripts. Given twice, log nothing.
+    #[arg(global = true, short, long, action = clap::ArgAction::Count, conflicts_with = "verbosity")]
+    pub quiet: u8,
+    /// Config file (toml, or json with a .json extension) with the args.
+    /// Top level keys are the global args, and each command has a table
+    /// of its own with the arg names as keys, eg. `[cindex] sqlite_path = ".."`,
+    /// nested for the nested commands, eg. `[export.csv]`.
+    /// Every arg can also be set with a `DINDEXER_` prefixed env var,
+    /// eg. `DINDEXER_SQLITE_PATH`. Precedence:
+    /// flag > env > config file > default.
+    #[arg(global = true, long, verbatim_doc_comment)]
+    pub config: Option<String>,
+    /// Log output format. `json` has a line per event, with the fields
+    /// (height, rate, error, ..) as keys, for log aggregators.
+    #[arg(global = true, long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// Also write the logs to this file, rotated by `--log-rotation`.
+    /// The rotated files get the date (and hour) as a suffix.
+    #[arg(global = true, long)]
+    pub log_file: Option<String>,
+    #[arg(global = true, long, value_enum, default_value_t = LogRotation::Daily)]
+    pub log_rotation: LogRotation,
+    /// With `--log-file`, only write the logs to the file.
+    #[arg(global = true, long, requires = "log_file")]
+    pub no_console_log: bool,
+    /// Log the progress lines instead of a live progress bar. The bar is
+    /// only shown on a terminal, without `--log-file`, `-q` or json logs.
+    #[arg(global = true, long)]
+    pub no_progress_bar: bool,
     #[command(subcommand)]
     pub command: Cmd,
 }
 
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogRotation {
+    Hourly,
+    Daily,
+    Never,
+}
+
 #[derive(Subcommand, Debug)]
 pub enum Cmd {
     /// Index from cli sqlite db
-    #[command(name = "cindex")]
+    #[command(name = "cindex", visible_alias = "index")]
     CliIndex(crate::cliindexer::CliIndexArgs),
+    /// Index up to the chain tip, then keep indexing the new blocks as
+    /// they come in. Reorgs are rolled back as usual
+    #[command(name = "follow")]
+    Follow(crate::cliindexer::FollowArgs),
+    /// Check the index db for missing heights and txs without a block
+    #[command(name = "verify")]
+    Verify(crate::cliindexer::VerifyArgs),
+    /// Print the height range, rows per table and last run of the index
+    /// db, and whether it's contiguous. Exits non-zero when it isn't, or
+    /// the db can't be read
+    #[command(name = "status")]
+    Status(crate::cliindexer::StatusArgs),
+    /// Rebuild the balances table of a sqlite index db: the unspent outs
+    /// and dvm account balances of each address, as of its last height
+    #[command(name = "balances")]
+    Balances(crate::cliindexer::BalancesArgs),
+    /// Fetch a tx and its block from the node, run it through the
+    /// indexer and print the resulting rows as json, without writing
+    #[command(name = "reprocess-tx")]
+    ReprocessTx(crate::cliindexer::ReprocessTxArgs),
+    /// Print the stored txs touching an address as json
+    #[command(name = "query")]
+    Query(crate::cliindexer::QueryArgs),
     /// Index / transform / migrate from src sqlite db to dest db
     #[command(name = "sindex")]
     SqliteIndex(crate::sqliteindex::SqliteIndexArgs),
+    /// Delete all rows in a height range, eg. to re-index it
+    #[command(name = "prune")]
+    Prune(crate::prune::PruneArgs),
+    /// Export the index db to csv, parquet or a graph file
+    #[command(name = "export", subcommand)]
+    Export(ExportCmd),
     /// Reduce dot graph files
     #[command(name = "dotreduce")]
     DotReduce {
@@ -67,24 +138,293 @@ pub enum Cmd {
     LogParseCheck(crate::logparse::LogParseArgs),
 }
 
-pub fn verbosity_to_level(verbosity: u8, min: Option<u8>) -> Level {
+#[derive(Subcommand, Debug)]
+pub enum ExportCmd {
+    /// Export the txs table to csv
+    #[command(name = "csv")]
+    Csv(crate::csvexport::CsvExportArgs),
+    /// Export the blocks and txs tables to partitioned parquet
+    #[cfg(feature = "parquet")]
+    #[command(name = "parquet")]
+    Parquet(crate::pqexport::ParquetExportArgs),
+    /// Stream the graph table to GraphML or a csv edge list, eg. to
+    /// load it into gephi or networkx
+    #[command(name = "graph")]
+    Graph(crate::graphexport::GraphExportArgs),
+}
+
+/// Start height that can either be explicit or resolved from the
+/// current state of the store with `auto`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartHeight {
+    Auto,
+    Height(i64),
+}
+
+impl std::str::FromStr for StartHeight {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(StartHeight::Auto),
+            _ => s
+                .parse::<i64>()
+                .map(StartHeight::Height)
+                .map_err(|e| format!("expected a height or `auto`: {}", e)),
+        }
+    }
+}
+
+/// A point in time as unix seconds, from a UTC date `2023-01-01`, a date
+/// and time `2023-01-01T12:00:00Z` or the unix seconds themselves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp(pub i64);
+
+impl std::str::FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(secs) = s.parse::<i64>() {
+            return Ok(Timestamp(secs));
+        }
+        let err = || {
+            format!(
+                "expected a date like 2023-01-01, optionally with T12:00:00Z, or unix seconds: {}",
+                s
+            )
+        };
+        let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, "00:00:00"));
+        let num = |x: &str| x.parse::<i64>().map_err(|_| err());
+        let [y, m, d] = date.splitn(3, '-').collect::<Vec<_>>()[..] else {
+            return Err(err());
+        };
+        let (y, m, d) = (num(y)?, num(m)?, num(d)?);
+        let time = time.strip_suffix('Z').unwrap_or(time);
+        let [hh, mm, ss] = time.splitn(3, ':').collect::<Vec<_>>()[..] else {
+            return Err(err());
+        };
+        let (hh, mm, ss) = (num(hh)?, num(mm)?, num(ss)?);
+        if !(1..=12).contains(&m) || !(1..=31).contains(&d) || hh > 23 || mm > 59 || ss > 60 {
+            return Err(err());
+        }
+        Ok(Timestamp(
+            days_from_civil(y, m, d) * 86_400 + hh * 3600 + mm * 60 + ss,
+        ))
+    }
+}
+
+/// Days since 1970-01-01 of the proleptic gregorian date, after Howard
+/// Hinnant's `days_from_civil`.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// `quiet` takes precedence over the verbosity and its minimum: errors
+/// only once, nothing at all twice.
+pub fn verbosity_to_level(verbosity: u8, min: Option<u8>, quiet: u8) -> LevelFilter {
+    match quiet {
+        0 => {}
+        1 => return LevelFilter::ERROR,
+        _ => return LevelFilter::OFF,
+    }
     let m = min.unwrap_or(0);
     let v = if verbosity < m { m } else { verbosity };
     match v {
-        0 => Level::ERROR,
-        1 => Level::WARN,
-        2 => Level::INFO,
-        3 => Level::DEBUG,
-        4 => Level::TRACE,
-        _ => Level::TRACE,
+        0 => LevelFilter::ERROR,
+        1 => LevelFilter::WARN,
+        2 => LevelFilter::INFO,
+        3 => LevelFilter::DEBUG,
+        4 => LevelFilter::TRACE,
+        _ => LevelFilter::TRACE,
     }
 }
 
 pub fn get_args() -> &'static Args {
-    static ARGS: LazyLock<Args> = LazyLock::new(Args::parse);
+    static ARGS: LazyLock<Args> = LazyLock::new(|| {
+        let argv = std::env::args().collect::<Vec<_>>();
+        match merge_env_and_config_args(argv) {
+            Ok(argv) => Args::parse_from(argv),
+            Err(e) => {
+                clap::Error::raw(clap::error::ErrorKind::InvalidValue, format!("{}\n", e)).exit()
+            }
+        }
+    });
     &ARGS
 }
 
+/// Splice in the args from the env and the config file. They're placed
+/// before the ones given on the command line, so that the latter win for
+/// the args that only take one value. The env takes precedence over the
+/// config file, so the order is: flag > env > config file > default.
+fn merge_env_and_config_args(mut argv: Vec<String>) -> Result<Vec<String>> {
+    let config = match find_config_path(&argv) {
+        Some(path) => load_config(&path)?,
+        None => serde_json::Map::new(),
+    };
+
+    let cmd = Args::command();
+    let sub_pos = argv
+        .iter()
+        .skip(1)
+        .position(|a| cmd.find_subcommand(a).is_some())
+        .map(|i| i + 1);
+
+    for key in config.keys() {
+        if cmd.find_subcommand(key).is_none() && !is_known_arg(&cmd, key) {
+            return Err(format!("config: unknown key `{}`", key).into());
+        }
+    }
+    let global_args = env_and_config_to_args(&cmd, &config)?;
+
+    if let Some(mut at) = sub_pos {
+        // Only the command that's being run is of interest. The table of
+        // a nested one is nested too, eg. `[export.csv]`.
+        let empty = serde_json::Map::new();
+        let mut sub = cmd.find_subcommand(&argv[at]).ok_or_err()?;
+        let mut name = sub.get_name().to_owned();
+        let mut table = config_table(&config, sub.get_name(), &name, &empty)?;
+        while let Some(next) = argv.get(at + 1).and_then(|a| sub.find_subcommand(a)) {
+            at += 1;
+            sub = next;
+            name = format!("{}.{}", name, sub.get_name());
+            table = config_table(table, sub.get_name(), &name, &empty)?;
+        }
+        for key in table.keys() {
+            if !is_known_arg(sub, key) && sub.find_subcommand(key).is_none() {
+                return Err(format!("config: unknown key `{}` for `{}`", key, name).into());
+            }
+        }
+        let sub_args = env_and_config_to_args(sub, table)?;
+        argv.splice(at + 1..at + 1, sub_args);
+    }
+    argv.splice(1..1, global_args);
+    Ok(argv)
+}
+
+fn config_table<'a>(
+    config: &'a serde_json::Map<String, serde_json::Value>,
+    key: &str,
+    name: &str,
+    empty: &'a serde_json::Map<String, serde_json::Value>,
+) -> Result<&'a serde_json::Map<String, serde_json::Value>> {
+    match config.get(key) {
+        Some(serde_json::Value::Object(v)) => Ok(v),
+        Some(_) => Err(format!("config: `{}` has to be a table", name).into()),
+        None => Ok(empty),
+    }
+}
+
+fn load_config(path: &str) -> Result<serde_json::Map<String, serde_json::Value>> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("config: {}: {}", path, e))?;
+    let config = if path.ends_with(".json") {
+        serde_json::from_str(&content)?
+    } else {
+        toml::from_str(&content).map_err(|e| format!("config: {}: {}", path, e))?
+    };
+    Ok(config)
+}
+
+fn is_known_arg(cmd: &clap::Command, key: &str) -> bool {
+    cmd.get_arguments()
+        .any(|a| a.get_id() == key && a.get_long().is_some())
+}
+
+/// Env var for an arg, eg. `DINDEXER_SQLITE_PATH` for `sqlite_path`.
+fn arg_env_var(id: &str) -> String {
+    format!("DINDEXER_{}", id.to_uppercase())
+}
+
+fn env_and_config_to_args(
+    cmd: &clap::Command,
+    config: &serde_json::Map<String, serde_json::Value>,
+) -> Result<Vec<String>> {
+    let mut out = Vec::new();
+    for arg in cmd.get_arguments() {
+        let id = arg.get_id().as_str();
+        let long = match arg.get_long() {
+            Some(v) if id != "config" => v,
+            _ => continue,
+        };
+        if let Ok(v) = std::env::var(arg_env_var(id)) {
+            let v = env_value_to_json(arg.get_action(), &v);
+            arg_value_to_args(arg.get_action(), long, id, &v, &mut out)?;
+        } else if let Some(v) = config.get(id) {
+            arg_value_to_args(arg.get_action(), long, id, v, &mut out)?;
+        }
+    }
+    Ok(out)
+}
+
+fn env_value_to_json(action: &clap::ArgAction, value: &str) -> serde_json::Value {
+    use clap::ArgAction;
+    use serde_json::Value;
+    match action {
+        ArgAction::SetTrue => Value::Bool(matches!(value, "1" | "true" | "yes")),
+        ArgAction::Count => Value::from(value.parse::<u64>().unwrap_or_default()),
+        _ => Value::String(value.to_owned()),
+    }
+}
+
+fn find_config_path(argv: &[String]) -> Option<String> {
+    let mut it = argv.iter();
+    while let Some(a) = it.next() {
+        if a == "--config" {
+            return it.next().cloned();
+        }
+        if let Some(v) = a.strip_prefix("--config=") {
+            return Some(v.to_owned());
+        }
+    }
+    None
+}
+
+fn arg_value_to_args(
+    action: &clap::ArgAction,
+    long: &str,
+    key: &str,
+    value: &serde_json::Value,
+    out: &mut Vec<String>,
+) -> Result<()> {
+    use clap::ArgAction;
+    use serde_json::Value;
+
+    let flag = format!("--{}", long);
+    match (action, value) {
+        (ArgAction::SetTrue, Value::Bool(true)) => out.push(flag),
+        (ArgAction::SetTrue, Value::Bool(false)) => {}
+        (ArgAction::Count, Value::Number(n)) => {
+            let n = n.as_u64().unwrap_or_default();
+            out.extend(std::iter::repeat(flag).take(n as usize));
+        }
+        (_, Value::Array(items)) => {
+            for item in items {
+                out.push(flag.clone());
+                out.push(config_scalar_to_string(key, item)?);
+            }
+        }
+        (_, v) => {
+            out.push(flag);
+            out.push(config_scalar_to_string(key, v)?);
+        }
+    }
+    Ok(())
+}
+
+fn config_scalar_to_string(key: &str, value: &serde_json::Value) -> Result<String> {
+    use serde_json::Value;
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(format!("config: unsupported value for `{}`: {}", key, value).into()),
+    }
+}
+
 pub fn process_list_args_with_file_paths(list: &[String]) -> Result<Vec<String>> {
     let mut r_list: Vec<String> = Vec::with_capacity(list.len());
     for x in list.iter() {
@@ -105,3 +445,105 @@ pub fn process_list_args_with_file_paths(list: &[String]) -> Result<Vec<String>>
     }
     Ok(r_list)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_args_are_overridden_by_cli() {
+        let path = std::env::temp_dir().join("dindexer-test-config.toml");
+        std::fs::write(
+            &path,
+            "verbosity = 1\n\
+            [cindex]\n\
+            sqlite_path = \"from-config.sqlite\"\n\
+            end_height = 100\n\
+            refresh_tokens = true\n",
+        )
+        .unwrap();
+
+        let argv = [
+            "bin",
+            "--config",
+            path.to_str().unwrap(),
+            "cindex",
+            "-e",
+            "5",
+        ]
+        .map(String::from)
+        .to_vec();
+        let args = Args::parse_from(merge_env_and_config_args(argv).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(args.verbosity, 1);
+        assert_eq!(args.log_format, LogFormat::Text);
+        match args.command {
+            Cmd::CliIndex(a) => {
+                assert_eq!(a.db.sqlite_path, "from-config.sqlite");
+                assert_eq!(a.end_height, 5);
+                assert!(a.refresh_tokens);
+            }
+            _ => panic!("expected cindex"),
+        }
+    }
+
+    #[test]
+    fn test_config_args_of_a_nested_command() {
+        let path = std::env::temp_dir().join("dindexer-test-config-nested.toml");
+        std::fs::write(
+            &path,
+            "[export.csv]\n\
+            sqlite_path = \"from-config.sqlite\"\n\
+            out = \"from-config.csv\"\n\
+            start_height = 10\n",
+        )
+        .unwrap();
+
+        let argv = [
+            "bin",
+            "--config",
+            path.to_str().unwrap(),
+            "export",
+            "csv",
+            "-s",
+            "5",
+        ]
+        .map(String::from)
+        .to_vec();
+        let args = Args::parse_from(merge_env_and_config_args(argv).unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        match args.command {
+            Cmd::Export(ExportCmd::Csv(a)) => {
+                assert_eq!(a.db.sqlite_path, "from-config.sqlite");
+                assert_eq!(a.out, "from-config.csv");
+                assert_eq!(a.start_height, 5);
+            }
+            _ => panic!("expected export csv"),
+        }
+    }
+
+    #[test]
+    fn test_timestamp_from_str() {
+        let ts = |s: &str| s.parse::<Timestamp>().map(|x| x.0);
+        assert_eq!(ts("1970-01-01"), Ok(0));
+        assert_eq!(ts("2023-01-01"), Ok(1_672_531_200));
+        assert_eq!(ts("2024-02-29T12:30:15Z"), Ok(1_709_209_815));
+        assert_eq!(ts("2024-02-29 12:30:15"), Ok(1_709_209_815));
+        assert_eq!(ts("1709209815"), Ok(1_709_209_815));
+        for bad in ["2023-13-01", "2023-01", "2023-01-01T12:00", "yesterday"] {
+            assert!(ts(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_verbosity_to_level() {
+        assert_eq!(verbosity_to_level(0, Some(2), 0), LevelFilter::INFO);
+        assert_eq!(verbosity_to_level(3, Some(2), 0), LevelFilter::DEBUG);
+        assert_eq!(verbosity_to_level(0, None, 0), LevelFilter::ERROR);
+        assert_eq!(verbosity_to_level(0, Some(2), 1), LevelFilter::ERROR);
+        assert_eq!(verbosity_to_level(0, Some(2), 2), LevelFilter::OFF);
+        assert!(Args::try_parse_from(["bin", "-q", "-v", "status"]).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+//! Per address balances, materialized into the balances table of an
+//! indexed sqlite db, so holdings can be looked up without replaying
+//! the chain.
+
+use crate::db::{sqlite_json_text, SqliteBlockStore, META_BALANCES_HEIGHT};
+use crate::dfiutils::TokenMap;
+use crate::lang::{OptionExt, Result};
+use crate::models::{self, Amount, Transaction, TxType};
+use rusqlite::params;
+use std::collections::{BTreeMap, HashMap};
+use tracing::{info, warn};
+
+// Rebuilt from scratch on every run, so it's not part of the migrations.
+const SQLITE_CREATE_BALANCES: &str = "
+    CREATE TABLE IF NOT EXISTS balances (
+        address TEXT NOT NULL,
+        token TEXT NOT NULL,
+        source TEXT NOT NULL,
+        amount REAL NOT NULL,
+        PRIMARY KEY (address, token, source)
+    )
+";
+
+// Address and token pair, to the amount.
+type Balances = BTreeMap<(String, String), Amount>;
+
+/// Replace the balances table with the balances as of the highest stored
+/// height. `source` is `utxo` for the unspent outs of the utxos table,
+/// in the utxo token, so these need `--track-utxos` from the start.
+/// It's `account` for the dvm accounts, which only follow the transfers
+/// between accounts and utxos. Swaps, liquidity, loans and rewards
+/// aren't applied, so the accounts of addresses that use them are off.
+/// Returns the number of rows of each.
+pub fn build_balances(store: &SqliteBlockStore, tokens: &TokenMap) -> Result<(usize, usize)> {
+    let conn = &store.conn;
+    let height: Option<i64> = conn.query_row("SELECT max(height) FROM blocks", [], |r| r.get(0))?;
+    let utxos = utxo_balances(store)?;
+    if utxos.is_empty() {
+        warn!("balances: no unspent outs, index with --track-utxos for the utxo side");
+    }
+    let accounts = account_balances(store, tokens)?;
+
+    let utxo_token = tokens.symbol_maybe("0");
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(SQLITE_CREATE_BALANCES, [])?;
+    tx.execute("DELETE FROM balances", [])?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO balances (address, token, source, amount) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for (address, value) in &utxos {
+            stmt.execute(params![address, utxo_token, "utxo", value])?;
+        }
+        for ((address, token), amount) in &accounts {
+            let amount = amount.to_string().parse::<f64>().unwrap_or_default();
+            stmt.execute(params![address, token, "account", amount])?;
+        }
+    }
+    tx.execute(
+        "insert or replace into dindexer_meta (key, value) values (?1, ?2)",
+        params![
+            META_BALANCES_HEIGHT,
+            height.map(|h| h.to_string()).unwrap_or_default()
+        ],
+    )?;
+    tx.commit()?;
+
+    info!(
+        "balances: {} utxo and {} account rows, as of [{}]",
+        utxos.len(),
+        accounts.len(),
+        height.unwrap_or_default()
+    );
+    Ok((utxos.len(), accounts.len()))
+}
+
+fn utxo_balances(store: &SqliteBlockStore) -> Result<Vec<(String, f64)>> {
+    let mut stmt = store.conn.prepare(
+        "SELECT address, sum(value) FROM utxos WHERE spent_txid IS NULL
+        GROUP BY address ORDER BY address",
+    )?;
+    let res = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(res)
+}
+
+/// The account transfers, applied in the order of the chain.
+fn account_balances(store: &SqliteBlockStore, tokens: &TokenMap) -> Result<Balances> {
+    let types = [
+        TxType::UtxosToAccount,
+        TxType::AccountToUtxos,
+        TxType::AccountToAccount,
+        TxType::AnyAccountsToAccounts,
+    ]
+    .map(|t| t.to_string());
+    let mut stmt = store
+        .conn
+        .prepare("SELECT data FROM txs WHERE tx_type IN (?1, ?2, ?3, ?4) ORDER BY height, rowid")?;
+    let mut rows = stmt.query(params![types[0], types[1], types[2], types[3]])?;
+    let mut balances = Balances::new();
+    while let Some(row) = rows.next()? {
+        let tx: Transaction = serde_json::from_str(&sqlite_json_text(row.get_ref(0)?)?)?;
+        apply_transfer(&mut balances, tokens, &tx)
+            .map_err(|e| format!("balances: {}: {}", tx.txid, e))?;
+    }
+    balances.retain(|_, v| *v != Amount::default());
+    Ok(balances)
+}
+
+fn apply_transfer(balances: &mut Balances, tokens: &TokenMap, tx: &Transaction) -> Result<()> {
+    let vm = tx.vm.as_ref().ok_or_err()?;
+    let msg = vm.msg.get();
+    let (from, to): (HashMap<_, _>, HashMap<_, _>) = match TxType::from(&*vm.txtype) {
+        TxType::UtxosToAccount => {
+            let msg: models::UtxosToAccountMsg = serde_json::from_str(msg)?;
+            (HashMap::new(), msg)
+        }
+        // The utxos side is in the utxos table.
+        TxType::AccountToUtxos => {
+            let msg: models::AccountToUtxosMsg = serde_json::from_str(msg)?;
+            let sent = msg.to.values().cloned().collect::<Vec<_>>().join(",");
+            (HashMap::from([(msg.from, sent.into())]), HashMap::new())
+        }
+        TxType::AccountToAccount => {
+            let msg: models::AccountToAccountMsg = serde_json::from_str(msg)?;
+            let sent = msg.to.values().cloned().collect::<Vec<_>>().join(",");
+            (HashMap::from([(msg.from, sent.into())]), msg.to)
+        }
+        TxType::AnyAccountsToAccounts => {
+            let msg: models::AnyAccountsToAccountsMsg = serde_json::from_str(msg)?;
+            (msg.from, msg.to)
+        }
+        _ => return Ok(()),
+    };
+    for (credit, side) in [(false, from), (true, to)] {
+        for (address, amounts) in side {
+            for (amount, token) in token_amounts(&amounts)? {
+                let k = (address.to_string(), tokens.symbol_maybe(token).to_owned());
+                let entry = balances.entry(k).or_default();
+                if credit {
+                    *entry += amount;
+                } else {
+                    *entry -= amount;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The amounts of a `1.00000000@0,2.00000000@2` list, with the token ids.
+fn token_amounts(amounts: &str) -> Result<Vec<(Amount, &str)>> {
+    amounts
+        .split(',')
+        .filter_map(|x| x.trim().rsplit_once('@'))
+        .map(|(amount, id)| -> Result<(Amount, &str)> {
+            let amount =
+                models::parse_amount(amount).map_err(|e| format!("amount: {}: {}", amount, e))?;
+            Ok((amount, id))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{BlockStore, TxRecord};
+    use crate::dfiutils::Network;
+
+    #[test]
+    fn test_build_balances() {
+        let store = SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let tokens = TokenMap::new(
+            HashMap::from([
+                ("0".to_owned(), "DFI".to_owned()),
+                ("2".to_owned(), "BTC".to_owned()),
+            ]),
+            Network::Mainnet,
+        );
+        let dvm_tx = |txid: &str, height, txtype: TxType, msg: serde_json::Value| {
+            let data = serde_json::json!({
+                "txid": txid, "hash": txid, "version": 1, "size": 0, "vsize": 0,
+                "weight": 0, "locktime": 0, "hex": "", "vin": [], "vout": [],
+                "vm": { "vmtype": "dvm", "txtype": format!("{:?}", txtype), "msg": msg },
+            })
+            .to_string();
+            let tx_type = txtype.to_string();
+            store
+                .put_tx(&TxRecord {
+                    txid,
+                    height,
+                    tx_type: &tx_type,
+                    data: &data,
+                    ..Default::default()
+                })
+                .unwrap();
+        };
+        store.put_block(2, "h2", "{}").unwrap();
+        dvm_tx(
+            "t1",
+            1,
+            TxType::UtxosToAccount,
+            serde_json::json!({ "a1": "5.00000000@0" }),
+        );
+        dvm_tx(
+            "t2",
+            2,
+            TxType::AccountToAccount,
+            serde_json::json!({ "from": "a1", "to": { "a2": "2.00000000@0" } }),
+        );
+        dvm_tx(
+            "t3",
+            2,
+            TxType::AnyAccountsToAccounts,
+            serde_json::json!({
+                "from": { "a3": "1.00000000@2" },
+                "to": { "a1": "1.00000000@2" },
+            }),
+        );
+        store.put_utxo("u1", 0, "a1", 1.5, 1).unwrap();
+        store.put_utxo("u1", 1, "a2", 2.5, 1).unwrap();
+        store.spend_utxo("u1", 1, "t2", 2).unwrap();
+
+        assert_eq!(build_balances(&store, &tokens).unwrap(), (1, 4));
+        let mut stmt = store
+            .conn
+            .prepare("SELECT address, token, source, amount FROM balances ORDER BY 1, 2, 3")
+            .unwrap();
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, f64>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        let row = |a: &str, t: &str, s: &str, v| (a.to_owned(), t.to_owned(), s.to_owned(), v);
+        assert_eq!(
+            rows,
+            vec![
+                row("a1", "BTC", "account", 1.),
+                row("a1", "DFI", "account", 3.),
+                row("a1", "DFI", "utxo", 1.5),
+                row("a2", "DFI", "account", 2.),
+                row("a3", "BTC", "account", -1.),
+            ]
+        );
+        assert_eq!(
+            store.get_meta().unwrap()[META_BALANCES_HEIGHT],
+            "2".to_owned()
+        );
+    }
+}
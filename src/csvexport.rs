@@ -0,0 +1,91 @@
+use crate::cliindexer::DbArgs;
+use crate::db::{is_postgres_url, sqlite_json_text, SqliteBlockStore};
+use crate::lang::Result;
+use clap::Parser;
+use rusqlite::types::ValueRef;
+use std::io::Write;
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct CsvExportArgs {
+    #[command(flatten)]
+    pub db: DbArgs,
+    /// Output path, `-` for stdout
+    #[arg(short = 'o', long, default_value = "data/txs.csv")]
+    pub out: String,
+    #[arg(short = 's', long, default_value_t = 0)]
+    pub start_height: i64,
+    #[arg(short = 'e', long, default_value_t = i64::MAX)]
+    pub end_height: i64,
+}
+
+pub fn run(args: &CsvExportArgs) -> Result<()> {
+    debug!("{:?}", args);
+
+    let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    crate::lang::on_signal(signal_hook::consts::SIGINT, &quit)?;
+
+    if is_postgres_url(args.db.db_url()) {
+        return Err("export csv: only sqlite dbs are supported".into());
+    }
+    let sql_store = SqliteBlockStore::new_v2_with_opts(args.db.sqlite_db_path()?, &args.db.sqlite)?;
+    let out: Box<dyn Write> = match args.out.as_str() {
+        "-" => Box::new(std::io::stdout().lock()),
+        path => Box::new(std::fs::File::create(path)?),
+    };
+    let mut w = std::io::BufWriter::new(out);
+
+    let mut stmt = sql_store
+        .conn
+        .prepare("SELECT * FROM txs WHERE height BETWEEN ?1 AND ?2 ORDER BY height")?;
+    let cols = stmt
+        .column_names()
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    writeln!(w, "{}", cols.join(","))?;
+
+    let mut rows = stmt.query(rusqlite::params![args.start_height, args.end_height])?;
+    let mut count = 0usize;
+    while let Some(row) = rows.next()? {
+        if quit.load(std::sync::atomic::Ordering::Relaxed) {
+            info!("int: early exit");
+            break;
+        }
+        for i in 0..cols.len() {
+            if i > 0 {
+                w.write_all(b",")?;
+            }
+            write_csv_value(&mut w, row.get_ref(i)?)?;
+        }
+        w.write_all(b"\n")?;
+        count += 1;
+        if count % 1_000_000 == 0 {
+            info!("exported: {} txs", count);
+        }
+    }
+    w.flush()?;
+
+    info!("done: {} txs", count);
+    Ok(())
+}
+
+// Numbers are written bare, everything else is quoted so json columns
+// come through as is.
+fn write_csv_value(w: &mut impl Write, v: ValueRef) -> Result<()> {
+    match v {
+        ValueRef::Null => {}
+        ValueRef::Integer(i) => write!(w, "{}", i)?,
+        ValueRef::Real(f) => write!(w, "{}", f)?,
+        ValueRef::Text(t) => write_csv_quoted(w, &String::from_utf8_lossy(t))?,
+        ValueRef::Blob(_) => write_csv_quoted(w, &sqlite_json_text(v)?)?,
+    }
+    Ok(())
+}
+
+fn write_csv_quoted(w: &mut impl Write, s: &str) -> Result<()> {
+    w.write_all(b"\"")?;
+    w.write_all(s.replace('"', "\"\"").as_bytes())?;
+    w.write_all(b"\"")?;
+    Ok(())
+}
@@ -0,0 +1,214 @@
+use crate::db::{BlockStats, BlockStore, EdgeRecord, TxAddrData, TxRecord};
+use crate::lang::Result;
+use crate::models::{Block, Transaction};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Everything goes through to the inner store. On every commit, the
+/// height of the last block put is written to the checkpoint file, so a
+/// run can resume from there even when the db can't be trusted after a
+/// crash. Rolled back heights pull it back.
+pub struct CheckpointStore<'a, S> {
+    inner: &'a S,
+    path: Option<&'a str>,
+    last_height: Cell<Option<i64>>,
+}
+
+impl<'a, S: BlockStore> CheckpointStore<'a, S> {
+    /// Without a `path`, this is just a passthrough.
+    pub fn new(inner: &'a S, path: Option<&'a str>) -> Self {
+        Self {
+            inner,
+            path,
+            last_height: Cell::new(None),
+        }
+    }
+}
+
+/// The height in the checkpoint file, if there's one.
+pub fn read_checkpoint(path: &str) -> Result<Option<i64>> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => Ok(Some(
+            s.trim()
+                .parse::<i64>()
+                .map_err(|e| format!("checkpoint: {}: {}", path, e))?,
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Written to a temp file that's synced and then renamed over, so the
+// file is either the old or the new height, never a torn write.
+fn write_checkpoint(path: &str, height: i64) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    let mut f = std::fs::File::create(&tmp)?;
+    writeln!(f, "{}", height)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+impl<S: BlockStore> BlockStore for CheckpointStore<'_, S> {
+    fn get_block_from_hash(&self, hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_from_hash(hash)
+    }
+
+    fn get_block_hash(&self, height: i64) -> Result<Option<String>> {
+        self.inner.get_block_hash(height)
+    }
+
+    fn get_block_hash_for_tx(&self, tx_hash: &str) -> Result<Option<String>> {
+        self.inner.get_block_hash_for_tx(tx_hash)
+    }
+
+    fn get_block_for_tx(&self, tx_hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_for_tx(tx_hash)
+    }
+
+    fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+        self.inner.get_block_from_height(height)
+    }
+
+    fn get_block_json(&self, height: i64) -> Result<Option<String>> {
+        self.inner.get_block_json(height)
+    }
+
+    fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
+        self.inner.get_tx_from_hash(hash)
+    }
+
+    fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>> {
+        self.inner.get_tx_addr_data_from_hash(hash)
+    }
+
+    fn get_tx_height(&self, txid: &str) -> Result<Option<i64>> {
+        self.inner.get_tx_height(txid)
+    }
+
+    fn get_max_height(&self) -> Result<Option<i64>> {
+        self.inner.get_max_height()
+    }
+
+    fn get_token_symbols(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_token_symbols()
+    }
+
+    fn get_height_range(&self) -> Result<Option<(i64, i64)>> {
+        self.inner.get_height_range()
+    }
+
+    fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>> {
+        self.inner.get_height_gaps()
+    }
+
+    fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>> {
+        self.inner.get_orphan_tx_heights()
+    }
+
+    fn get_meta(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_meta()
+    }
+
+    fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+        self.inner.get_table_counts()
+    }
+
+    fn put_block(&self, height: i64, hash: &str, data: &str) -> Result<()> {
+        self.inner.put_block(height, hash, data)?;
+        self.last_height.set(Some(height));
+        Ok(())
+    }
+
+    fn put_block_stats(&self, stats: &BlockStats) -> Result<()> {
+        self.inner.put_block_stats(stats)
+    }
+
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        self.inner.put_tx(tx)
+    }
+
+    fn put_edge(&self, edge: &EdgeRecord) -> Result<()> {
+        self.inner.put_edge(edge)
+    }
+
+    fn put_address_tx(
+        &self,
+        address: &str,
+        txid: &str,
+        height: i64,
+        direction: &str,
+        value: f64,
+    ) -> Result<()> {
+        self.inner
+            .put_address_tx(address, txid, height, direction, value)
+    }
+
+    fn put_utxo(&self, txid: &str, n: u64, address: &str, value: f64, height: i64) -> Result<()> {
+        self.inner.put_utxo(txid, n, address, value, height)
+    }
+
+    fn spend_utxo(&self, txid: &str, n: u64, spent_txid: &str, height: i64) -> Result<()> {
+        self.inner.spend_utxo(txid, n, spent_txid, height)
+    }
+
+    fn put_token_symbols(&self, symbols: &HashMap<String, String>) -> Result<()> {
+        self.inner.put_token_symbols(symbols)
+    }
+
+    fn put_meta(&self, meta: &HashMap<String, String>) -> Result<()> {
+        self.inner.put_meta(meta)
+    }
+
+    fn delete_height(&self, height: i64) -> Result<()> {
+        self.inner.delete_height(height)?;
+        if self.last_height.get().is_some_and(|h| h >= height) {
+            self.last_height.set(Some(height - 1));
+        }
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<()> {
+        self.inner.begin()
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.inner.commit()?;
+        if let (Some(path), Some(height)) = (self.path, self.last_height.get()) {
+            write_checkpoint(path, height)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::tests::MemStore;
+
+    #[test]
+    fn test_checkpoint_store() {
+        let path = std::env::temp_dir().join("dindexer-test-checkpoint");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let store = MemStore::default();
+        let cp_store = CheckpointStore::new(&store, Some(path));
+        assert_eq!(read_checkpoint(path).unwrap(), None);
+
+        for h in 1..=3 {
+            cp_store.put_block(h, &format!("h{}", h), "{}").unwrap();
+        }
+        // Only written on commit.
+        assert_eq!(read_checkpoint(path).unwrap(), None);
+        cp_store.commit().unwrap();
+        assert_eq!(read_checkpoint(path).unwrap(), Some(3));
+
+        cp_store.delete_height(3).unwrap();
+        cp_store.delete_height(2).unwrap();
+        cp_store.commit().unwrap();
+        assert_eq!(read_checkpoint(path).unwrap(), Some(1));
+        assert_eq!(store.blocks.borrow().len(), 1);
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+    }
+}
@@ -0,0 +1,239 @@
+use crate::db::{BlockStats, BlockStore, EdgeRecord, TxAddrData, TxRecord};
+use crate::lang::Result;
+use crate::models::{Block, Transaction};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+
+/// Reads go through to the inner store and writes are only counted.
+/// Block hashes and txs put during the run are kept in memory, so that
+/// reorg checks and prevout lookups still see them. Memory grows with
+/// the range, it's meant for debugging and benchmarking runs.
+pub struct DryRunStore<'a, S> {
+    inner: &'a S,
+    blocks: RefCell<BTreeMap<i64, String>>,
+    // txid -> (height, data)
+    txs: RefCell<HashMap<String, (i64, String)>>,
+    counts: Cell<DryRunCounts>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DryRunCounts {
+    pub blocks: usize,
+    pub txs: usize,
+    pub edges: usize,
+    pub address_txs: usize,
+    pub utxos: usize,
+}
+
+impl<'a, S: BlockStore> DryRunStore<'a, S> {
+    pub fn new(inner: &'a S) -> Self {
+        Self {
+            inner,
+            blocks: RefCell::new(BTreeMap::new()),
+            txs: RefCell::new(HashMap::new()),
+            counts: Cell::new(DryRunCounts::default()),
+        }
+    }
+
+    pub fn counts(&self) -> DryRunCounts {
+        self.counts.get()
+    }
+
+    fn count(&self, f: impl FnOnce(&mut DryRunCounts)) {
+        let mut c = self.counts.get();
+        f(&mut c);
+        self.counts.set(c);
+    }
+}
+
+impl<S: BlockStore> BlockStore for DryRunStore<'_, S> {
+    fn get_block_from_hash(&self, hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_from_hash(hash)
+    }
+
+    fn get_block_hash(&self, height: i64) -> Result<Option<String>> {
+        match self.blocks.borrow().get(&height) {
+            Some(hash) => Ok(Some(hash.clone())),
+            None => self.inner.get_block_hash(height),
+        }
+    }
+
+    fn get_block_hash_for_tx(&self, tx_hash: &str) -> Result<Option<String>> {
+        self.inner.get_block_hash_for_tx(tx_hash)
+    }
+
+    fn get_block_for_tx(&self, tx_hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_for_tx(tx_hash)
+    }
+
+    fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+        self.inner.get_block_from_height(height)
+    }
+
+    fn get_block_json(&self, height: i64) -> Result<Option<String>> {
+        self.inner.get_block_json(height)
+    }
+
+    fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
+        match self.txs.borrow().get(hash) {
+            Some((_, data)) => Ok(Some(serde_json::from_str(data)?)),
+            None => self.inner.get_tx_from_hash(hash),
+        }
+    }
+
+    fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>> {
+        self.inner.get_tx_addr_data_from_hash(hash)
+    }
+
+    fn get_tx_height(&self, txid: &str) -> Result<Option<i64>> {
+        match self.txs.borrow().get(txid) {
+            Some((height, _)) => Ok(Some(*height)),
+            None => self.inner.get_tx_height(txid),
+        }
+    }
+
+    fn get_max_height(&self) -> Result<Option<i64>> {
+        let inner = self.inner.get_max_height()?;
+        let local = self.blocks.borrow().keys().next_back().copied();
+        Ok(inner.max(local))
+    }
+
+    fn get_token_symbols(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_token_symbols()
+    }
+
+    fn get_height_range(&self) -> Result<Option<(i64, i64)>> {
+        self.inner.get_height_range()
+    }
+
+    fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>> {
+        self.inner.get_height_gaps()
+    }
+
+    fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>> {
+        self.inner.get_orphan_tx_heights()
+    }
+
+    fn get_meta(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_meta()
+    }
+
+    fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+        self.inner.get_table_counts()
+    }
+
+    fn put_block(&self, height: i64, hash: &str, _data: &str) -> Result<()> {
+        self.blocks.borrow_mut().insert(height, hash.to_owned());
+        self.count(|c| c.blocks += 1);
+        Ok(())
+    }
+
+    fn put_block_stats(&self, _stats: &BlockStats) -> Result<()> {
+        Ok(())
+    }
+
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        self.txs
+            .borrow_mut()
+            .insert(tx.txid.to_owned(), (tx.height, tx.data.to_owned()));
+        self.count(|c| c.txs += 1);
+        Ok(())
+    }
+
+    fn put_edge(&self, _edge: &EdgeRecord) -> Result<()> {
+        self.count(|c| c.edges += 1);
+        Ok(())
+    }
+
+    fn put_address_tx(
+        &self,
+        _address: &str,
+        _txid: &str,
+        _height: i64,
+        _direction: &str,
+        _value: f64,
+    ) -> Result<()> {
+        self.count(|c| c.address_txs += 1);
+        Ok(())
+    }
+
+    fn put_utxo(
+        &self,
+        _txid: &str,
+        _n: u64,
+        _address: &str,
+        _value: f64,
+        _height: i64,
+    ) -> Result<()> {
+        self.count(|c| c.utxos += 1);
+        Ok(())
+    }
+
+    fn spend_utxo(&self, _txid: &str, _n: u64, _spent_txid: &str, _height: i64) -> Result<()> {
+        Ok(())
+    }
+
+    fn put_token_symbols(&self, _symbols: &HashMap<String, String>) -> Result<()> {
+        Ok(())
+    }
+
+    fn put_meta(&self, _meta: &HashMap<String, String>) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete_height(&self, height: i64) -> Result<()> {
+        self.blocks.borrow_mut().remove(&height);
+        self.txs.borrow_mut().retain(|_, (h, _)| *h != height);
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfiutils::TokenMap;
+    use crate::indexer::index_block;
+    use crate::indexer::tests::{
+        block_json, coinbase_json, spend_vin, test_ctx, tx_json, MemStore,
+    };
+    use crate::indexer::IndexCtx;
+    use crate::models::LogEntryMap;
+
+    #[test]
+    fn test_index_block_dry_run() {
+        let store = MemStore::default();
+        let dry_store = DryRunStore::new(&store);
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let spend = tx_json("t2", spend_vin("t1", 0), "a2");
+
+        // t2 resolves its prevout from t1, which only the dry store has.
+        for (height, hash, tx) in [(1, "h1", cb), (2, "h2", spend)] {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &dry_store, &dry_store, height, &json, block).unwrap();
+        }
+
+        let counts = dry_store.counts();
+        assert_eq!((counts.blocks, counts.txs, counts.edges), (2, 2, 1));
+        assert_eq!(dry_store.get_max_height().unwrap(), Some(2));
+        assert!(store.blocks.borrow().is_empty());
+        assert!(store.txs.borrow().is_empty());
+        assert!(store.edges.borrow().is_empty());
+    }
+}
@@ -0,0 +1,248 @@
+use crate::db::{BlockStats, BlockStore, EdgeRecord, SqliteBlockStore, TxAddrData, TxRecord};
+use crate::lang::Result;
+use crate::models::{Block, Transaction};
+use std::collections::HashMap;
+
+/// Everything goes through to the inner store but the graph edges, that
+/// go to a sqlite db of their own if there's one, eg. for graph tools to
+/// load without the block and tx json. The graph db also gets a slim row
+/// of each tx, with only its height, type and in and out counts, so the
+/// edges of a rolled back height can be found there.
+pub struct GraphDbStore<'a, S> {
+    inner: &'a S,
+    graph: Option<SqliteBlockStore>,
+}
+
+impl<'a, S: BlockStore> GraphDbStore<'a, S> {
+    pub fn new(inner: &'a S, graph: Option<SqliteBlockStore>) -> Self {
+        Self { inner, graph }
+    }
+}
+
+impl<S: BlockStore> BlockStore for GraphDbStore<'_, S> {
+    fn get_block_from_hash(&self, hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_from_hash(hash)
+    }
+
+    fn get_block_hash(&self, height: i64) -> Result<Option<String>> {
+        self.inner.get_block_hash(height)
+    }
+
+    fn get_block_hash_for_tx(&self, tx_hash: &str) -> Result<Option<String>> {
+        self.inner.get_block_hash_for_tx(tx_hash)
+    }
+
+    fn get_block_for_tx(&self, tx_hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_for_tx(tx_hash)
+    }
+
+    fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+        self.inner.get_block_from_height(height)
+    }
+
+    fn get_block_json(&self, height: i64) -> Result<Option<String>> {
+        self.inner.get_block_json(height)
+    }
+
+    fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
+        self.inner.get_tx_from_hash(hash)
+    }
+
+    fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>> {
+        self.inner.get_tx_addr_data_from_hash(hash)
+    }
+
+    fn get_tx_height(&self, txid: &str) -> Result<Option<i64>> {
+        self.inner.get_tx_height(txid)
+    }
+
+    fn get_max_height(&self) -> Result<Option<i64>> {
+        self.inner.get_max_height()
+    }
+
+    fn get_token_symbols(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_token_symbols()
+    }
+
+    fn get_height_range(&self) -> Result<Option<(i64, i64)>> {
+        self.inner.get_height_range()
+    }
+
+    fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>> {
+        self.inner.get_height_gaps()
+    }
+
+    fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>> {
+        self.inner.get_orphan_tx_heights()
+    }
+
+    fn get_meta(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_meta()
+    }
+
+    fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+        self.inner.get_table_counts()
+    }
+
+    fn put_block(&self, height: i64, hash: &str, data: &str) -> Result<()> {
+        self.inner.put_block(height, hash, data)
+    }
+
+    fn put_block_stats(&self, stats: &BlockStats) -> Result<()> {
+        self.inner.put_block_stats(stats)?;
+        match &self.graph {
+            Some(graph) => graph.flush_edges(),
+            None => Ok(()),
+        }
+    }
+
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        self.inner.put_tx(tx)?;
+        let Some(graph) = &self.graph else {
+            return Ok(());
+        };
+        graph.put_tx(&TxRecord {
+            txid: tx.txid,
+            height: tx.height,
+            tx_type: tx.tx_type,
+            vin_count: tx.vin_count,
+            vout_count: tx.vout_count,
+            ..Default::default()
+        })
+    }
+
+    fn put_edge(&self, edge: &EdgeRecord) -> Result<()> {
+        match &self.graph {
+            Some(graph) => graph.put_edge(edge),
+            None => self.inner.put_edge(edge),
+        }
+    }
+
+    fn put_address_tx(
+        &self,
+        address: &str,
+        txid: &str,
+        height: i64,
+        direction: &str,
+        value: f64,
+    ) -> Result<()> {
+        self.inner
+            .put_address_tx(address, txid, height, direction, value)
+    }
+
+    fn put_utxo(&self, txid: &str, n: u64, address: &str, value: f64, height: i64) -> Result<()> {
+        self.inner.put_utxo(txid, n, address, value, height)
+    }
+
+    fn spend_utxo(&self, txid: &str, n: u64, spent_txid: &str, height: i64) -> Result<()> {
+        self.inner.spend_utxo(txid, n, spent_txid, height)
+    }
+
+    fn put_token_symbols(&self, symbols: &HashMap<String, String>) -> Result<()> {
+        self.inner.put_token_symbols(symbols)
+    }
+
+    fn put_meta(&self, meta: &HashMap<String, String>) -> Result<()> {
+        self.inner.put_meta(meta)?;
+        match &self.graph {
+            Some(graph) => graph.put_meta(meta),
+            None => Ok(()),
+        }
+    }
+
+    fn delete_height(&self, height: i64) -> Result<()> {
+        self.inner.delete_height(height)?;
+        match &self.graph {
+            Some(graph) => graph.delete_height(height),
+            None => Ok(()),
+        }
+    }
+
+    fn begin(&self) -> Result<()> {
+        self.inner.begin()?;
+        match &self.graph {
+            Some(graph) => graph.begin(),
+            None => Ok(()),
+        }
+    }
+
+    // The graph db goes first. If the run stops in between, its edges
+    // of the last heights are put again when they're indexed again,
+    // instead of missing.
+    fn commit(&self) -> Result<()> {
+        if let Some(graph) = &self.graph {
+            graph.commit()?;
+        }
+        self.inner.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dfiutils::TokenMap;
+    use crate::indexer::index_block;
+    use crate::indexer::tests::{
+        block_json, coinbase_json, spend_vin, test_ctx, tx_json, MemStore,
+    };
+    use crate::indexer::IndexCtx;
+    use crate::models::LogEntryMap;
+
+    #[test]
+    fn test_index_block_graph_db() {
+        let path = std::env::temp_dir().join("dindexer-test-graph-db.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let spend = |txid: &str, prev: &str, out_addr| {
+            let vin = spend_vin(prev, 0);
+            tx_json(txid, vin, out_addr)
+        };
+        let blocks = [
+            (1, "h1", cb),
+            (2, "h2", spend("t2", "t1", "a2")),
+            (3, "h3", spend("t3", "t2", "a3")),
+        ];
+
+        {
+            let graph = crate::db::SqliteBlockStore::new_v2(Some(path)).unwrap();
+            let graph_store = GraphDbStore::new(&store, Some(graph));
+            graph_store.begin().unwrap();
+            for (height, hash, tx) in blocks {
+                let json = block_json(height, hash, tx);
+                let block = serde_json::from_str(&json).unwrap();
+                index_block(&ctx, &graph_store, &graph_store, height, &json, block).unwrap();
+            }
+            graph_store.delete_height(3).unwrap();
+            graph_store.commit().unwrap();
+        }
+
+        // The txs still go to the inner store, the edges don't.
+        assert_eq!(store.txs.borrow().len(), 3);
+        assert!(store.edges.borrow().is_empty());
+        let graph = crate::db::SqliteBlockStore::new_v2(Some(path)).unwrap();
+        let edges = graph
+            .conn
+            .prepare("SELECT txid, in_addr, out_addr FROM tx_addr_graph")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(String, String, String)>>>()
+            .unwrap();
+        assert_eq!(edges, vec![("t2".into(), "a1".into(), "a2".into())]);
+        // Only the slim rows of the txs, without the json.
+        let tx = graph.get_tx_data("t2").unwrap().unwrap();
+        assert_eq!((tx.height, tx.vout_count), (2, 1));
+        assert!(tx.tx_out.is_empty());
+        assert_eq!(graph.get_tx_height("t3").unwrap(), None);
+        assert_eq!(graph.get_max_height().unwrap(), None);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::db::sink::TxSink;
+use crate::db::TxRecord;
+use crate::lang::Result;
+use crate::metrics;
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
+use rdkafka::ClientContext;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Kafka producer knobs. Only used when `--kafka-brokers` is set.
+#[derive(clap::Args, Debug, Clone)]
+pub struct KafkaOpts {
+    /// Publish every stored tx to kafka, eg. `host1:9092,host2:9092`
+    #[arg(long, requires = "kafka_topic")]
+    pub kafka_brokers: Option<String>,
+    /// Topic the txs are published to
+    #[arg(long)]
+    pub kafka_topic: Option<String>,
+    /// Max messages queued in the producer. Txs that don't fit are
+    /// dropped and counted as produce errors
+    #[arg(long, default_value_t = 100_000)]
+    pub kafka_queue_size: usize,
+}
+
+/// Counts the failed deliveries, they're reported once we're done.
+struct DeliveryCounter {
+    errors: AtomicU64,
+}
+
+impl DeliveryCounter {
+    fn inc(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        metrics::inc_kafka_errors();
+    }
+}
+
+impl ClientContext for DeliveryCounter {}
+
+impl ProducerContext for DeliveryCounter {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &DeliveryResult<'_>, _: Self::DeliveryOpaque) {
+        if let Err((e, _)) = result {
+            debug!("kafka: delivery: {}", e);
+            self.inc();
+        }
+    }
+}
+
+/// Publishes a message per tx, keyed by txid, with the txs table columns
+/// as a json object. Fire and forget: produce errors, including a full
+/// queue, are counted and never stop the indexing.
+/// A rolled back height is published as `{"rollback":<height>}`, keyed
+/// by the height.
+pub struct KafkaSink {
+    producer: BaseProducer<DeliveryCounter>,
+    topic: String,
+}
+
+impl KafkaSink {
+    pub fn new(brokers: &str, opts: &KafkaOpts) -> Result<Self> {
+        let topic = opts.kafka_topic.clone().unwrap_or_default();
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set(
+                "queue.buffering.max.messages",
+                std::cmp::max(opts.kafka_queue_size, 1).to_string(),
+            )
+            .create_with_context(DeliveryCounter {
+                errors: AtomicU64::new(0),
+            })?;
+        info!("kafka: publishing to {} on {}", topic, brokers);
+        Ok(Self { producer, topic })
+    }
+
+    fn send(&self, key: &str, payload: &str) {
+        let record = BaseRecord::to(&self.topic).key(key).payload(payload);
+        if let Err((e, _)) = self.producer.send(record) {
+            debug!("kafka: produce: {}", e);
+            self.producer.context().inc();
+        }
+        // Serves the delivery callbacks, doesn't block.
+        self.producer.poll(Duration::ZERO);
+    }
+
+    fn errors(&self) -> u64 {
+        self.producer.context().errors.load(Ordering::Relaxed)
+    }
+}
+
+impl TxSink for KafkaSink {
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        self.send(tx.txid, &serde_json::to_string(tx)?);
+        Ok(())
+    }
+
+    fn rollback(&self, height: i64) -> Result<()> {
+        let payload = serde_json::json!({ "rollback": height }).to_string();
+        self.send(&height.to_string(), &payload);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.producer.poll(Duration::ZERO);
+        Ok(())
+    }
+}
+
+impl Drop for KafkaSink {
+    // Give what's still queued a chance to go out before we exit.
+    fn drop(&mut self) {
+        if let Err(e) = self.producer.flush(Duration::from_secs(10)) {
+            warn!("kafka: flush: {}", e);
+        }
+        match self.errors() {
+            0 => info!("kafka: produce errors: 0"),
+            n => warn!("kafka: produce errors: {}", n),
+        }
+    }
+}
@@ -1,33 +1,168 @@
 #![allow(dead_code)]
 
+pub mod checkpoint;
+pub mod dryrun;
+pub mod graphdb;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod ndjson;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+#[cfg(feature = "rocksdb")]
+pub mod rocks;
+pub mod sink;
 #[cfg(feature = "legacy-sqlite-v1")]
 pub mod sqlite_v1;
 
 use crate::lang::{Result, ResultExt};
-use crate::models::{Block, IcxTxSet, Transaction};
+use crate::models::{Block, IcxLink, IcxTxSet, Transaction, Vout};
+use rusqlite::types::{ToSqlOutput, ValueRef};
 use rusqlite::{params, CachedStatement, Connection, OptionalExtension, Row};
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+use tracing::{info, warn};
 
-pub fn sqlite_init_db_v2(path: Option<&str>) -> Result<Connection> {
+/// Connection level knobs applied right after the db is opened.
+/// Defaults favor bulk-load throughput, which is the common case. Use
+/// `--sqlite-journal-mode delete --sqlite-synchronous full` for durability.
+/// WAL along with the busy timeout lets other connections read the db
+/// while it's being indexed. Outside of WAL, readers only get in between
+/// the commits.
+#[derive(clap::Args, Debug, Clone)]
+pub struct SqliteOpts {
+    /// SQLite journal mode
+    #[arg(
+        long = "sqlite-journal-mode",
+        default_value = "wal",
+        value_parser = ["wal", "delete", "truncate", "persist", "memory", "off"]
+    )]
+    pub journal_mode: String,
+    /// SQLite synchronous mode
+    #[arg(
+        long = "sqlite-synchronous",
+        default_value = "normal",
+        value_parser = ["off", "normal", "full", "extra"]
+    )]
+    pub synchronous: String,
+    /// SQLite page cache size in KiB
+    #[arg(long = "sqlite-cache-kb", default_value_t = 256 * 1024)]
+    pub cache_kb: i64,
+    /// SQLite max mmap size in MiB. 0 disables mmap
+    #[arg(long = "sqlite-mmap-mb", default_value_t = 1024)]
+    pub mmap_mb: i64,
+    /// How long to wait on a lock held by another connection before
+    /// giving up with "database is locked", in ms
+    #[arg(long = "sqlite-busy-timeout-ms", default_value_t = 5000)]
+    pub busy_timeout_ms: u64,
+    /// Zstd compress the block and tx json, stored as blobs. Reads take
+    /// either, so it can be turned on for an existing db. Off by default,
+    /// as plain sql can't look into the compressed json
+    #[arg(long)]
+    pub compress_blobs: bool,
+    /// SQLite page size in bytes, a power of two from 512 to 65536.
+    /// Only applies to a new db, larger pages can speed up bulk loads.
+    /// Default: 4096
+    #[arg(long = "sqlite-page-size", value_parser = parse_page_size)]
+    pub page_size: Option<u32>,
+    /// Where SQLite keeps its temp tables and indexes, eg. while the
+    /// indexes are built
+    #[arg(
+        long = "sqlite-temp-store",
+        default_value = "default",
+        value_parser = ["default", "file", "memory"]
+    )]
+    pub temp_store: String,
+}
+
+fn parse_page_size(s: &str) -> std::result::Result<u32, String> {
+    let size = s.parse::<u32>().map_err(|e| e.to_string())?;
+    match size.is_power_of_two() && (512..=65536).contains(&size) {
+        true => Ok(size),
+        false => Err("expected a power of two from 512 to 65536".to_owned()),
+    }
+}
+
+impl Default for SqliteOpts {
+    fn default() -> Self {
+        Self {
+            journal_mode: "wal".to_owned(),
+            synchronous: "normal".to_owned(),
+            cache_kb: 256 * 1024,
+            mmap_mb: 1024,
+            busy_timeout_ms: 5000,
+            compress_blobs: false,
+            page_size: None,
+            temp_store: "default".to_owned(),
+        }
+    }
+}
+
+impl SqliteOpts {
+    /// Warn when readers are going to be kept out of the db for long
+    /// stretches by the write transactions.
+    pub fn warn_reader_lockout(&self, commit_interval: i64) {
+        if self.journal_mode == "wal" {
+            return;
+        }
+        match commit_interval {
+            0 => warn!(
+                "sqlite: journal mode {}, readers are locked out until the run is done",
+                self.journal_mode
+            ),
+            n => warn!(
+                "sqlite: journal mode {}, readers are locked out between the commits every {} blocks",
+                self.journal_mode, n
+            ),
+        }
+    }
+}
+
+pub fn is_postgres_url(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
+
+/// The path of a `rocksdb://<path>` url.
+pub fn rocksdb_url_path(url: &str) -> Option<&str> {
+    url.strip_prefix("rocksdb://")
+}
+
+pub fn sqlite_init_db_v2(path: Option<&str>, opts: &SqliteOpts) -> Result<Connection> {
     let path = path.unwrap_or("data/index.sqlite");
     let conn = rusqlite::Connection::open(path)?;
-    sqlite_init_pragma_v1(&conn)?;
-    sqlite_init_tables_v2(&conn)?;
+    sqlite_init_pragma_v2(&conn, opts)?;
+    sqlite_migrate(&conn)?;
     Ok(conn)
 }
 
 fn sqlite_init_pragma_v1(conn: &Connection) -> Result<()> {
+    sqlite_init_pragma_v2(conn, &SqliteOpts::default())
+}
+
+// Note: These have to be applied before the first transaction is started,
+// journal_mode in particular is a no-op inside a transaction.
+fn sqlite_init_pragma_v2(conn: &Connection, opts: &SqliteOpts) -> Result<()> {
+    let page_size = opts.page_size.unwrap_or(4096); // default
+    sqlite_warn_page_size(conn, opts.page_size)?;
     let pragmas = [
         // "pragma locking_mode=exclusive",
-        "pragma journal_mode=wal",
-        "pragma secure_delete=off",
-        "pragma synchronous=normal",
-        "pragma analysis_limit=1000",         // recommended
-        "pragma wal_autocheckpoint=1000",     // default
-        "pragma page_size=4096",              // default
-        "pragma auto_vacuum=0",               // 0| none / 1| full / 2|incremental
-        "pragma journal_size_limit=67108864", // 1024 * 1024 * 64 // default: -1
-        "pragma wal_checkpoint(truncate)",    // let's restart the wal
+        // First, so the rest wait on other connections as well.
+        format!("pragma busy_timeout={}", opts.busy_timeout_ms),
+        // Before the journal mode, WAL fixes the page size of a new db.
+        format!("pragma page_size={}", page_size),
+        format!("pragma journal_mode={}", opts.journal_mode),
+        "pragma secure_delete=off".to_owned(),
+        format!("pragma synchronous={}", opts.synchronous),
+        format!("pragma cache_size=-{}", opts.cache_kb), // negative is in KiB
+        format!("pragma mmap_size={}", opts.mmap_mb * 1024 * 1024),
+        "pragma analysis_limit=1000".to_owned(), // recommended
+        "pragma wal_autocheckpoint=1000".to_owned(), // default
+        format!("pragma temp_store={}", opts.temp_store),
+        "pragma auto_vacuum=0".to_owned(), // 0| none / 1| full / 2|incremental
+        "pragma journal_size_limit=67108864".to_owned(), // 1024 * 1024 * 64 // default: -1
+        "pragma wal_checkpoint(truncate)".to_owned(), // let's restart the wal
     ];
 
     for pragma in &pragmas {
@@ -36,6 +171,101 @@ fn sqlite_init_pragma_v1(conn: &Connection) -> Result<()> {
     Ok(())
 }
 
+/// The page size of an existing db stays as it is, warn when it's not
+/// the one asked for.
+fn sqlite_warn_page_size(conn: &Connection, page_size: Option<u32>) -> Result<()> {
+    let Some(page_size) = page_size else {
+        return Ok(());
+    };
+    let page_count: i64 = conn.query_row("pragma page_count", [], |row| row.get(0))?;
+    let current: u32 = conn.query_row("pragma page_size", [], |row| row.get(0))?;
+    if page_count > 0 && current != page_size {
+        warn!(
+            "sqlite: page size {} only applies to a new db, the existing one keeps {}",
+            page_size, current
+        );
+    }
+    Ok(())
+}
+
+/// Schema changes in order, the schema version is the number of them
+/// applied. Dbs from before the version was recorded go through all of
+/// them, so each step has to be safe to re-apply on a db that already
+/// has it.
+const SQLITE_MIGRATIONS: [fn(&Connection) -> Result<()>; 13] = [
+    sqlite_init_tables_v2,
+    sqlite_migrate_swap_pools,
+    sqlite_migrate_transfers,
+    sqlite_migrate_blocks_stats,
+    sqlite_migrate_address_tx,
+    sqlite_migrate_edge_values,
+    sqlite_migrate_edge_tokens,
+    sqlite_migrate_tx_fees,
+    sqlite_migrate_tx_out_types,
+    sqlite_migrate_tx_icx_links,
+    sqlite_migrate_coinbase_addrs,
+    sqlite_migrate_utxos,
+    sqlite_migrate_tx_io_counts,
+];
+
+/// Version of the tables this build reads and writes.
+pub const SCHEMA_VERSION: i64 = SQLITE_MIGRATIONS.len() as i64;
+
+/// Bring the db up to `SCHEMA_VERSION`, one step at a time, each in its
+/// own transaction along with the version bump. A db with a newer schema
+/// is refused.
+fn sqlite_migrate(conn: &Connection) -> Result<()> {
+    // How the db was built, see `META_*`.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS dindexer_meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+    let version = conn
+        .query_row(
+            "SELECT value FROM dindexer_meta WHERE key = ?1",
+            [META_SCHEMA_VERSION],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+    let version = parse_schema_version(version.as_deref())?;
+    for (i, step) in SQLITE_MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let to = i as i64 + 1;
+        if version > 0 {
+            info!("schema: migrating to version {}", to);
+        }
+        let tx = conn.unchecked_transaction()?;
+        step(&tx)?;
+        tx.execute(
+            "insert or replace into dindexer_meta (key, value) values (?1, ?2)",
+            params![META_SCHEMA_VERSION, to.to_string()],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// The recorded schema version, 0 if there's none. Errors if it's newer
+/// than this build, as writing to it could lose data.
+pub(crate) fn parse_schema_version(recorded: Option<&str>) -> Result<i64> {
+    let version = match recorded {
+        Some(v) => v
+            .parse::<i64>()
+            .map_err(|e| format!("schema: bad version {}: {}", v, e))?,
+        None => 0,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(format!(
+            "schema: db is at version {}, this build only knows up to {}, upgrade the indexer",
+            version, SCHEMA_VERSION
+        )
+        .into());
+    }
+    Ok(version)
+}
+
 fn sqlite_init_tables_v2(conn: &Connection) -> Result<()> {
     // height is coalesced into rowid, so height is stored in the btree
     // and rest is stored on the leaf data page.
@@ -74,7 +304,13 @@ fn sqlite_init_tables_v2(conn: &Connection) -> Result<()> {
         )",
         [],
     )?;
-
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tokens (
+            id TEXT PRIMARY KEY,
+            symbol TEXT NOT NULL
+        )",
+        [],
+    )?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS tx_addr_graph (
             rowid INTEGER PRIMARY KEY,
@@ -86,11 +322,163 @@ fn sqlite_init_tables_v2(conn: &Connection) -> Result<()> {
         )",
         [],
     )?;
+    Ok(())
+}
+
+fn sqlite_migrate_swap_pools(conn: &Connection) -> Result<()> {
+    sqlite_add_column_if_missing(conn, "txs", "swap_pools", "TEXT NOT NULL DEFAULT ''")
+}
+
+fn sqlite_migrate_transfers(conn: &Connection) -> Result<()> {
+    for col in ["transfer_from", "transfer_to"] {
+        sqlite_add_column_if_missing(conn, "txs", col, "TEXT NOT NULL DEFAULT ''")?;
+    }
+    Ok(())
+}
+
+// Per block aggregates, so dashboards don't have to scan the txs.
+fn sqlite_migrate_blocks_stats(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blocks_stats (
+            height INTEGER PRIMARY KEY,
+            tx_count INTEGER NOT NULL,
+            total_vout_value REAL NOT NULL,
+            coinbase_value REAL NOT NULL,
+            size INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// A row per address a tx touches, so address lookups don't have to
+// scan the json columns. direction is `in` and `out` for the utxo sides,
+// valued, and `dvm` for the addresses in the dvm message, valued 0.
+// The unique key doubles as the index on address.
+fn sqlite_migrate_address_tx(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS address_tx (
+            address TEXT NOT NULL,
+            txid TEXT NOT NULL,
+            height INTEGER NOT NULL,
+            direction TEXT NOT NULL,
+            value REAL NOT NULL,
+            UNIQUE (address, txid, direction)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+// Value moved along each graph edge, 0 on the edges from before.
+fn sqlite_migrate_edge_values(conn: &Connection) -> Result<()> {
+    sqlite_add_column_if_missing(conn, "tx_addr_graph", "value", "REAL NOT NULL DEFAULT 0")
+}
+
+// Edges are per token, so the token goes into the unique key. Sqlite
+// can't change the constraints of a table, so it's rebuilt. The edges
+// from before get an empty token.
+fn sqlite_migrate_edge_tokens(conn: &Connection) -> Result<()> {
+    if sqlite_column_exists(conn, "tx_addr_graph", "token")? {
+        return Ok(());
+    }
+    conn.execute_batch(
+        "CREATE TABLE tx_addr_graph_new (
+            rowid INTEGER PRIMARY KEY,
+            txid TEXT NOT NULL,
+            in_addr TEXT NOT NULL,
+            out_addr TEXT NOT NULL,
+            c_flags TEXT NOT NULL,
+            value REAL NOT NULL DEFAULT 0,
+            token TEXT NOT NULL DEFAULT '',
+            UNIQUE (txid, in_addr, out_addr, token)
+        );
+        INSERT INTO tx_addr_graph_new (rowid, txid, in_addr, out_addr, c_flags, value)
+            SELECT rowid, txid, in_addr, out_addr, c_flags, value FROM tx_addr_graph;
+        DROP TABLE tx_addr_graph;
+        ALTER TABLE tx_addr_graph_new RENAME TO tx_addr_graph;",
+    )?;
+    Ok(())
+}
+
+// Fee paid by each tx, empty for coinbase txs and the txs from before.
+fn sqlite_migrate_tx_fees(conn: &Connection) -> Result<()> {
+    sqlite_add_column_if_missing(conn, "txs", "fee", "TEXT NOT NULL DEFAULT ''")
+}
+
+// Script type of each of the tx outs in order, see `OutputType`.
+fn sqlite_migrate_tx_out_types(conn: &Connection) -> Result<()> {
+    sqlite_add_column_if_missing(conn, "txs", "tx_out_types", "TEXT NOT NULL DEFAULT ''")
+}
+
+// ICX swaps each ICX tx is part of, see `IcxLink`.
+fn sqlite_migrate_tx_icx_links(conn: &Connection) -> Result<()> {
+    sqlite_add_column_if_missing(conn, "txs", "icx_links", "TEXT NOT NULL DEFAULT ''")
+}
+
+// Where the coinbase of each block paid out to, empty on the blocks
+// from before.
+fn sqlite_migrate_coinbase_addrs(conn: &Connection) -> Result<()> {
+    sqlite_add_column_if_missing(
+        conn,
+        "blocks_stats",
+        "coinbase_addrs",
+        "TEXT NOT NULL DEFAULT ''",
+    )
+}
+
+// The outs to an address, with the tx that spent them, if any. Only
+// filled with `--track-utxos`, so the unspent ones are the utxo set as
+// of the last height, if tracked from the start.
+fn sqlite_migrate_utxos(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS utxos (
+            txid TEXT NOT NULL,
+            n INTEGER NOT NULL,
+            address TEXT NOT NULL,
+            value REAL NOT NULL,
+            height INTEGER NOT NULL,
+            spent_txid TEXT,
+            spent_height INTEGER,
+            PRIMARY KEY (txid, n)
+        )",
+        [],
+    )?;
+    Ok(())
+}
 
+// Number of ins and outs of each tx, 0 on the txs from before.
+fn sqlite_migrate_tx_io_counts(conn: &Connection) -> Result<()> {
+    sqlite_add_column_if_missing(conn, "txs", "vin_count", "INTEGER NOT NULL DEFAULT 0")?;
+    sqlite_add_column_if_missing(conn, "txs", "vout_count", "INTEGER NOT NULL DEFAULT 0")
+}
+
+fn sqlite_column_exists(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|c| c == column);
+    Ok(exists)
+}
+
+fn sqlite_add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> Result<()> {
+    if !sqlite_column_exists(conn, table, column)? {
+        conn.execute_batch(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, decl
+        ))?;
+    }
     Ok(())
 }
 
-#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
 pub struct TxRow {
     pub txid: String,
     pub height: i64,
@@ -106,12 +494,20 @@ pub struct TxRow {
     pub swap_from: String,
     pub swap_to: String,
     pub swap_amt: String,
+    pub swap_pools: Vec<String>,
+    pub transfer_from: String,
+    pub transfer_to: HashMap<String, String>,
+    pub fee: String,
+    pub tx_out_types: Vec<String>,
+    pub icx_links: Vec<IcxLink>,
+    pub vin_count: i64,
+    pub vout_count: i64,
 }
 
 impl TxRow {
     pub fn from_sqlite_row(row: &rusqlite::Row) -> Result<Self> {
         let mut v = TxRow::from_sqlite_row_partial(row)?;
-        let data_str = row.get::<_, String>(7)?;
+        let data_str = sqlite_json_text(row.get_ref(7)?)?;
         let icx_data_str = row.get::<_, String>(8)?;
         if !data_str.is_empty() {
             v.data = serde_json::from_str(&data_str)?;
@@ -154,6 +550,41 @@ impl TxRow {
         } else {
             serde_json::from_str(dvm_out_str)?
         };
+
+        let swap_pools_ref = row.get_ref(14)?;
+        let swap_pools_str = swap_pools_ref.as_str().map_err(|_| "swap_pools error")?;
+        let swap_pools = if swap_pools_str.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_str(swap_pools_str)?
+        };
+
+        let transfer_to_ref = row.get_ref(16)?;
+        let transfer_to_str = transfer_to_ref.as_str().map_err(|_| "transfer_to error")?;
+        let transfer_to = if transfer_to_str.is_empty() {
+            HashMap::new()
+        } else {
+            serde_json::from_str(transfer_to_str)?
+        };
+
+        let tx_out_types_ref = row.get_ref(18)?;
+        let tx_out_types_str = tx_out_types_ref
+            .as_str()
+            .map_err(|_| "tx_out_types error")?;
+        let tx_out_types = if tx_out_types_str.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_str(tx_out_types_str)?
+        };
+
+        let icx_links_ref = row.get_ref(19)?;
+        let icx_links_str = icx_links_ref.as_str().map_err(|_| "icx_links error")?;
+        let icx_links = if icx_links_str.is_empty() {
+            Vec::new()
+        } else {
+            serde_json::from_str(icx_links_str)?
+        };
+
         Ok(Self {
             txid: row.get(0)?,
             height: row.get(1)?,
@@ -169,6 +600,14 @@ impl TxRow {
             swap_from: row.get(11)?,
             swap_to: row.get(12)?,
             swap_amt: row.get(13)?,
+            swap_pools,
+            transfer_from: row.get(15)?,
+            transfer_to,
+            fee: row.get(17)?,
+            tx_out_types,
+            icx_links,
+            vin_count: row.get(20)?,
+            vout_count: row.get(21)?,
         })
     }
 }
@@ -197,6 +636,22 @@ pub fn sqlite_create_index_factory_v2(
             "CREATE INDEX IF NOT EXISTS idx_txs_swap_to ON txs (swap_to)",
             "idx_txs_swap_to",
         ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_txs_transfer_from ON txs (transfer_from)",
+            "idx_txs_transfer_from",
+        ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_txs_vin_count ON txs (vin_count)",
+            "idx_txs_vin_count",
+        ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_txs_vout_count ON txs (vout_count)",
+            "idx_txs_vout_count",
+        ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_blocks_stats_tx_count ON blocks_stats (tx_count)",
+            "idx_blocks_stats_tx_count",
+        ),
         (
             "CREATE INDEX IF NOT EXISTS idx_tx_addr_graph_txid ON tx_addr_graph (txid)",
             "idx_tx_addr_graph_txid",
@@ -209,6 +664,26 @@ pub fn sqlite_create_index_factory_v2(
             "CREATE INDEX IF NOT EXISTS idx_tx_addr_graph_out_addr ON tx_addr_graph (out_addr)",
             "idx_tx_addr_graph_out_addr",
         ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_tx_addr_graph_token ON tx_addr_graph (token)",
+            "idx_tx_addr_graph_token",
+        ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_address_tx_height ON address_tx (height)",
+            "idx_address_tx_height",
+        ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_utxos_address ON utxos (address)",
+            "idx_utxos_address",
+        ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_utxos_height ON utxos (height)",
+            "idx_utxos_height",
+        ),
+        (
+            "CREATE INDEX IF NOT EXISTS idx_utxos_spent_height ON utxos (spent_height)",
+            "idx_utxos_spent_height",
+        ),
     ];
 
     let mut itr = indexes.into_iter();
@@ -222,31 +697,167 @@ pub fn sqlite_create_index_factory_v2(
     })
 }
 
-pub fn sqlite_get_stmts_v2(conn: &rusqlite::Connection) -> Result<[CachedStatement<'_>; 3]> {
-    let insert_block_stmt = conn.prepare_cached(
-        "
-        insert or replace into blocks (height, hash, data)
-        values (?1, ?2, ?3)
-    ",
-    )?;
+pub fn sqlite_index_exists(conn: &rusqlite::Connection, name: &str) -> rusqlite::Result<bool> {
+    conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = ?1)",
+        [name],
+        |r| r.get(0),
+    )
+}
 
-    let insert_tx_stmt = conn.prepare_cached(
-        "
-        insert or replace into txs (
-            txid, height, tx_type, tx_in, tx_out, dvm_in, dvm_out, data, icx_data, icx_addr, icx_btc_exp_amt, swap_from, swap_to, swap_amt
-        )
-        values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14)
-    ",
-    )?;
+/// Refresh the query planner stats, and optionally compact the file.
+/// Meant to be run once the indexes are in place.
+pub fn sqlite_optimize(conn: &Connection, vacuum: bool, quit: &AtomicBool) -> Result<()> {
+    if quit.load(Ordering::Relaxed) {
+        info!("int: early exit optimize");
+        return Ok(());
+    }
+    info!("analyze..");
+    conn.execute_batch("ANALYZE")?;
 
-    let insert_tx_addr_graph_stmt = conn.prepare_cached(
-        "
-        insert or replace into tx_addr_graph (txid, in_addr, out_addr, c_flags)
-        values (?1, ?2, ?3, ?4)
-    ",
-    )?;
+    if !vacuum || quit.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let size_before = sqlite_file_size(conn)?;
+    info!("vacuum..");
+    conn.execute_batch("VACUUM")?;
+    let size_after = sqlite_file_size(conn)?;
+    info!(
+        "vacuum: {} MiB -> {} MiB",
+        size_before / (1024 * 1024),
+        size_after / (1024 * 1024)
+    );
+    Ok(())
+}
+
+// Size of the main db file. The wal is checkpointed first, so that
+// what's still pending in it is accounted for.
+fn sqlite_file_size(conn: &Connection) -> Result<u64> {
+    conn.execute_batch("pragma wal_checkpoint(truncate)")?;
+    match conn.path() {
+        Some(path) if !path.is_empty() => Ok(std::fs::metadata(path)?.len()),
+        _ => Ok(0),
+    }
+}
+
+// All inserts are upserts so that re-running over an already indexed
+// range (eg: partial re-index after a parser fix) doesn't fail on the
+// primary keys. The graph table upserts on (txid, in_addr, out_addr, token).
+const SQLITE_INSERT_BLOCK_V2: &str = "
+    insert or replace into blocks (height, hash, data)
+    values (?1, ?2, ?3)
+";
+
+const SQLITE_INSERT_TX_V2: &str = "
+    insert or replace into txs (
+        txid, height, tx_type, tx_in, tx_out, dvm_in, dvm_out, data, icx_data, icx_addr, icx_btc_exp_amt, swap_from, swap_to, swap_amt, swap_pools, transfer_from, transfer_to, fee, tx_out_types, icx_links, vin_count, vout_count
+    )
+    values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22)
+";
+
+const SQLITE_INSERT_TX_ADDR_GRAPH_V2: &str = "
+    insert or replace into tx_addr_graph (txid, in_addr, out_addr, c_flags, value, token)
+    values (?1, ?2, ?3, ?4, ?5, ?6)
+";
+
+const SQLITE_INSERT_BLOCK_STATS_V2: &str = "
+    insert or replace into blocks_stats
+        (height, tx_count, total_vout_value, coinbase_value, size, coinbase_addrs)
+    values (?1, ?2, ?3, ?4, ?5, ?6)
+";
+
+const SQLITE_INSERT_UTXO_V2: &str = "
+    insert or replace into utxos (txid, n, address, value, height)
+    values (?1, ?2, ?3, ?4, ?5)
+";
+
+const SQLITE_SPEND_UTXO_V2: &str = "
+    UPDATE utxos SET spent_txid = ?3, spent_height = ?4 WHERE txid = ?1 AND n = ?2
+";
+
+const SQLITE_INSERT_ADDRESS_TX_V2: &str = "
+    insert or replace into address_tx (address, txid, height, direction, value)
+    values (?1, ?2, ?3, ?4, ?5)
+";
+
+const SQLITE_SELECT_TXS_BY_ADDRESS_TABLE: &str = "
+    SELECT * FROM txs WHERE txid IN
+        (SELECT txid FROM address_tx WHERE address = ?1)
+    ORDER BY height
+";
+
+// Addresses are the keys of tx_in and tx_out, and the items of dvm_out,
+// which has all the dvm ones. Matched quoted, so it's the whole address.
+const SQLITE_SELECT_TXS_BY_ADDRESS_SCAN: &str = "
+    SELECT * FROM txs
+    WHERE instr(tx_in, '\"' || ?1 || '\"') > 0
+        OR instr(tx_out, '\"' || ?1 || '\"') > 0
+        OR instr(dvm_out, '\"' || ?1 || '\"') > 0
+    ORDER BY height
+";
 
-    Ok([insert_block_stmt, insert_tx_stmt, insert_tx_addr_graph_stmt])
+// Keys of the dindexer_meta table.
+pub const META_NETWORK: &str = "network";
+pub const META_SCHEMA_VERSION: &str = "schema_version";
+pub const META_CRATE_VERSION: &str = "crate_version";
+pub const META_GRAPH_TABLE: &str = "graph_table";
+pub const META_ADDRESS_TABLE: &str = "address_table";
+pub const META_MIN_HEIGHT: &str = "min_height";
+pub const META_MAX_HEIGHT: &str = "max_height";
+pub const META_CREATED_AT: &str = "created_at";
+pub const META_RUN_STARTED_AT: &str = "run_started_at";
+pub const META_RUN_FINISHED_AT: &str = "run_finished_at";
+/// The height the balances table was last built at.
+pub const META_BALANCES_HEIGHT: &str = "balances_height";
+/// Set when only every nth height was indexed, see `--stride`.
+pub const META_STRIDE: &str = "stride";
+
+// Consistency checks, these are the same on sqlite and postgres.
+pub const INDEX_TABLES: [&str; 7] = [
+    "blocks",
+    "blocks_stats",
+    "txs",
+    "tx_addr_graph",
+    "address_tx",
+    "utxos",
+    "tokens",
+];
+
+pub(crate) const SQL_SELECT_HEIGHT_RANGE: &str = "SELECT min(height), max(height) FROM blocks";
+
+pub(crate) const SQL_SELECT_HEIGHT_GAPS: &str = "
+    SELECT height + 1, next_height - 1 FROM (
+        SELECT height, LEAD(height) OVER (ORDER BY height) AS next_height FROM blocks
+    ) t
+    WHERE next_height > height + 1
+    ORDER BY height
+";
+
+pub(crate) const SQL_SELECT_ORPHAN_TX_HEIGHTS: &str = "
+    SELECT t.height, count(*) FROM txs t
+    LEFT JOIN blocks b ON b.height = t.height
+    WHERE b.height IS NULL
+    GROUP BY t.height
+    ORDER BY t.height
+";
+
+pub fn sqlite_get_stmts_v2(conn: &rusqlite::Connection) -> Result<[CachedStatement<'_>; 4]> {
+    let insert_block_stmt = conn.prepare_cached(SQLITE_INSERT_BLOCK_V2)?;
+    let insert_tx_stmt = conn.prepare_cached(SQLITE_INSERT_TX_V2)?;
+    let insert_tx_addr_graph_stmt = conn.prepare_cached(SQLITE_INSERT_TX_ADDR_GRAPH_V2)?;
+    let insert_block_stats_stmt = conn.prepare_cached(SQLITE_INSERT_BLOCK_STATS_V2)?;
+    Ok([
+        insert_block_stmt,
+        insert_tx_stmt,
+        insert_tx_addr_graph_stmt,
+        insert_block_stats_stmt,
+    ])
+}
+
+pub fn sqlite_get_max_height(conn: &rusqlite::Connection) -> Result<Option<i64>> {
+    let height: Option<i64> =
+        conn.query_row("SELECT max(height) FROM blocks", [], |row| row.get(0))?;
+    Ok(height)
 }
 
 // Raw tx to get around the borrow checker.
@@ -272,14 +883,126 @@ pub struct TxAddrData {
     pub tx_out: HashMap<String, f64>,
 }
 
+/// A row of the txs table, with all the json columns already serialized.
+/// Empty strings are used for the fields that don't apply to the tx.
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct TxRecord<'a> {
+    pub txid: &'a str,
+    pub height: i64,
+    pub tx_type: &'a str,
+    pub tx_in: &'a str,
+    pub tx_out: &'a str,
+    pub dvm_in: &'a str,
+    pub dvm_out: &'a str,
+    pub data: &'a str,
+    pub icx_data: &'a str,
+    pub icx_addr: &'a str,
+    pub icx_btc_exp_amt: &'a str,
+    pub swap_from: &'a str,
+    pub swap_to: &'a str,
+    pub swap_amt: &'a str,
+    pub swap_pools: &'a str,
+    pub transfer_from: &'a str,
+    pub transfer_to: &'a str,
+    /// Inputs less outputs, 8 decimals. Empty for coinbase txs.
+    pub fee: &'a str,
+    /// Json array of the script type of each out, in order.
+    pub tx_out_types: &'a str,
+    /// Json array of the ICX swaps the tx is part of, see `IcxLink`.
+    pub icx_links: &'a str,
+    /// Number of ins, a coinbase counts as one.
+    pub vin_count: i64,
+    pub vout_count: i64,
+}
+
+/// A row of the tx_addr_graph table, an in address to out address edge
+/// of a tx.
+#[derive(Debug, Clone, Default)]
+pub struct EdgeRecord<'a> {
+    pub txid: &'a str,
+    pub in_addr: &'a str,
+    pub out_addr: &'a str,
+    /// Symbol of the token moved, empty when it isn't known.
+    pub token: &'a str,
+    /// 0 for utxo, 1 for dvm, 2 for both.
+    pub c_flags: i64,
+    /// Estimate of the value moved along the edge, see `index_block`.
+    pub value: f64,
+}
+
+/// A row of the blocks_stats table. Values are in coins, the same as
+/// the tx outputs they're summed from.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct BlockStats {
+    pub height: i64,
+    pub tx_count: i64,
+    pub total_vout_value: f64,
+    pub coinbase_value: f64,
+    pub size: i64,
+    /// Json object of the addresses the coinbase paid, with the value to
+    /// each. The block reward split on DeFiChain, eg. the masternode
+    /// operator and the community fund.
+    pub coinbase_addrs: String,
+}
+
 pub trait BlockStore {
     fn get_block_from_hash(&self, hash: &str) -> Result<Option<Block>>;
     fn get_block_hash(&self, height: i64) -> Result<Option<String>>;
     fn get_block_hash_for_tx(&self, tx_hash: &str) -> Result<Option<String>>;
     fn get_block_for_tx(&self, tx_hash: &str) -> Result<Option<Block>>;
     fn get_block_from_height(&self, height: i64) -> Result<Option<Block>>;
+    /// The block json as it was stored.
+    fn get_block_json(&self, height: i64) -> Result<Option<String>>;
     fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>>;
     fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>>;
+    /// Height the tx is stored at, if it is.
+    fn get_tx_height(&self, txid: &str) -> Result<Option<i64>>;
+    fn get_max_height(&self) -> Result<Option<i64>>;
+    fn get_token_symbols(&self) -> Result<HashMap<String, String>>;
+    /// Lowest and highest stored block heights.
+    fn get_height_range(&self) -> Result<Option<(i64, i64)>>;
+    /// Runs of missing heights between the lowest and highest stored
+    /// block, as inclusive ranges in order.
+    fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>>;
+    /// Heights that have txs stored but no block, with the tx count.
+    fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>>;
+    /// Key/values describing how the db was built.
+    fn get_meta(&self) -> Result<HashMap<String, String>>;
+    /// Row count of each of the `INDEX_TABLES`.
+    fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>>;
+
+    fn get_txout_by_outpoint(&self, txid: &str, n: u64) -> Result<Option<Vout>> {
+        let tx = self.get_tx_from_hash(txid)?;
+        Ok(tx.and_then(|tx| tx.vout.into_iter().find(|v| v.n == n)))
+    }
+
+    // Writes. Stores are expected to batch these between begin and commit.
+
+    fn put_block(&self, height: i64, hash: &str, data: &str) -> Result<()>;
+    fn put_block_stats(&self, stats: &BlockStats) -> Result<()>;
+    fn put_tx(&self, tx: &TxRecord) -> Result<()>;
+    fn put_edge(&self, edge: &EdgeRecord) -> Result<()>;
+    /// `direction` is one of `in`, `out` or `dvm`, see the address_tx table.
+    fn put_address_tx(
+        &self,
+        address: &str,
+        txid: &str,
+        height: i64,
+        direction: &str,
+        value: f64,
+    ) -> Result<()>;
+    /// An out to an address, unspent until `spend_utxo`.
+    fn put_utxo(&self, txid: &str, n: u64, address: &str, value: f64, height: i64) -> Result<()>;
+    /// Mark the out as spent by `spent_txid` at `height`. Outs that were
+    /// never put are left alone.
+    fn spend_utxo(&self, txid: &str, n: u64, spent_txid: &str, height: i64) -> Result<()>;
+    fn put_token_symbols(&self, symbols: &HashMap<String, String>) -> Result<()>;
+    fn put_meta(&self, meta: &HashMap<String, String>) -> Result<()>;
+    /// Remove the block at height along with its stats, txs and graph
+    /// edges. The outs it spent are unspent again.
+    fn delete_height(&self, height: i64) -> Result<()>;
+    fn begin(&self) -> Result<()>;
+    fn commit(&self) -> Result<()>;
 }
 
 impl BlockStore for SqliteBlockStore {
@@ -303,6 +1026,10 @@ impl BlockStore for SqliteBlockStore {
         self.get_block_from_height(height)
     }
 
+    fn get_block_json(&self, height: i64) -> Result<Option<String>> {
+        self.get_block_json(height)
+    }
+
     fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
         self.get_tx_from_hash(hash)
     }
@@ -310,22 +1037,369 @@ impl BlockStore for SqliteBlockStore {
     fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>> {
         self.get_tx_addr_data_from_hash(hash)
     }
+
+    fn get_tx_height(&self, txid: &str) -> Result<Option<i64>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT height FROM txs WHERE txid = ?1")?;
+        Ok(stmt.query_row([txid], |row| row.get(0)).optional()?)
+    }
+
+    fn get_max_height(&self) -> Result<Option<i64>> {
+        sqlite_get_max_height(&self.conn)
+    }
+
+    fn get_token_symbols(&self) -> Result<HashMap<String, String>> {
+        let mut stmt = self.conn.prepare_cached("SELECT id, symbol FROM tokens")?;
+        let res = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        Ok(res)
+    }
+
+    fn get_height_range(&self) -> Result<Option<(i64, i64)>> {
+        let range: (Option<i64>, Option<i64>) =
+            self.conn.query_row(SQL_SELECT_HEIGHT_RANGE, [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+        Ok(range.0.zip(range.1))
+    }
+
+    fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>> {
+        self.query_height_pairs(SQL_SELECT_HEIGHT_GAPS)
+    }
+
+    fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>> {
+        self.query_height_pairs(SQL_SELECT_ORPHAN_TX_HEIGHTS)
+    }
+
+    fn get_meta(&self) -> Result<HashMap<String, String>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT key, value FROM dindexer_meta")?;
+        let res = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+        Ok(res)
+    }
+
+    fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+        self.flush_edges()?;
+        let mut res = Vec::with_capacity(INDEX_TABLES.len());
+        for table in INDEX_TABLES {
+            let q = format!("SELECT count(*) FROM {}", table);
+            res.push((table, self.conn.query_row(&q, [], |row| row.get(0))?));
+        }
+        Ok(res)
+    }
+
+    fn put_block(&self, height: i64, hash: &str, data: &str) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(SQLITE_INSERT_BLOCK_V2)?;
+        stmt.execute(params![height, hash, self.json_col(data)?])?;
+        Ok(())
+    }
+
+    fn put_block_stats(&self, stats: &BlockStats) -> Result<()> {
+        // The stats are the last of a block, its edges go in together.
+        self.flush_edges()?;
+        let mut stmt = self.conn.prepare_cached(SQLITE_INSERT_BLOCK_STATS_V2)?;
+        stmt.execute(params![
+            stats.height,
+            stats.tx_count,
+            stats.total_vout_value,
+            stats.coinbase_value,
+            stats.size,
+            stats.coinbase_addrs,
+        ])?;
+        Ok(())
+    }
+
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(SQLITE_INSERT_TX_V2)?;
+        stmt.execute(params![
+            tx.txid,
+            tx.height,
+            tx.tx_type,
+            tx.tx_in,
+            tx.tx_out,
+            tx.dvm_in,
+            tx.dvm_out,
+            self.json_col(tx.data)?,
+            tx.icx_data,
+            tx.icx_addr,
+            tx.icx_btc_exp_amt,
+            tx.swap_from,
+            tx.swap_to,
+            tx.swap_amt,
+            tx.swap_pools,
+            tx.transfer_from,
+            tx.transfer_to,
+            tx.fee,
+            tx.tx_out_types,
+            tx.icx_links,
+            tx.vin_count,
+            tx.vout_count,
+        ])?;
+        Ok(())
+    }
+
+    fn put_edge(&self, edge: &EdgeRecord) -> Result<()> {
+        let len = {
+            let mut edges = self.edges.borrow_mut();
+            edges.push(SqliteEdgeRow {
+                txid: edge.txid.to_owned(),
+                in_addr: edge.in_addr.to_owned(),
+                out_addr: edge.out_addr.to_owned(),
+                c_flags: edge.c_flags,
+                value: edge.value,
+                token: edge.token.to_owned(),
+            });
+            edges.len()
+        };
+        if len >= SQLITE_EDGE_FLUSH_ROWS {
+            self.flush_edges()?;
+        }
+        Ok(())
+    }
+
+    fn put_address_tx(
+        &self,
+        address: &str,
+        txid: &str,
+        height: i64,
+        direction: &str,
+        value: f64,
+    ) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(SQLITE_INSERT_ADDRESS_TX_V2)?;
+        stmt.execute(params![address, txid, height, direction, value])?;
+        Ok(())
+    }
+
+    fn put_utxo(&self, txid: &str, n: u64, address: &str, value: f64, height: i64) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(SQLITE_INSERT_UTXO_V2)?;
+        stmt.execute(params![txid, n, address, value, height])?;
+        Ok(())
+    }
+
+    fn spend_utxo(&self, txid: &str, n: u64, spent_txid: &str, height: i64) -> Result<()> {
+        let mut stmt = self.conn.prepare_cached(SQLITE_SPEND_UTXO_V2)?;
+        stmt.execute(params![txid, n, spent_txid, height])?;
+        Ok(())
+    }
+
+    fn put_token_symbols(&self, symbols: &HashMap<String, String>) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("insert or replace into tokens (id, symbol) values (?1, ?2)")?;
+        for (id, symbol) in symbols {
+            stmt.execute(params![id, symbol])?;
+        }
+        Ok(())
+    }
+
+    fn put_meta(&self, meta: &HashMap<String, String>) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("insert or replace into dindexer_meta (key, value) values (?1, ?2)")?;
+        for (key, value) in meta {
+            stmt.execute(params![key, value])?;
+        }
+        Ok(())
+    }
+
+    fn delete_height(&self, height: i64) -> Result<()> {
+        self.delete_range(height, height).map(|_| ())
+    }
+
+    fn begin(&self) -> Result<()> {
+        sqlite_begin_tx(&self.conn).map(|_| ())
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.flush_edges()?;
+        sqlite_commit_tx(&self.conn)
+    }
 }
 
 pub struct SqliteBlockStore {
     pub conn: Connection,
+    /// See `SqliteOpts::compress_blobs`.
+    pub compress_blobs: bool,
+    /// Graph edges yet to be written, see `flush_edges`.
+    edges: RefCell<Vec<SqliteEdgeRow>>,
+}
+
+struct SqliteEdgeRow {
+    txid: String,
+    in_addr: String,
+    out_addr: String,
+    c_flags: i64,
+    value: f64,
+    token: String,
+}
+
+// Edges are written a block at a time, or at this many if a block has
+// more, in statements of `SQLITE_EDGE_BATCH_ROWS` rows each, and the
+// rows left over one at a time. A statement for every other row count
+// would churn the statement cache.
+const SQLITE_EDGE_FLUSH_ROWS: usize = 10_000;
+const SQLITE_EDGE_BATCH_ROWS: usize = 500;
+
+// Multi-row `insert or replace` into the graph table. Rows later in the
+// statement replace the earlier ones with the same key, the same as
+// they would one at a time.
+fn sqlite_insert_edges_query(rows: usize) -> String {
+    let values = vec!["(?, ?, ?, ?, ?, ?)"; rows].join(", ");
+    format!(
+        "insert or replace into tx_addr_graph (txid, in_addr, out_addr, c_flags, value, token) values {}",
+        values
+    )
+}
+
+impl Drop for SqliteBlockStore {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_edges() {
+            warn!("sqlite: edges not written: {}", e);
+        }
+    }
+}
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// The json of a blocks or txs data column, which is a zstd compressed
+/// blob when it was stored with `--compress-blobs`.
+pub fn sqlite_json_text(v: ValueRef) -> Result<Cow<str>> {
+    match v {
+        ValueRef::Text(t) => Ok(Cow::Borrowed(std::str::from_utf8(t)?)),
+        ValueRef::Blob(b) => Ok(Cow::Owned(String::from_utf8(zstd::decode_all(b)?)?)),
+        ValueRef::Null => Ok(Cow::Borrowed("")),
+        _ => Err("json column: not text or blob".into()),
+    }
 }
 
 impl SqliteBlockStore {
+    fn query_height_pairs(&self, q: &str) -> Result<Vec<(i64, i64)>> {
+        let mut stmt = self.conn.prepare_cached(q)?;
+        let res = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(res)
+    }
+
+    /// Delete everything in the height range, inclusive. Graph edges
+    /// go first as they're only linked through the txs. Returns the rows
+    /// removed per table. The outs spent in the range are unspent again,
+    /// as if it was never indexed.
+    pub fn delete_range(&self, from: i64, to: i64) -> Result<[(&'static str, usize); 6]> {
+        self.flush_edges()?;
+        let graph = self.conn.execute(
+            "DELETE FROM tx_addr_graph WHERE txid IN
+                (SELECT txid FROM txs WHERE height BETWEEN ?1 AND ?2)",
+            params![from, to],
+        )?;
+        let address_tx = self.conn.execute(
+            "DELETE FROM address_tx WHERE height BETWEEN ?1 AND ?2",
+            params![from, to],
+        )?;
+        let utxos = self.conn.execute(
+            "DELETE FROM utxos WHERE height BETWEEN ?1 AND ?2",
+            params![from, to],
+        )?;
+        self.conn.execute(
+            "UPDATE utxos SET spent_txid = NULL, spent_height = NULL
+            WHERE spent_height BETWEEN ?1 AND ?2",
+            params![from, to],
+        )?;
+        let txs = self.conn.execute(
+            "DELETE FROM txs WHERE height BETWEEN ?1 AND ?2",
+            params![from, to],
+        )?;
+        let stats = self.conn.execute(
+            "DELETE FROM blocks_stats WHERE height BETWEEN ?1 AND ?2",
+            params![from, to],
+        )?;
+        let blocks = self.conn.execute(
+            "DELETE FROM blocks WHERE height BETWEEN ?1 AND ?2",
+            params![from, to],
+        )?;
+        Ok([
+            ("tx_addr_graph", graph),
+            ("address_tx", address_tx),
+            ("utxos", utxos),
+            ("txs", txs),
+            ("blocks_stats", stats),
+            ("blocks", blocks),
+        ])
+    }
+
     #[cfg(feature = "legacy-sqlite-v1")]
     pub fn new_v1(path: Option<&str>) -> Result<Self> {
         let conn = crate::db::sqlite_v1::sqlite_init_db_v1(path)?;
-        Ok(Self { conn })
+        Ok(Self {
+            conn,
+            compress_blobs: false,
+            edges: RefCell::new(Vec::new()),
+        })
     }
 
     pub fn new_v2(path: Option<&str>) -> Result<Self> {
-        let conn = sqlite_init_db_v2(path)?;
-        Ok(Self { conn })
+        Self::new_v2_with_opts(path, &SqliteOpts::default())
+    }
+
+    pub fn new_v2_with_opts(path: Option<&str>, opts: &SqliteOpts) -> Result<Self> {
+        let conn = sqlite_init_db_v2(path, opts)?;
+        Ok(Self {
+            conn,
+            compress_blobs: opts.compress_blobs,
+            edges: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Write the graph edges put so far, a batch of rows per statement
+    /// instead of one each, as dense blocks can have thousands. Done at
+    /// the end of each block, on commit and before the graph table is
+    /// read or deleted from here.
+    pub fn flush_edges(&self) -> Result<()> {
+        static BATCH_QUERY: LazyLock<String> =
+            LazyLock::new(|| sqlite_insert_edges_query(SQLITE_EDGE_BATCH_ROWS));
+        let edges = std::mem::take(&mut *self.edges.borrow_mut());
+        let mut chunks = edges.chunks_exact(SQLITE_EDGE_BATCH_ROWS);
+        for chunk in &mut chunks {
+            let mut stmt = self.conn.prepare_cached(&BATCH_QUERY)?;
+            let mut params = Vec::<&dyn rusqlite::ToSql>::with_capacity(chunk.len() * 6);
+            for edge in chunk {
+                params.extend([
+                    &edge.txid as &dyn rusqlite::ToSql,
+                    &edge.in_addr,
+                    &edge.out_addr,
+                    &edge.c_flags,
+                    &edge.value,
+                    &edge.token,
+                ]);
+            }
+            stmt.execute(params.as_slice())?;
+        }
+        let mut stmt = self.conn.prepare_cached(SQLITE_INSERT_TX_ADDR_GRAPH_V2)?;
+        for edge in chunks.remainder() {
+            stmt.execute(params![
+                edge.txid,
+                edge.in_addr,
+                edge.out_addr,
+                edge.c_flags,
+                edge.value,
+                edge.token
+            ])?;
+        }
+        Ok(())
+    }
+
+    /// The json as it's written to the data columns.
+    fn json_col<'a>(&self, json: &'a str) -> Result<ToSqlOutput<'a>> {
+        if !self.compress_blobs {
+            return Ok(ToSqlOutput::from(json));
+        }
+        let blob = zstd::encode_all(json.as_bytes(), ZSTD_LEVEL)?;
+        Ok(ToSqlOutput::from(blob))
     }
 
     // Note index for this might not be there in the beginning.
@@ -360,14 +1434,20 @@ impl SqliteBlockStore {
         Ok(hash)
     }
 
-    pub fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+    pub fn get_block_json(&self, height: i64) -> Result<Option<String>> {
         let mut stmt = self
             .conn
             .prepare_cached("SELECT data FROM blocks WHERE height = ?1")?;
-        let block: Option<String> = stmt
-            .query_row(params![height], |row| row.get(0))
+        let data = stmt
+            .query_row(params![height], |row| {
+                Ok(sqlite_json_text(row.get_ref(0)?).map(Cow::into_owned))
+            })
             .optional()?;
-        match block {
+        data.transpose()
+    }
+
+    pub fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+        match self.get_block_json(height)? {
             Some(data) => {
                 let block: Block = serde_json::from_str(&data)?;
                 Ok(Some(block))
@@ -380,7 +1460,12 @@ impl SqliteBlockStore {
         let mut stmt = self
             .conn
             .prepare_cached("SELECT data FROM blocks WHERE hash = ?1")?;
-        let block: Option<String> = stmt.query_row(params![hash], |row| row.get(0)).optional()?;
+        let block = stmt
+            .query_row(params![hash], |row| {
+                Ok(sqlite_json_text(row.get_ref(0)?).map(Cow::into_owned))
+            })
+            .optional()?
+            .transpose()?;
         match block {
             Some(data) => {
                 let block: Block = serde_json::from_str(&data)?;
@@ -407,7 +1492,12 @@ impl SqliteBlockStore {
         let mut stmt = self
             .conn
             .prepare_cached("SELECT data FROM txs WHERE txid = ?1")?;
-        let tx: Option<String> = stmt.query_row(params![hash], |row| row.get(0)).optional()?;
+        let tx = stmt
+            .query_row(params![hash], |row| {
+                Ok(sqlite_json_text(row.get_ref(0)?).map(Cow::into_owned))
+            })
+            .optional()?
+            .transpose()?;
         match tx {
             Some(data) => {
                 let tx: Transaction = serde_json::from_str(&data)?;
@@ -455,8 +1545,8 @@ impl SqliteBlockStore {
         let mut stmt = self.conn.prepare(&query)?;
         let mut q = stmt.query([])?;
         while let Some(row) = q.next()? {
-            let data: &str = row.get_ref(0)?.as_str().map_err(|_| "ref error")?;
-            let block: Result<Block> = serde_json::from_str(data).map_err(|e| e.into());
+            let data = sqlite_json_text(row.get_ref(0)?)?;
+            let block: Result<Block> = serde_json::from_str(&data).map_err(|e| e.into());
             f(block)?;
         }
         Ok(())
@@ -542,12 +1632,288 @@ impl SqliteBlockStore {
         let mut stmt = self
             .conn
             .prepare_cached("SELECT * FROM txs WHERE txid = ?1")?;
-        let tx_row = stmt
+        let mut q = stmt.query(params![txid])?;
+        match q.next()? {
+            Some(row) => Ok(Some(TxRow::from_sqlite_row(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// All the txs touching `address`, as an input, an output or in the
+    /// dvm message, oldest first. Uses the address_tx table if it's been
+    /// filled for the whole db, see `META_ADDRESS_TABLE`, and otherwise
+    /// scans the json address columns of all the txs.
+    pub fn get_txs_by_address(&self, address: &str) -> Result<Vec<TxRow>> {
+        let meta = self.get_meta()?;
+        let query = match meta.get(META_ADDRESS_TABLE).map(String::as_str) {
+            Some("true") => SQLITE_SELECT_TXS_BY_ADDRESS_TABLE,
+            _ => {
+                info!("query: no address table, scanning the txs");
+                SQLITE_SELECT_TXS_BY_ADDRESS_SCAN
+            }
+        };
+        let mut stmt = self.conn.prepare_cached(query)?;
+        let mut q = stmt.query(params![address])?;
+        let mut res = Vec::new();
+        while let Some(row) = q.next()? {
+            res.push(TxRow::from_sqlite_row(row)?);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sqlite_busy_timeout() {
+        let opts = SqliteOpts {
+            busy_timeout_ms: 1234,
+            ..Default::default()
+        };
+        let store = SqliteBlockStore::new_v2_with_opts(Some(":memory:"), &opts).unwrap();
+        let timeout: i64 = store
+            .conn
+            .query_row("pragma busy_timeout", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(timeout, 1234);
+    }
+
+    #[test]
+    fn test_sqlite_page_size_and_temp_store() {
+        let path = std::env::temp_dir().join("dindexer-test-page-size.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+        let open = |page_size| {
+            let opts = SqliteOpts {
+                page_size: Some(page_size),
+                temp_store: "memory".to_owned(),
+                ..Default::default()
+            };
+            let store = SqliteBlockStore::new_v2_with_opts(Some(path), &opts).unwrap();
+            let pragma = |name: &str| -> i64 {
+                let sql = format!("pragma {}", name);
+                store.conn.query_row(&sql, [], |row| row.get(0)).unwrap()
+            };
+            (pragma("page_size"), pragma("temp_store"))
+        };
+
+        assert_eq!(open(16384), (16384, 2));
+        // Kept for the existing db.
+        assert_eq!(open(8192), (16384, 2));
+        assert!(parse_page_size("1000").is_err());
+        assert!(parse_page_size("131072").is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_compress_blobs() {
+        let opts = SqliteOpts {
+            compress_blobs: true,
+            ..Default::default()
+        };
+        let mut store = SqliteBlockStore::new_v2_with_opts(Some(":memory:"), &opts).unwrap();
+        let block = r#"{"hash":"h1","tx":[]}"#;
+        let tx_json = serde_json::json!({
+            "txid": "t1", "hash": "t1", "version": 1, "size": 0, "vsize": 0,
+            "weight": 0, "locktime": 0, "hex": "", "vin": [], "vout": [],
+        })
+        .to_string();
+        store.put_block(1, "h1", block).unwrap();
+        store
+            .put_tx(&TxRecord {
+                txid: "t1",
+                height: 1,
+                data: &tx_json,
+                ..Default::default()
+            })
+            .unwrap();
+        // Existing text rows are read the same.
+        store.compress_blobs = false;
+        store.put_block(2, "h2", block).unwrap();
+
+        let types = store
+            .conn
+            .prepare("SELECT typeof(data) FROM blocks ORDER BY height")
+            .unwrap()
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(types, vec!["blob", "text"]);
+        assert_eq!(store.get_block_json(1).unwrap().as_deref(), Some(block));
+        assert_eq!(store.get_block_json(2).unwrap().as_deref(), Some(block));
+        let tx = store.get_tx_from_hash("t1").unwrap().unwrap();
+        assert_eq!(&*tx.txid, "t1");
+    }
+
+    #[test]
+    fn test_sqlite_migrate() {
+        let path = std::env::temp_dir().join("dindexer-test-migrate.sqlite");
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().unwrap();
+
+        // A db from before the schema was versioned, with just the
+        // initial tables and some data.
+        {
+            let conn = Connection::open(path).unwrap();
+            sqlite_init_tables_v2(&conn).unwrap();
+            conn.execute(
+                "insert into blocks (height, hash, data) values (1, 'h1', '{}')",
+                [],
+            )
+            .unwrap();
+        }
+
+        let store = SqliteBlockStore::new_v2(Some(path)).unwrap();
+        let meta = store.get_meta().unwrap();
+        assert_eq!(meta[META_SCHEMA_VERSION], SCHEMA_VERSION.to_string());
+        assert_eq!(store.get_block_hash(1).unwrap().as_deref(), Some("h1"));
+        let tx = TxRecord {
+            txid: "t1",
+            height: 1,
+            transfer_to: "{}",
+            vin_count: 2,
+            vout_count: 3,
+            ..Default::default()
+        };
+        store.put_tx(&tx).unwrap();
+        let counts: (i64, i64) = store
+            .conn
+            .query_row("SELECT vin_count, vout_count FROM txs", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(counts, (2, 3));
+        store.put_block_stats(&BlockStats::default()).unwrap();
+        // Edges of the same addresses are kept apart per token.
+        for token in ["dfi", "btc"] {
+            let edge = EdgeRecord {
+                txid: "t1",
+                in_addr: "a1",
+                out_addr: "a2",
+                token,
+                ..Default::default()
+            };
+            store.put_edge(&edge).unwrap();
+        }
+        store.flush_edges().unwrap();
+        let edges: i64 = store
+            .conn
+            .query_row("SELECT count(*) FROM tx_addr_graph", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(edges, 2);
+
+        // Already current, nothing to do.
+        drop(store);
+        SqliteBlockStore::new_v2(Some(path)).unwrap();
+
+        let newer = HashMap::from([(
+            META_SCHEMA_VERSION.to_owned(),
+            (SCHEMA_VERSION + 1).to_string(),
+        )]);
+        SqliteBlockStore::new_v2(Some(path))
+            .unwrap()
+            .put_meta(&newer)
+            .unwrap();
+        assert!(SqliteBlockStore::new_v2(Some(path)).is_err());
+    }
+
+    #[test]
+    fn test_sqlite_batched_edges() {
+        let store = SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let count = || -> i64 {
+            store
+                .conn
+                .query_row("SELECT count(*) FROM tx_addr_graph", [], |row| row.get(0))
+                .unwrap()
+        };
+        // More than a statement's worth, with the first edge put again
+        // at the end with another type, in another statement.
+        let outs = (0..SQLITE_EDGE_BATCH_ROWS + 10)
+            .map(|i| format!("b{}", i))
+            .collect::<Vec<_>>();
+        for (i, out_addr) in outs.iter().enumerate() {
+            let edge = EdgeRecord {
+                txid: "t1",
+                in_addr: "a",
+                out_addr,
+                c_flags: 0,
+                value: i as f64,
+                ..Default::default()
+            };
+            store.put_edge(&edge).unwrap();
+        }
+        let edge = EdgeRecord {
+            txid: "t1",
+            in_addr: "a",
+            out_addr: "b0",
+            c_flags: 2,
+            value: 1.5,
+            ..Default::default()
+        };
+        store.put_edge(&edge).unwrap();
+        assert_eq!(count(), 0);
+
+        store.put_block_stats(&BlockStats::default()).unwrap();
+        assert_eq!(count(), outs.len() as i64);
+        let b0: (i64, f64) = store
+            .conn
             .query_row(
-                params![txid],
-                |row| Ok(TxRow::from_sqlite_row(row).unwrap()),
+                "SELECT c_flags, value FROM tx_addr_graph WHERE out_addr = 'b0'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
             )
-            .optional()?;
-        Ok(tx_row)
+            .unwrap();
+        assert_eq!(b0, (2, 1.5));
+    }
+
+    #[test]
+    fn test_get_txs_by_address() {
+        let store = SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let txs = [
+            ("t2", 2, r#"{"a1":1.5}"#, r#"{"a2":1.5}"#, ""),
+            ("t1", 1, "", r#"{"a1":1.5}"#, ""),
+            ("t3", 3, "", r#"{"a1":1.0}"#, r#"["a1"]"#),
+            ("t4", 4, "", r#"{"a11":1.0}"#, ""),
+        ];
+        for (txid, height, tx_in, tx_out, dvm_out) in txs {
+            let tx = TxRecord {
+                txid,
+                height,
+                tx_in,
+                tx_out,
+                dvm_out,
+                ..Default::default()
+            };
+            store.put_tx(&tx).unwrap();
+        }
+        let txids = |addr| {
+            let txs = store.get_txs_by_address(addr).unwrap();
+            txs.into_iter().map(|x| x.txid).collect::<Vec<_>>()
+        };
+
+        // No address table, from the json columns.
+        assert_eq!(txids("a1"), vec!["t1", "t2", "t3"]);
+        assert_eq!(txids("a2"), vec!["t2"]);
+        assert!(txids("a3").is_empty());
+
+        let meta = HashMap::from([(META_ADDRESS_TABLE.to_owned(), "true".to_owned())]);
+        store.put_meta(&meta).unwrap();
+        store.put_address_tx("a1", "t1", 1, "out", 1.5).unwrap();
+        store.put_address_tx("a1", "t2", 2, "in", 1.5).unwrap();
+        store.put_address_tx("a2", "t2", 2, "out", 1.5).unwrap();
+        store.put_address_tx("a1", "t3", 3, "dvm", 0.).unwrap();
+        // Same tx through more than one side is listed once.
+        store.put_address_tx("a1", "t3", 3, "out", 1.).unwrap();
+        store.put_address_tx("a11", "t4", 4, "out", 1.).unwrap();
+        assert_eq!(txids("a1"), vec!["t1", "t2", "t3"]);
+        assert_eq!(txids("a2"), vec!["t2"]);
+        assert!(txids("a3").is_empty());
+
+        let removed = store.delete_range(3, 3).unwrap();
+        assert!(removed.contains(&("address_tx", 2)));
+        assert_eq!(txids("a1"), vec!["t1", "t2"]);
     }
 }
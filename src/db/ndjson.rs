@@ -0,0 +1,177 @@
+use crate::db::sink::TxSink;
+use crate::db::TxRecord;
+use crate::lang::Result;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Appends every tx as a json line to the output. Lines are buffered,
+/// and flushed at most a second apart and on every commit.
+/// A rolled back height is written as a `{"rollback":<height>}` line,
+/// and the txs at and above it should be dropped by the consumer.
+pub struct NdjsonSink {
+    out: RefCell<BufWriter<Box<dyn Write>>>,
+    last_flush: Cell<Instant>,
+}
+
+#[derive(Serialize)]
+struct NdjsonTx<'a> {
+    height: i64,
+    txid: &'a str,
+    tx_type: &'a str,
+    tx_in: serde_json::Value,
+    tx_out: serde_json::Value,
+    dvm_in: serde_json::Value,
+    dvm_out: serde_json::Value,
+    swap_from: Option<&'a str>,
+    swap_to: Option<&'a str>,
+    swap_amt: Option<&'a str>,
+    swap_pools: serde_json::Value,
+    transfer_from: Option<&'a str>,
+    transfer_to: serde_json::Value,
+    fee: Option<&'a str>,
+    tx_out_types: serde_json::Value,
+    icx_links: serde_json::Value,
+    vin_count: i64,
+    vout_count: i64,
+}
+
+impl NdjsonSink {
+    /// `path` is a file to append to, or `-` for stdout.
+    pub fn new(path: &str) -> Result<Self> {
+        let out: Box<dyn Write> = match path {
+            "-" => Box::new(std::io::stdout()),
+            path => Box::new(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+        };
+        Ok(Self {
+            out: RefCell::new(BufWriter::new(out)),
+            last_flush: Cell::new(Instant::now()),
+        })
+    }
+
+    fn write_line(&self, v: &impl Serialize) -> Result<()> {
+        let mut out = self.out.borrow_mut();
+        serde_json::to_writer(&mut *out, v)?;
+        out.write_all(b"\n")?;
+        if self.last_flush.get().elapsed() >= FLUSH_INTERVAL {
+            out.flush()?;
+            self.last_flush.set(Instant::now());
+        }
+        Ok(())
+    }
+}
+
+// Empty columns are null in the output, the json ones are nested as is.
+fn opt_str(s: &str) -> Option<&str> {
+    (!s.is_empty()).then_some(s)
+}
+
+fn opt_json(s: &str) -> Result<serde_json::Value> {
+    match s.is_empty() {
+        true => Ok(serde_json::Value::Null),
+        false => Ok(serde_json::from_str(s)?),
+    }
+}
+
+impl TxSink for NdjsonSink {
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        self.write_line(&NdjsonTx {
+            height: tx.height,
+            txid: tx.txid,
+            tx_type: tx.tx_type,
+            tx_in: opt_json(tx.tx_in)?,
+            tx_out: opt_json(tx.tx_out)?,
+            dvm_in: opt_json(tx.dvm_in)?,
+            dvm_out: opt_json(tx.dvm_out)?,
+            swap_from: opt_str(tx.swap_from),
+            swap_to: opt_str(tx.swap_to),
+            swap_amt: opt_str(tx.swap_amt),
+            swap_pools: opt_json(tx.swap_pools)?,
+            transfer_from: opt_str(tx.transfer_from),
+            transfer_to: opt_json(tx.transfer_to)?,
+            fee: opt_str(tx.fee),
+            tx_out_types: opt_json(tx.tx_out_types)?,
+            icx_links: opt_json(tx.icx_links)?,
+            vin_count: tx.vin_count,
+            vout_count: tx.vout_count,
+        })
+    }
+
+    fn rollback(&self, height: i64) -> Result<()> {
+        self.write_line(&serde_json::json!({ "rollback": height }))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.out.borrow_mut().flush()?;
+        self.last_flush.set(Instant::now());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::sink::SinkStore;
+    use crate::db::BlockStore;
+    use crate::dfiutils::TokenMap;
+    use crate::indexer::index_block;
+    use crate::indexer::tests::{
+        block_json, coinbase_json, spend_vin, test_ctx, tx_json, MemStore,
+    };
+    use crate::indexer::IndexCtx;
+    use crate::models::LogEntryMap;
+    use crate::models::TxType;
+
+    #[test]
+    fn test_index_block_ndjson_out() {
+        let path = std::env::temp_dir().join("dindexer-test-ndjson-out.ndjson");
+        let _ = std::fs::remove_file(&path);
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let spend = tx_json("t2", spend_vin("t1", 0), "a2");
+
+        {
+            let sink = NdjsonSink::new(path.to_str().unwrap()).unwrap();
+            let nd_store = SinkStore::new(&store, vec![Box::new(sink)]);
+            for (height, hash, tx) in [(1, "h1", cb), (2, "h2", spend)] {
+                let json = block_json(height, hash, tx);
+                let block = serde_json::from_str(&json).unwrap();
+                index_block(&ctx, &nd_store, &nd_store, height, &json, block).unwrap();
+            }
+            nd_store.delete_height(2).unwrap();
+            nd_store.commit().unwrap();
+        }
+
+        // Still goes through to the inner store.
+        assert_eq!(store.txs.borrow().len(), 2);
+        let lines = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|x| serde_json::from_str::<serde_json::Value>(x).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["txid"], "t1");
+        assert_eq!(lines[0]["tx_type"], TxType::Coinbase.to_string());
+        assert_eq!(lines[0]["tx_in"], serde_json::Value::Null);
+        assert_eq!(lines[1]["height"], 2);
+        assert_eq!(lines[1]["tx_in"], serde_json::json!({ "a1": 1.5 }));
+        assert_eq!(lines[1]["tx_out"], serde_json::json!({ "a2": 1.5 }));
+        assert_eq!(lines[2], serde_json::json!({ "rollback": 2 }));
+    }
+}
@@ -0,0 +1,770 @@
+use crate::db::{
+    parse_schema_version, BlockStats, BlockStore, EdgeRecord, TxAddrData, TxRecord, INDEX_TABLES,
+    META_SCHEMA_VERSION, SQL_SELECT_HEIGHT_GAPS, SQL_SELECT_HEIGHT_RANGE,
+    SQL_SELECT_ORPHAN_TX_HEIGHTS,
+};
+use crate::lang::Result;
+use crate::models::{Block, Transaction};
+use postgres::types::ToSql;
+use postgres::{Client, NoTls};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use tracing::info;
+
+/// Postgres connection knobs. Connection parameters themselves come in
+/// through the `--db-url`.
+#[derive(clap::Args, Debug, Clone)]
+pub struct PgOpts {
+    /// Connect to postgres over TLS
+    #[arg(long)]
+    pub pg_tls: bool,
+    /// Max rows per multi-row insert
+    #[arg(long, default_value_t = 1000)]
+    pub pg_batch_rows: usize,
+}
+
+/// Writes are buffered and flushed as multi-row inserts on commit, or
+/// once any of the buffers go over `pg_batch_rows`. The buffers are
+/// keyed the same as the tables, as an upsert can't touch the same row
+/// twice within a statement.
+pub struct PostgresBlockStore {
+    client: RefCell<Client>,
+    batch_rows: usize,
+    blocks: RefCell<BTreeMap<i64, (String, String)>>,
+    blocks_stats: RefCell<BTreeMap<i64, BlockStats>>,
+    txs: RefCell<HashMap<String, PgTxRow>>,
+    // (txid, in_addr, out_addr, token) -> (c_flags, value)
+    edges: RefCell<HashMap<(String, String, String, String), (i64, f64)>>,
+    // (address, txid, direction) -> (height, value)
+    address_txs: RefCell<HashMap<(String, String, String), (i64, f64)>>,
+    // (txid, n) -> (address, value, height, spent_txid, spent_height)
+    utxos: RefCell<HashMap<(String, i64), PgUtxoRow>>,
+    // Spends of the outs that are already flushed, applied after the
+    // buffered outs. (txid, n, spent_txid, spent_height)
+    utxo_spends: RefCell<Vec<(String, i64, String, i64)>>,
+}
+
+type PgUtxoRow = (String, f64, i64, Option<String>, Option<i64>);
+
+#[derive(Debug, Clone)]
+struct PgTxRow {
+    height: i64,
+    cols: [String; 18],
+    vin_count: i64,
+    vout_count: i64,
+}
+
+impl PostgresBlockStore {
+    pub fn new(url: &str, opts: &PgOpts) -> Result<Self> {
+        let mut client = if opts.pg_tls {
+            let connector = native_tls::TlsConnector::new()?;
+            let connector = postgres_native_tls::MakeTlsConnector::new(connector);
+            Client::connect(url, connector)?
+        } else {
+            Client::connect(url, NoTls)?
+        };
+        pg_migrate(&mut client)?;
+        Ok(Self {
+            client: RefCell::new(client),
+            batch_rows: std::cmp::max(opts.pg_batch_rows, 1),
+            blocks: RefCell::new(BTreeMap::new()),
+            blocks_stats: RefCell::new(BTreeMap::new()),
+            txs: RefCell::new(HashMap::new()),
+            edges: RefCell::new(HashMap::new()),
+            address_txs: RefCell::new(HashMap::new()),
+            utxos: RefCell::new(HashMap::new()),
+            utxo_spends: RefCell::new(Vec::new()),
+        })
+    }
+
+    fn pending_rows(&self) -> usize {
+        self.blocks.borrow().len()
+            + self.blocks_stats.borrow().len()
+            + self.txs.borrow().len()
+            + self.edges.borrow().len()
+            + self.address_txs.borrow().len()
+            + self.utxos.borrow().len()
+            + self.utxo_spends.borrow().len()
+    }
+
+    fn maybe_flush(&self) -> Result<()> {
+        if self.pending_rows() >= self.batch_rows {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let mut client = self.client.borrow_mut();
+        let batch_rows = self.batch_rows;
+
+        let blocks = std::mem::take(&mut *self.blocks.borrow_mut());
+        let blocks = blocks.iter().collect::<Vec<_>>();
+        for chunk in blocks.chunks(pg_chunk_rows(batch_rows, 3)) {
+            let mut params = Vec::<&(dyn ToSql + Sync)>::with_capacity(chunk.len() * 3);
+            for (height, (hash, data)) in chunk {
+                params.extend([*height as &(dyn ToSql + Sync), hash, data]);
+            }
+            let q = pg_upsert_query("blocks", &["height", "hash", "data"], "height", chunk.len());
+            client.execute(&q, &params)?;
+        }
+
+        let blocks_stats = std::mem::take(&mut *self.blocks_stats.borrow_mut());
+        let blocks_stats = blocks_stats.values().collect::<Vec<_>>();
+        for chunk in blocks_stats.chunks(pg_chunk_rows(batch_rows, PG_BLOCKS_STATS_COLS.len())) {
+            let mut params =
+                Vec::<&(dyn ToSql + Sync)>::with_capacity(chunk.len() * PG_BLOCKS_STATS_COLS.len());
+            for stats in chunk {
+                params.extend([
+                    &stats.height as &(dyn ToSql + Sync),
+                    &stats.tx_count,
+                    &stats.total_vout_value,
+                    &stats.coinbase_value,
+                    &stats.size,
+                    &stats.coinbase_addrs,
+                ]);
+            }
+            let q = pg_upsert_query("blocks_stats", &PG_BLOCKS_STATS_COLS, "height", chunk.len());
+            client.execute(&q, &params)?;
+        }
+
+        let txs = std::mem::take(&mut *self.txs.borrow_mut());
+        let txs = txs.iter().collect::<Vec<_>>();
+        for chunk in txs.chunks(pg_chunk_rows(batch_rows, PG_TX_COLS.len())) {
+            let mut params =
+                Vec::<&(dyn ToSql + Sync)>::with_capacity(chunk.len() * PG_TX_COLS.len());
+            for (txid, row) in chunk {
+                params.push(*txid);
+                params.push(&row.height);
+                params.extend(row.cols.iter().map(|x| x as &(dyn ToSql + Sync)));
+                params.push(&row.vin_count);
+                params.push(&row.vout_count);
+            }
+            let q = pg_upsert_query("txs", &PG_TX_COLS, "txid", chunk.len());
+            client.execute(&q, &params)?;
+        }
+
+        let edges = std::mem::take(&mut *self.edges.borrow_mut());
+        let edges = edges.iter().collect::<Vec<_>>();
+        for chunk in edges.chunks(pg_chunk_rows(batch_rows, 6)) {
+            let mut params = Vec::<&(dyn ToSql + Sync)>::with_capacity(chunk.len() * 6);
+            for ((txid, in_addr, out_addr, token), (c_flags, value)) in chunk {
+                params.extend([
+                    txid as &(dyn ToSql + Sync),
+                    in_addr,
+                    out_addr,
+                    c_flags,
+                    value,
+                    token,
+                ]);
+            }
+            let q = pg_upsert_query(
+                "tx_addr_graph",
+                &["txid", "in_addr", "out_addr", "c_flags", "value", "token"],
+                "txid, in_addr, out_addr, token",
+                chunk.len(),
+            );
+            client.execute(&q, &params)?;
+        }
+
+        let address_txs = std::mem::take(&mut *self.address_txs.borrow_mut());
+        let address_txs = address_txs.iter().collect::<Vec<_>>();
+        for chunk in address_txs.chunks(pg_chunk_rows(batch_rows, 5)) {
+            let mut params = Vec::<&(dyn ToSql + Sync)>::with_capacity(chunk.len() * 5);
+            for ((address, txid, direction), (height, value)) in chunk {
+                params.extend([
+                    address as &(dyn ToSql + Sync),
+                    txid,
+                    height,
+                    direction,
+                    value,
+                ]);
+            }
+            let q = pg_upsert_query(
+                "address_tx",
+                &["address", "txid", "height", "direction", "value"],
+                "address, txid, direction",
+                chunk.len(),
+            );
+            client.execute(&q, &params)?;
+        }
+
+        let utxos = std::mem::take(&mut *self.utxos.borrow_mut());
+        let utxos = utxos.iter().collect::<Vec<_>>();
+        for chunk in utxos.chunks(pg_chunk_rows(batch_rows, 7)) {
+            let mut params = Vec::<&(dyn ToSql + Sync)>::with_capacity(chunk.len() * 7);
+            for ((txid, n), (address, value, height, spent_txid, spent_height)) in chunk {
+                params.extend([
+                    txid as &(dyn ToSql + Sync),
+                    n,
+                    address,
+                    value,
+                    height,
+                    spent_txid,
+                    spent_height,
+                ]);
+            }
+            let q = pg_upsert_query(
+                "utxos",
+                &[
+                    "txid",
+                    "n",
+                    "address",
+                    "value",
+                    "height",
+                    "spent_txid",
+                    "spent_height",
+                ],
+                "txid, n",
+                chunk.len(),
+            );
+            client.execute(&q, &params)?;
+        }
+
+        let utxo_spends = std::mem::take(&mut *self.utxo_spends.borrow_mut());
+        if !utxo_spends.is_empty() {
+            let stmt = client.prepare(
+                "UPDATE utxos SET spent_txid = $3, spent_height = $4 WHERE txid = $1 AND n = $2",
+            )?;
+            for (txid, n, spent_txid, spent_height) in &utxo_spends {
+                client.execute(&stmt, &[txid, n, spent_txid, spent_height])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn query_opt_string(&self, q: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Option<String>> {
+        let row = self.client.borrow_mut().query_opt(q, params)?;
+        Ok(row.map(|r| r.get(0)))
+    }
+
+    fn query_height_pairs(&self, q: &str) -> Result<Vec<(i64, i64)>> {
+        let rows = self.client.borrow_mut().query(q, &[])?;
+        Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+    }
+}
+
+impl BlockStore for PostgresBlockStore {
+    fn get_block_from_hash(&self, hash: &str) -> Result<Option<Block>> {
+        let data = self.query_opt_string("SELECT data FROM blocks WHERE hash = $1", &[&hash])?;
+        data.map(|d| serde_json::from_str(&d).map_err(|e| e.into()))
+            .transpose()
+    }
+
+    fn get_block_hash(&self, height: i64) -> Result<Option<String>> {
+        if let Some((hash, _)) = self.blocks.borrow().get(&height) {
+            return Ok(Some(hash.clone()));
+        }
+        self.query_opt_string("SELECT hash FROM blocks WHERE height = $1", &[&height])
+    }
+
+    fn get_block_hash_for_tx(&self, tx_hash: &str) -> Result<Option<String>> {
+        self.query_opt_string(
+            "SELECT b.hash FROM blocks b JOIN txs t ON b.height = t.height WHERE t.txid = $1",
+            &[&tx_hash],
+        )
+    }
+
+    fn get_block_for_tx(&self, tx_hash: &str) -> Result<Option<Block>> {
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt("SELECT height FROM txs WHERE txid = $1", &[&tx_hash])?;
+        match row {
+            Some(r) => self.get_block_from_height(r.get(0)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+        let data =
+            self.query_opt_string("SELECT data FROM blocks WHERE height = $1", &[&height])?;
+        data.map(|d| serde_json::from_str(&d).map_err(|e| e.into()))
+            .transpose()
+    }
+
+    fn get_block_json(&self, height: i64) -> Result<Option<String>> {
+        if let Some((_, data)) = self.blocks.borrow().get(&height) {
+            return Ok(Some(data.clone()));
+        }
+        self.query_opt_string("SELECT data FROM blocks WHERE height = $1", &[&height])
+    }
+
+    // Prior outputs are very often still in the write buffers while
+    // indexing, so look there first.
+    fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
+        let data = match self.txs.borrow().get(hash) {
+            Some(row) => Some(row.cols[5].clone()),
+            None => None,
+        };
+        let data = match data {
+            Some(d) => Some(d),
+            None => self.query_opt_string("SELECT data FROM txs WHERE txid = $1", &[&hash])?,
+        };
+        data.map(|d| serde_json::from_str(&d).map_err(|e| e.into()))
+            .transpose()
+    }
+
+    fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>> {
+        let row = self.client.borrow_mut().query_opt(
+            "SELECT tx_in, tx_out, tx_type FROM txs WHERE txid = $1",
+            &[&hash],
+        )?;
+        match row {
+            Some(r) => {
+                let tx_in: String = r.get(0);
+                let tx_out: String = r.get(1);
+                Ok(Some(TxAddrData {
+                    tx_type: r.get(2),
+                    tx_in: serde_json::from_str(&tx_in)?,
+                    tx_out: serde_json::from_str(&tx_out)?,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn get_tx_height(&self, txid: &str) -> Result<Option<i64>> {
+        if let Some(row) = self.txs.borrow().get(txid) {
+            return Ok(Some(row.height));
+        }
+        let row = self
+            .client
+            .borrow_mut()
+            .query_opt("SELECT height FROM txs WHERE txid = $1", &[&txid])?;
+        Ok(row.map(|r| r.get(0)))
+    }
+
+    fn get_max_height(&self) -> Result<Option<i64>> {
+        let row = self
+            .client
+            .borrow_mut()
+            .query_one("SELECT max(height) FROM blocks", &[])?;
+        Ok(row.get(0))
+    }
+
+    fn get_token_symbols(&self) -> Result<HashMap<String, String>> {
+        let rows = self
+            .client
+            .borrow_mut()
+            .query("SELECT id, symbol FROM tokens", &[])?;
+        Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+    }
+
+    fn get_height_range(&self) -> Result<Option<(i64, i64)>> {
+        let row = self
+            .client
+            .borrow_mut()
+            .query_one(SQL_SELECT_HEIGHT_RANGE, &[])?;
+        let (min, max): (Option<i64>, Option<i64>) = (row.get(0), row.get(1));
+        Ok(min.zip(max))
+    }
+
+    fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>> {
+        self.query_height_pairs(SQL_SELECT_HEIGHT_GAPS)
+    }
+
+    fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>> {
+        self.query_height_pairs(SQL_SELECT_ORPHAN_TX_HEIGHTS)
+    }
+
+    fn get_meta(&self) -> Result<HashMap<String, String>> {
+        let rows = self
+            .client
+            .borrow_mut()
+            .query("SELECT key, value FROM dindexer_meta", &[])?;
+        Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+    }
+
+    fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+        let mut client = self.client.borrow_mut();
+        let mut res = Vec::with_capacity(INDEX_TABLES.len());
+        for table in INDEX_TABLES {
+            let q = format!("SELECT count(*) FROM {}", table);
+            res.push((table, client.query_one(&q, &[])?.get(0)));
+        }
+        Ok(res)
+    }
+
+    fn put_block(&self, height: i64, hash: &str, data: &str) -> Result<()> {
+        self.blocks
+            .borrow_mut()
+            .insert(height, (hash.to_owned(), data.to_owned()));
+        self.maybe_flush()
+    }
+
+    fn put_block_stats(&self, stats: &BlockStats) -> Result<()> {
+        self.blocks_stats
+            .borrow_mut()
+            .insert(stats.height, stats.clone());
+        self.maybe_flush()
+    }
+
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        let cols = [
+            tx.tx_type,
+            tx.tx_in,
+            tx.tx_out,
+            tx.dvm_in,
+            tx.dvm_out,
+            tx.data,
+            tx.icx_data,
+            tx.icx_addr,
+            tx.icx_btc_exp_amt,
+            tx.swap_from,
+            tx.swap_to,
+            tx.swap_amt,
+            tx.swap_pools,
+            tx.transfer_from,
+            tx.transfer_to,
+            tx.fee,
+            tx.tx_out_types,
+            tx.icx_links,
+        ]
+        .map(|x| x.to_owned());
+        let row = PgTxRow {
+            height: tx.height,
+            cols,
+            vin_count: tx.vin_count,
+            vout_count: tx.vout_count,
+        };
+        self.txs.borrow_mut().insert(tx.txid.to_owned(), row);
+        self.maybe_flush()
+    }
+
+    fn put_edge(&self, edge: &EdgeRecord) -> Result<()> {
+        let k = (
+            edge.txid.to_owned(),
+            edge.in_addr.to_owned(),
+            edge.out_addr.to_owned(),
+            edge.token.to_owned(),
+        );
+        self.edges
+            .borrow_mut()
+            .insert(k, (edge.c_flags, edge.value));
+        self.maybe_flush()
+    }
+
+    fn put_address_tx(
+        &self,
+        address: &str,
+        txid: &str,
+        height: i64,
+        direction: &str,
+        value: f64,
+    ) -> Result<()> {
+        let k = (address.to_owned(), txid.to_owned(), direction.to_owned());
+        self.address_txs.borrow_mut().insert(k, (height, value));
+        self.maybe_flush()
+    }
+
+    fn put_utxo(&self, txid: &str, n: u64, address: &str, value: f64, height: i64) -> Result<()> {
+        let row = (address.to_owned(), value, height, None, None);
+        self.utxos
+            .borrow_mut()
+            .insert((txid.to_owned(), n as i64), row);
+        self.maybe_flush()
+    }
+
+    fn spend_utxo(&self, txid: &str, n: u64, spent_txid: &str, height: i64) -> Result<()> {
+        let k = (txid.to_owned(), n as i64);
+        if let Some(row) = self.utxos.borrow_mut().get_mut(&k) {
+            row.3 = Some(spent_txid.to_owned());
+            row.4 = Some(height);
+            return Ok(());
+        }
+        self.utxo_spends
+            .borrow_mut()
+            .push((k.0, k.1, spent_txid.to_owned(), height));
+        self.maybe_flush()
+    }
+
+    // Small and rare, so no buffering here.
+    fn put_token_symbols(&self, symbols: &HashMap<String, String>) -> Result<()> {
+        let mut client = self.client.borrow_mut();
+        for (id, symbol) in symbols {
+            client.execute(
+                "INSERT INTO tokens (id, symbol) VALUES ($1, $2)
+                ON CONFLICT (id) DO UPDATE SET symbol = EXCLUDED.symbol",
+                &[id, symbol],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn put_meta(&self, meta: &HashMap<String, String>) -> Result<()> {
+        let mut client = self.client.borrow_mut();
+        for (key, value) in meta {
+            client.execute(
+                "INSERT INTO dindexer_meta (key, value) VALUES ($1, $2)
+                ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+                &[key, value],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn delete_height(&self, height: i64) -> Result<()> {
+        self.flush()?;
+        self.client.borrow_mut().execute(
+            "DELETE FROM tx_addr_graph WHERE txid IN (SELECT txid FROM txs WHERE height = $1)",
+            &[&height],
+        )?;
+        self.client
+            .borrow_mut()
+            .execute("DELETE FROM address_tx WHERE height = $1", &[&height])?;
+        self.client
+            .borrow_mut()
+            .execute("DELETE FROM utxos WHERE height = $1", &[&height])?;
+        self.client.borrow_mut().execute(
+            "UPDATE utxos SET spent_txid = NULL, spent_height = NULL WHERE spent_height = $1",
+            &[&height],
+        )?;
+        self.client
+            .borrow_mut()
+            .execute("DELETE FROM txs WHERE height = $1", &[&height])?;
+        self.client
+            .borrow_mut()
+            .execute("DELETE FROM blocks_stats WHERE height = $1", &[&height])?;
+        self.client
+            .borrow_mut()
+            .execute("DELETE FROM blocks WHERE height = $1", &[&height])?;
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<()> {
+        self.client.borrow_mut().batch_execute("BEGIN")?;
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.flush()?;
+        self.client.borrow_mut().batch_execute("COMMIT")?;
+        Ok(())
+    }
+}
+
+const PG_BLOCKS_STATS_COLS: [&str; 6] = [
+    "height",
+    "tx_count",
+    "total_vout_value",
+    "coinbase_value",
+    "size",
+    "coinbase_addrs",
+];
+
+const PG_TX_COLS: [&str; 22] = [
+    "txid",
+    "height",
+    "tx_type",
+    "tx_in",
+    "tx_out",
+    "dvm_in",
+    "dvm_out",
+    "data",
+    "icx_data",
+    "icx_addr",
+    "icx_btc_exp_amt",
+    "swap_from",
+    "swap_to",
+    "swap_amt",
+    "swap_pools",
+    "transfer_from",
+    "transfer_to",
+    "fee",
+    "tx_out_types",
+    "icx_links",
+    "vin_count",
+    "vout_count",
+];
+
+// Postgres caps the bind params of a statement at 65535.
+const PG_MAX_PARAMS: usize = u16::MAX as usize;
+
+// Rows per upsert, so a chunk of `cols` wide rows stays under the param cap
+// whatever `pg_batch_rows` is set to.
+fn pg_chunk_rows(batch_rows: usize, cols: usize) -> usize {
+    batch_rows.min(PG_MAX_PARAMS / cols)
+}
+
+// Multi-row `insert .. on conflict do update`, the postgres equivalent of
+// our sqlite `insert or replace`.
+fn pg_upsert_query(table: &str, cols: &[&str], conflict: &str, rows: usize) -> String {
+    let values = (0..rows)
+        .map(|r| {
+            let placeholders = (1..=cols.len())
+                .map(|c| format!("${}", r * cols.len() + c))
+                .collect::<Vec<_>>();
+            format!("({})", placeholders.join(", "))
+        })
+        .collect::<Vec<_>>();
+    let updates = cols
+        .iter()
+        .map(|c| format!("{c} = EXCLUDED.{c}"))
+        .collect::<Vec<_>>();
+    format!(
+        "INSERT INTO {} ({}) VALUES {} ON CONFLICT ({}) DO UPDATE SET {}",
+        table,
+        cols.join(", "),
+        values.join(", "),
+        conflict,
+        updates.join(", ")
+    )
+}
+
+// Same layout as the sqlite v2 schema. Indexes are created upfront since
+// the db is expected to be queried while indexing.
+/// Same steps as the sqlite migrations, so the versions line up.
+const PG_MIGRATIONS: [&str; 13] = [
+    "
+    CREATE TABLE IF NOT EXISTS blocks (
+        height BIGINT PRIMARY KEY,
+        hash TEXT UNIQUE NOT NULL,
+        data TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS txs (
+        txid TEXT PRIMARY KEY,
+        height BIGINT NOT NULL,
+        tx_type TEXT NOT NULL,
+        tx_in TEXT NOT NULL,
+        tx_out TEXT NOT NULL,
+        dvm_in TEXT NOT NULL,
+        dvm_out TEXT NOT NULL,
+        data TEXT NOT NULL,
+        icx_data TEXT NOT NULL,
+        icx_addr TEXT NOT NULL,
+        icx_btc_exp_amt TEXT NOT NULL,
+        swap_from TEXT NOT NULL,
+        swap_to TEXT NOT NULL,
+        swap_amt TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS tokens (
+        id TEXT PRIMARY KEY,
+        symbol TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS tx_addr_graph (
+        id BIGSERIAL PRIMARY KEY,
+        txid TEXT NOT NULL,
+        in_addr TEXT NOT NULL,
+        out_addr TEXT NOT NULL,
+        c_flags BIGINT NOT NULL,
+        UNIQUE (txid, in_addr, out_addr)
+    );
+
+    CREATE INDEX IF NOT EXISTS idx_txs_height ON txs (height);
+    CREATE INDEX IF NOT EXISTS idx_txs_tx_type ON txs (tx_type);
+    CREATE INDEX IF NOT EXISTS idx_txs_icx_addr ON txs (icx_addr);
+    CREATE INDEX IF NOT EXISTS idx_txs_swap_from ON txs (swap_from);
+    CREATE INDEX IF NOT EXISTS idx_txs_swap_to ON txs (swap_to);
+    CREATE INDEX IF NOT EXISTS idx_tx_addr_graph_in_addr ON tx_addr_graph (in_addr);
+    CREATE INDEX IF NOT EXISTS idx_tx_addr_graph_out_addr ON tx_addr_graph (out_addr);
+    ",
+    "
+    ALTER TABLE txs ADD COLUMN IF NOT EXISTS swap_pools TEXT NOT NULL DEFAULT '';
+    ",
+    "
+    ALTER TABLE txs ADD COLUMN IF NOT EXISTS transfer_from TEXT NOT NULL DEFAULT '';
+    ALTER TABLE txs ADD COLUMN IF NOT EXISTS transfer_to TEXT NOT NULL DEFAULT '';
+    CREATE INDEX IF NOT EXISTS idx_txs_transfer_from ON txs (transfer_from);
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS blocks_stats (
+        height BIGINT PRIMARY KEY,
+        tx_count BIGINT NOT NULL,
+        total_vout_value DOUBLE PRECISION NOT NULL,
+        coinbase_value DOUBLE PRECISION NOT NULL,
+        size BIGINT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_blocks_stats_tx_count ON blocks_stats (tx_count);
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS address_tx (
+        address TEXT NOT NULL,
+        txid TEXT NOT NULL,
+        height BIGINT NOT NULL,
+        direction TEXT NOT NULL,
+        value DOUBLE PRECISION NOT NULL,
+        PRIMARY KEY (address, txid, direction)
+    );
+    CREATE INDEX IF NOT EXISTS idx_address_tx_height ON address_tx (height);
+    ",
+    "
+    ALTER TABLE tx_addr_graph ADD COLUMN IF NOT EXISTS value DOUBLE PRECISION NOT NULL DEFAULT 0;
+    ",
+    "
+    ALTER TABLE tx_addr_graph ADD COLUMN IF NOT EXISTS token TEXT NOT NULL DEFAULT '';
+    ALTER TABLE tx_addr_graph DROP CONSTRAINT IF EXISTS tx_addr_graph_txid_in_addr_out_addr_key;
+    CREATE UNIQUE INDEX IF NOT EXISTS idx_tx_addr_graph_edge
+        ON tx_addr_graph (txid, in_addr, out_addr, token);
+    CREATE INDEX IF NOT EXISTS idx_tx_addr_graph_token ON tx_addr_graph (token);
+    ",
+    "
+    ALTER TABLE txs ADD COLUMN IF NOT EXISTS fee TEXT NOT NULL DEFAULT '';
+    ",
+    "
+    ALTER TABLE txs ADD COLUMN IF NOT EXISTS tx_out_types TEXT NOT NULL DEFAULT '';
+    ",
+    "
+    ALTER TABLE txs ADD COLUMN IF NOT EXISTS icx_links TEXT NOT NULL DEFAULT '';
+    ",
+    "
+    ALTER TABLE blocks_stats ADD COLUMN IF NOT EXISTS coinbase_addrs TEXT NOT NULL DEFAULT '';
+    ",
+    "
+    CREATE TABLE IF NOT EXISTS utxos (
+        txid TEXT NOT NULL,
+        n BIGINT NOT NULL,
+        address TEXT NOT NULL,
+        value DOUBLE PRECISION NOT NULL,
+        height BIGINT NOT NULL,
+        spent_txid TEXT,
+        spent_height BIGINT,
+        PRIMARY KEY (txid, n)
+    );
+    CREATE INDEX IF NOT EXISTS idx_utxos_address ON utxos (address);
+    CREATE INDEX IF NOT EXISTS idx_utxos_height ON utxos (height);
+    CREATE INDEX IF NOT EXISTS idx_utxos_spent_height ON utxos (spent_height);
+    ",
+    "
+    ALTER TABLE txs ADD COLUMN IF NOT EXISTS vin_count BIGINT NOT NULL DEFAULT 0;
+    ALTER TABLE txs ADD COLUMN IF NOT EXISTS vout_count BIGINT NOT NULL DEFAULT 0;
+    CREATE INDEX IF NOT EXISTS idx_txs_vin_count ON txs (vin_count);
+    CREATE INDEX IF NOT EXISTS idx_txs_vout_count ON txs (vout_count);
+    ",
+];
+
+/// Bring the db up to `SCHEMA_VERSION`, each step in its own transaction
+/// along with the version bump. A db with a newer schema is refused.
+fn pg_migrate(client: &mut Client) -> Result<()> {
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS dindexer_meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+    )?;
+    let row = client.query_opt(
+        "SELECT value FROM dindexer_meta WHERE key = $1",
+        &[&META_SCHEMA_VERSION],
+    )?;
+    let version: Option<String> = row.map(|r| r.get(0));
+    let version = parse_schema_version(version.as_deref())?;
+    for (i, step) in PG_MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let to = i as i64 + 1;
+        if version > 0 {
+            info!("schema: migrating to version {}", to);
+        }
+        let mut tx = client.transaction()?;
+        tx.batch_execute(step)?;
+        tx.execute(
+            "INSERT INTO dindexer_meta (key, value) VALUES ($1, $2)
+            ON CONFLICT (key) DO UPDATE SET value = EXCLUDED.value",
+            &[&META_SCHEMA_VERSION, &to.to_string()],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,581 @@
+use crate::db::{BlockStats, BlockStore, EdgeRecord, TxAddrData, TxRecord, INDEX_TABLES};
+use crate::lang::Result;
+use crate::models::{Block, Transaction};
+use rust_rocksdb::{DBCompressionType, Direction, IteratorMode, Options, WriteBatch, DB};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+// Everything is in the default column family, under one of these key
+// prefixes. Heights are fixed width big endian, see `height_key`, so that
+// they sort, and the parts of composite keys are nul separated.
+const K_BLOCK: &[u8] = b"b/"; // height -> hash
+const K_BLOCK_DATA: &[u8] = b"bd/"; // height -> block json
+const K_BLOCK_HASH: &[u8] = b"bx/"; // hash -> height
+const K_BLOCK_STATS: &[u8] = b"bs/"; // height -> stats json
+const K_TX: &[u8] = b"t/"; // txid -> tx record json
+const K_HEIGHT_TX: &[u8] = b"th/"; // height, txid
+const K_EDGE: &[u8] = b"g/"; // txid, in_addr, out_addr, token -> (c_flags, value)
+const K_ADDRESS_TX: &[u8] = b"a/"; // address, txid, direction -> (height, value)
+const K_HEIGHT_ADDRESS_TX: &[u8] = b"ah/"; // height, address, txid, direction
+const K_UTXO: &[u8] = b"u/"; // txid, n -> utxo json
+const K_HEIGHT_UTXO: &[u8] = b"uh/"; // height, txid, n
+const K_HEIGHT_SPEND: &[u8] = b"us/"; // spent height, txid, n
+const K_TOKEN: &[u8] = b"k/"; // id -> symbol
+const K_META: &[u8] = b"m/"; // key -> value
+
+/// A key/value store on rocksdb, for write heavy syncs. Blocks are keyed
+/// by height and txs by txid, and address_tx rows by address, so the
+/// store only does point lookups and prefix scans. There's no sql, so
+/// the commands that query the tables aren't supported on it.
+///
+/// Writes are kept in memory until the commit, and written as a single
+/// batch. Reads see them before that, as prior outputs are very often
+/// spent within the same commit interval. The scans commit what's
+/// pending first.
+pub struct RocksBlockStore {
+    db: DB,
+    // Key to value, or `None` for a delete.
+    pending: RefCell<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+// The fields of `TxRecord` that are read back.
+#[derive(serde::Deserialize)]
+struct RocksTxRow {
+    height: i64,
+    tx_type: String,
+    tx_in: String,
+    tx_out: String,
+    data: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct RocksUtxoRow {
+    address: String,
+    value: f64,
+    height: i64,
+    spent_txid: Option<String>,
+    spent_height: Option<i64>,
+}
+
+impl RocksBlockStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.set_write_buffer_size(256 << 20); // mb
+        opts.set_max_write_buffer_number(4);
+        opts.set_compression_type(DBCompressionType::Lz4);
+        opts.set_bottommost_compression_type(DBCompressionType::Zstd);
+        opts.increase_parallelism(std::thread::available_parallelism()?.get().try_into()?);
+        opts.set_level_compaction_dynamic_level_bytes(true);
+        let db = DB::open(&opts, path)?;
+        Ok(Self {
+            db,
+            pending: RefCell::new(BTreeMap::new()),
+        })
+    }
+
+    /// Write out what's pending as one batch.
+    pub fn flush(&self) -> Result<()> {
+        let pending = std::mem::take(&mut *self.pending.borrow_mut());
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let mut batch = WriteBatch::default();
+        for (k, v) in pending {
+            match v {
+                Some(v) => batch.put(k, v),
+                None => batch.delete(k),
+            }
+        }
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(v) = self.pending.borrow().get(key) {
+            return Ok(v.clone());
+        }
+        Ok(self.db.get(key)?)
+    }
+
+    fn get_string(&self, key: &[u8]) -> Result<Option<String>> {
+        self.get(key)?
+            .map(|v| String::from_utf8(v).map_err(|e| e.into()))
+            .transpose()
+    }
+
+    fn put(&self, key: Vec<u8>, value: impl Into<Vec<u8>>) {
+        self.pending.borrow_mut().insert(key, Some(value.into()));
+    }
+
+    fn delete(&self, key: Vec<u8>) {
+        self.pending.borrow_mut().insert(key, None);
+    }
+
+    /// The keys and values under the prefix, in key order, with the
+    /// prefix stripped from the keys. Only sees what's written out.
+    fn scan(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut res = Vec::new();
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix, Direction::Forward))
+        {
+            let (k, v) = item?;
+            let Some(k) = k.strip_prefix(prefix) else {
+                break;
+            };
+            res.push((k.to_vec(), v.to_vec()));
+        }
+        Ok(res)
+    }
+
+    /// The first key under the prefix, with the prefix stripped.
+    fn scan_first(&self, prefix: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut it = self
+            .db
+            .iterator(IteratorMode::From(prefix, Direction::Forward));
+        match it.next().transpose()? {
+            Some((k, _)) => Ok(k.strip_prefix(prefix).map(|k| k.to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    fn count(&self, prefix: &[u8]) -> Result<i64> {
+        let mut n = 0;
+        for item in self
+            .db
+            .iterator(IteratorMode::From(prefix, Direction::Forward))
+        {
+            if !item?.0.starts_with(prefix) {
+                break;
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// Heights of the stored blocks, in order.
+    fn block_heights(&self) -> Result<Vec<i64>> {
+        self.flush()?;
+        self.scan(K_BLOCK)?
+            .iter()
+            .map(|(k, _)| decode_height(k))
+            .collect()
+    }
+
+    fn get_tx_row(&self, txid: &str) -> Result<Option<RocksTxRow>> {
+        self.get(&key(K_TX, &[txid.as_bytes()]))?
+            .map(|v| serde_json::from_slice(&v).map_err(|e| e.into()))
+            .transpose()
+    }
+
+    fn get_utxo(&self, k: &[u8]) -> Result<Option<RocksUtxoRow>> {
+        self.get(k)?
+            .map(|v| serde_json::from_slice(&v).map_err(|e| e.into()))
+            .transpose()
+    }
+}
+
+impl BlockStore for RocksBlockStore {
+    fn get_block_from_hash(&self, hash: &str) -> Result<Option<Block>> {
+        match self.get(&key(K_BLOCK_HASH, &[hash.as_bytes()]))? {
+            Some(h) => self.get_block_from_height(decode_height(&h)?),
+            None => Ok(None),
+        }
+    }
+
+    fn get_block_hash(&self, height: i64) -> Result<Option<String>> {
+        self.get_string(&key(K_BLOCK, &[&height_key(height)]))
+    }
+
+    fn get_block_hash_for_tx(&self, tx_hash: &str) -> Result<Option<String>> {
+        match self.get_tx_row(tx_hash)? {
+            Some(row) => self.get_block_hash(row.height),
+            None => Ok(None),
+        }
+    }
+
+    fn get_block_for_tx(&self, tx_hash: &str) -> Result<Option<Block>> {
+        match self.get_tx_row(tx_hash)? {
+            Some(row) => self.get_block_from_height(row.height),
+            None => Ok(None),
+        }
+    }
+
+    fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+        let data = self.get_block_json(height)?;
+        data.map(|d| serde_json::from_str(&d).map_err(|e| e.into()))
+            .transpose()
+    }
+
+    fn get_block_json(&self, height: i64) -> Result<Option<String>> {
+        self.get_string(&key(K_BLOCK_DATA, &[&height_key(height)]))
+    }
+
+    fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
+        match self.get_tx_row(hash)? {
+            Some(row) => Ok(Some(serde_json::from_str(&row.data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>> {
+        match self.get_tx_row(hash)? {
+            Some(row) => Ok(Some(TxAddrData {
+                tx_type: row.tx_type,
+                tx_in: serde_json::from_str(&row.tx_in)?,
+                tx_out: serde_json::from_str(&row.tx_out)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    fn get_tx_height(&self, txid: &str) -> Result<Option<i64>> {
+        Ok(self.get_tx_row(txid)?.map(|row| row.height))
+    }
+
+    fn get_max_height(&self) -> Result<Option<i64>> {
+        Ok(self.get_height_range()?.map(|(_, max)| max))
+    }
+
+    fn get_token_symbols(&self) -> Result<HashMap<String, String>> {
+        self.flush()?;
+        self.scan(K_TOKEN)?
+            .into_iter()
+            .map(|(k, v)| -> Result<_> { Ok((String::from_utf8(k)?, String::from_utf8(v)?)) })
+            .collect()
+    }
+
+    fn get_height_range(&self) -> Result<Option<(i64, i64)>> {
+        self.flush()?;
+        let min = match self.scan_first(K_BLOCK)? {
+            Some(k) => decode_height(&k)?,
+            None => return Ok(None),
+        };
+        // The last key under the prefix, seeking back from the one after it.
+        let mut end = K_BLOCK.to_vec();
+        *end.last_mut().unwrap() += 1;
+        let mut it = self
+            .db
+            .iterator(IteratorMode::From(&end, Direction::Reverse));
+        let max = match it.next().transpose()? {
+            Some((k, _)) if k.starts_with(K_BLOCK) => decode_height(&k[K_BLOCK.len()..])?,
+            _ => min,
+        };
+        Ok(Some((min, max)))
+    }
+
+    fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>> {
+        let heights = self.block_heights()?;
+        Ok(heights
+            .windows(2)
+            .filter(|w| w[1] > w[0] + 1)
+            .map(|w| (w[0] + 1, w[1] - 1))
+            .collect())
+    }
+
+    fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>> {
+        self.flush()?;
+        let mut counts = BTreeMap::<i64, i64>::new();
+        for (k, _) in self.scan(K_HEIGHT_TX)? {
+            *counts.entry(decode_height(&k[..8])?).or_default() += 1;
+        }
+        let mut res = Vec::new();
+        for (height, n) in counts {
+            if self.get_block_hash(height)?.is_none() {
+                res.push((height, n));
+            }
+        }
+        Ok(res)
+    }
+
+    fn get_meta(&self) -> Result<HashMap<String, String>> {
+        self.flush()?;
+        self.scan(K_META)?
+            .into_iter()
+            .map(|(k, v)| -> Result<_> { Ok((String::from_utf8(k)?, String::from_utf8(v)?)) })
+            .collect()
+    }
+
+    fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+        self.flush()?;
+        let prefixes = [
+            K_BLOCK,
+            K_BLOCK_STATS,
+            K_TX,
+            K_EDGE,
+            K_ADDRESS_TX,
+            K_UTXO,
+            K_TOKEN,
+        ];
+        INDEX_TABLES
+            .iter()
+            .zip(prefixes)
+            .map(|(table, prefix)| -> Result<_> { Ok((*table, self.count(prefix)?)) })
+            .collect()
+    }
+
+    fn put_block(&self, height: i64, hash: &str, data: &str) -> Result<()> {
+        let h = height_key(height);
+        // A block replaced at the height leaves its hash behind otherwise.
+        if let Some(old) = self.get_block_hash(height)? {
+            self.delete(key(K_BLOCK_HASH, &[old.as_bytes()]));
+        }
+        self.put(key(K_BLOCK, &[&h]), hash);
+        self.put(key(K_BLOCK_DATA, &[&h]), data);
+        self.put(key(K_BLOCK_HASH, &[hash.as_bytes()]), h);
+        Ok(())
+    }
+
+    fn put_block_stats(&self, stats: &BlockStats) -> Result<()> {
+        let v = serde_json::to_vec(stats)?;
+        self.put(key(K_BLOCK_STATS, &[&height_key(stats.height)]), v);
+        Ok(())
+    }
+
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        // Moved to another height, it's no longer under the old one.
+        if let Some(prev) = self.get_tx_height(tx.txid)? {
+            if prev != tx.height {
+                self.delete(key(K_HEIGHT_TX, &[&height_key(prev), tx.txid.as_bytes()]));
+            }
+        }
+        let v = serde_json::to_vec(tx)?;
+        self.put(key(K_TX, &[tx.txid.as_bytes()]), v);
+        let h = height_key(tx.height);
+        self.put(key(K_HEIGHT_TX, &[&h, tx.txid.as_bytes()]), Vec::new());
+        Ok(())
+    }
+
+    fn put_edge(&self, edge: &EdgeRecord) -> Result<()> {
+        let k = key(
+            K_EDGE,
+            &[
+                edge.txid.as_bytes(),
+                edge.in_addr.as_bytes(),
+                edge.out_addr.as_bytes(),
+                edge.token.as_bytes(),
+            ],
+        );
+        self.put(k, serde_json::to_vec(&(edge.c_flags, edge.value))?);
+        Ok(())
+    }
+
+    fn put_address_tx(
+        &self,
+        address: &str,
+        txid: &str,
+        height: i64,
+        direction: &str,
+        value: f64,
+    ) -> Result<()> {
+        let parts = [address.as_bytes(), txid.as_bytes(), direction.as_bytes()];
+        self.put(
+            key(K_ADDRESS_TX, &parts),
+            serde_json::to_vec(&(height, value))?,
+        );
+        let h = height_key(height);
+        let mut index_parts = vec![&h[..]];
+        index_parts.extend(parts);
+        self.put(key(K_HEIGHT_ADDRESS_TX, &index_parts), Vec::new());
+        Ok(())
+    }
+
+    fn put_utxo(&self, txid: &str, n: u64, address: &str, value: f64, height: i64) -> Result<()> {
+        let row = RocksUtxoRow {
+            address: address.to_owned(),
+            value,
+            height,
+            spent_txid: None,
+            spent_height: None,
+        };
+        let n = n.to_be_bytes();
+        self.put(
+            key(K_UTXO, &[txid.as_bytes(), &n]),
+            serde_json::to_vec(&row)?,
+        );
+        let h = height_key(height);
+        self.put(key(K_HEIGHT_UTXO, &[&h, txid.as_bytes(), &n]), Vec::new());
+        Ok(())
+    }
+
+    fn spend_utxo(&self, txid: &str, n: u64, spent_txid: &str, height: i64) -> Result<()> {
+        let n = n.to_be_bytes();
+        let k = key(K_UTXO, &[txid.as_bytes(), &n]);
+        let Some(mut row) = self.get_utxo(&k)? else {
+            return Ok(());
+        };
+        row.spent_txid = Some(spent_txid.to_owned());
+        row.spent_height = Some(height);
+        self.put(k, serde_json::to_vec(&row)?);
+        let h = height_key(height);
+        self.put(key(K_HEIGHT_SPEND, &[&h, txid.as_bytes(), &n]), Vec::new());
+        Ok(())
+    }
+
+    fn put_token_symbols(&self, symbols: &HashMap<String, String>) -> Result<()> {
+        for (id, symbol) in symbols {
+            self.put(key(K_TOKEN, &[id.as_bytes()]), symbol.as_bytes());
+        }
+        Ok(())
+    }
+
+    fn put_meta(&self, meta: &HashMap<String, String>) -> Result<()> {
+        for (k, v) in meta {
+            self.put(key(K_META, &[k.as_bytes()]), v.as_bytes());
+        }
+        Ok(())
+    }
+
+    // The index keys under the height are what's scanned, so they go
+    // along with the rows.
+    fn delete_height(&self, height: i64) -> Result<()> {
+        self.flush()?;
+        let h = height_key(height);
+        // The rows keyed under the height, with the rest of the key.
+        let at_height = |prefix: &[u8]| {
+            let prefix = key(prefix, &[&h, b""]);
+            self.scan(&prefix).map(|rows| (prefix, rows))
+        };
+
+        let (prefix, rows) = at_height(K_HEIGHT_TX)?;
+        for (txid, _) in rows {
+            let edge_prefix = key(K_EDGE, &[&txid, b""]);
+            for (edge, _) in self.scan(&edge_prefix)? {
+                self.delete([&edge_prefix[..], &edge].concat());
+            }
+            self.delete(key(K_TX, &[&txid]));
+            self.delete([&prefix[..], &txid].concat());
+        }
+
+        let (prefix, rows) = at_height(K_HEIGHT_ADDRESS_TX)?;
+        for (parts, _) in rows {
+            self.delete([K_ADDRESS_TX, &parts].concat());
+            self.delete([&prefix[..], &parts].concat());
+        }
+
+        let (prefix, rows) = at_height(K_HEIGHT_UTXO)?;
+        for (outpoint, _) in rows {
+            self.delete([K_UTXO, &outpoint].concat());
+            self.delete([&prefix[..], &outpoint].concat());
+        }
+        // The outs created at the height are gone by now.
+        self.flush()?;
+        let (prefix, rows) = at_height(K_HEIGHT_SPEND)?;
+        for (outpoint, _) in rows {
+            let k = [K_UTXO, &outpoint].concat();
+            if let Some(mut row) = self.get_utxo(&k)? {
+                row.spent_txid = None;
+                row.spent_height = None;
+                self.put(k, serde_json::to_vec(&row)?);
+            }
+            self.delete([&prefix[..], &outpoint].concat());
+        }
+
+        if let Some(hash) = self.get_block_hash(height)? {
+            self.delete(key(K_BLOCK_HASH, &[hash.as_bytes()]));
+        }
+        self.delete(key(K_BLOCK_STATS, &[&h]));
+        self.delete(key(K_BLOCK_DATA, &[&h]));
+        self.delete(key(K_BLOCK, &[&h]));
+        self.flush()
+    }
+
+    // What's pending is the transaction.
+    fn begin(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.flush()
+    }
+}
+
+/// The parts nul separated after the prefix. Fixed width parts, heights
+/// and out indexes, are always either first or last, so they can't be
+/// mistaken for a separator.
+fn key(prefix: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut k = prefix.to_vec();
+    for (i, part) in parts.iter().enumerate() {
+        if i > 0 {
+            k.push(0);
+        }
+        k.extend_from_slice(part);
+    }
+    k
+}
+
+/// Big endian with the sign bit flipped, so negative heights sort first.
+fn height_key(height: i64) -> [u8; 8] {
+    ((height as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+fn decode_height(k: &[u8]) -> Result<i64> {
+    let b: [u8; 8] = k
+        .get(..8)
+        .and_then(|x| x.try_into().ok())
+        .ok_or("rocksdb: bad height key")?;
+    Ok((u64::from_be_bytes(b) ^ (1 << 63)) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rocks_store_delete_height() {
+        let path = std::env::temp_dir().join("dindexer-test-rocks");
+        let _ = std::fs::remove_dir_all(&path);
+        let store = RocksBlockStore::open(path.to_str().unwrap()).unwrap();
+        let tx = |txid, height| TxRecord {
+            txid,
+            height,
+            tx_in: "{}",
+            tx_out: "{}",
+            data: r#"{"txid":"x"}"#,
+            ..Default::default()
+        };
+        store.begin().unwrap();
+        store.put_block(1, "h1", "{}").unwrap();
+        store.put_block(3, "h3", "{}").unwrap();
+        store.put_tx(&tx("t1", 1)).unwrap();
+        store.put_tx(&tx("t3", 3)).unwrap();
+        store.put_utxo("t1", 0, "a1", 1.5, 1).unwrap();
+        store.put_utxo("t3", 0, "a2", 1.0, 3).unwrap();
+        store.spend_utxo("t1", 0, "t3", 3).unwrap();
+        store.put_address_tx("a2", "t3", 3, "out", 1.0).unwrap();
+        // Pending writes are seen before the commit.
+        assert_eq!(
+            store.get_block_hash_for_tx("t3").unwrap().as_deref(),
+            Some("h3")
+        );
+        store.commit().unwrap();
+
+        assert_eq!(store.get_height_range().unwrap(), Some((1, 3)));
+        assert_eq!(store.get_height_gaps().unwrap(), vec![(2, 2)]);
+        assert!(store.get_tx_addr_data_from_hash("t1").unwrap().is_some());
+
+        store.delete_height(3).unwrap();
+        assert_eq!(store.get_height_range().unwrap(), Some((1, 1)));
+        assert!(store.get_block_from_hash("h3").unwrap().is_none());
+        assert!(store.get_tx_row("t3").unwrap().is_none());
+        let k = key(K_UTXO, &[b"t1", &0u64.to_be_bytes()]);
+        let utxo = store.get_utxo(&k).unwrap().unwrap();
+        assert_eq!(utxo.spent_txid, None);
+        let counts = store.get_table_counts().unwrap();
+        assert_eq!(
+            counts,
+            vec![
+                ("blocks", 1),
+                ("blocks_stats", 0),
+                ("txs", 1),
+                ("tx_addr_graph", 0),
+                ("address_tx", 0),
+                ("utxos", 1),
+                ("tokens", 0),
+            ]
+        );
+        assert!(store.get_orphan_tx_heights().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&path);
+    }
+}
@@ -0,0 +1,161 @@
+use crate::db::{BlockStats, BlockStore, EdgeRecord, TxAddrData, TxRecord};
+use crate::lang::Result;
+use crate::models::{Block, Transaction};
+use std::collections::HashMap;
+
+/// An append-only consumer of the indexed txs, fed alongside the store.
+pub trait TxSink {
+    fn put_tx(&self, tx: &TxRecord) -> Result<()>;
+    /// The txs at `height` and above were rolled back. What's been
+    /// sent can't be taken back, so sinks pass this on to the consumer.
+    fn rollback(&self, height: i64) -> Result<()>;
+    /// Called on every store commit.
+    fn flush(&self) -> Result<()>;
+}
+
+/// Everything goes through to the inner store, and every tx put, height
+/// deleted and commit is passed on to each of the sinks.
+pub struct SinkStore<'a, S> {
+    inner: &'a S,
+    sinks: Vec<Box<dyn TxSink>>,
+}
+
+impl<'a, S: BlockStore> SinkStore<'a, S> {
+    pub fn new(inner: &'a S, sinks: Vec<Box<dyn TxSink>>) -> Self {
+        Self { inner, sinks }
+    }
+}
+
+impl<S: BlockStore> BlockStore for SinkStore<'_, S> {
+    fn get_block_from_hash(&self, hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_from_hash(hash)
+    }
+
+    fn get_block_hash(&self, height: i64) -> Result<Option<String>> {
+        self.inner.get_block_hash(height)
+    }
+
+    fn get_block_hash_for_tx(&self, tx_hash: &str) -> Result<Option<String>> {
+        self.inner.get_block_hash_for_tx(tx_hash)
+    }
+
+    fn get_block_for_tx(&self, tx_hash: &str) -> Result<Option<Block>> {
+        self.inner.get_block_for_tx(tx_hash)
+    }
+
+    fn get_block_from_height(&self, height: i64) -> Result<Option<Block>> {
+        self.inner.get_block_from_height(height)
+    }
+
+    fn get_block_json(&self, height: i64) -> Result<Option<String>> {
+        self.inner.get_block_json(height)
+    }
+
+    fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
+        self.inner.get_tx_from_hash(hash)
+    }
+
+    fn get_tx_addr_data_from_hash(&self, hash: &str) -> Result<Option<TxAddrData>> {
+        self.inner.get_tx_addr_data_from_hash(hash)
+    }
+
+    fn get_tx_height(&self, txid: &str) -> Result<Option<i64>> {
+        self.inner.get_tx_height(txid)
+    }
+
+    fn get_max_height(&self) -> Result<Option<i64>> {
+        self.inner.get_max_height()
+    }
+
+    fn get_token_symbols(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_token_symbols()
+    }
+
+    fn get_height_range(&self) -> Result<Option<(i64, i64)>> {
+        self.inner.get_height_range()
+    }
+
+    fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>> {
+        self.inner.get_height_gaps()
+    }
+
+    fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>> {
+        self.inner.get_orphan_tx_heights()
+    }
+
+    fn get_meta(&self) -> Result<HashMap<String, String>> {
+        self.inner.get_meta()
+    }
+
+    fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+        self.inner.get_table_counts()
+    }
+
+    fn put_block(&self, height: i64, hash: &str, data: &str) -> Result<()> {
+        self.inner.put_block(height, hash, data)
+    }
+
+    fn put_block_stats(&self, stats: &BlockStats) -> Result<()> {
+        self.inner.put_block_stats(stats)
+    }
+
+    fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+        self.inner.put_tx(tx)?;
+        for sink in &self.sinks {
+            sink.put_tx(tx)?;
+        }
+        Ok(())
+    }
+
+    fn put_edge(&self, edge: &EdgeRecord) -> Result<()> {
+        self.inner.put_edge(edge)
+    }
+
+    fn put_address_tx(
+        &self,
+        address: &str,
+        txid: &str,
+        height: i64,
+        direction: &str,
+        value: f64,
+    ) -> Result<()> {
+        self.inner
+            .put_address_tx(address, txid, height, direction, value)
+    }
+
+    fn put_utxo(&self, txid: &str, n: u64, address: &str, value: f64, height: i64) -> Result<()> {
+        self.inner.put_utxo(txid, n, address, value, height)
+    }
+
+    fn spend_utxo(&self, txid: &str, n: u64, spent_txid: &str, height: i64) -> Result<()> {
+        self.inner.spend_utxo(txid, n, spent_txid, height)
+    }
+
+    fn put_token_symbols(&self, symbols: &HashMap<String, String>) -> Result<()> {
+        self.inner.put_token_symbols(symbols)
+    }
+
+    fn put_meta(&self, meta: &HashMap<String, String>) -> Result<()> {
+        self.inner.put_meta(meta)
+    }
+
+    fn delete_height(&self, height: i64) -> Result<()> {
+        self.inner.delete_height(height)?;
+        for sink in &self.sinks {
+            sink.rollback(height)?;
+        }
+        Ok(())
+    }
+
+    fn begin(&self) -> Result<()> {
+        self.inner.begin()
+    }
+
+    fn commit(&self) -> Result<()> {
+        self.inner.commit()?;
+        for sink in &self.sinks {
+            sink.flush()?;
+        }
+        Ok(())
+    }
+}
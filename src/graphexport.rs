@@ -0,0 +1,211 @@
+use crate::cliindexer::DbArgs;
+use crate::db::{is_postgres_url, SqliteBlockStore};
+use crate::lang::Result;
+use clap::Parser;
+use rusqlite::Connection;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct GraphExportArgs {
+    #[command(flatten)]
+    pub db: DbArgs,
+    #[arg(long, value_enum, default_value_t = GraphFormat::Graphml)]
+    pub format: GraphFormat,
+    /// Output path, `-` for stdout
+    #[arg(short = 'o', long)]
+    pub out: String,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Addresses as nodes, and an edge per row with its txid, type,
+    /// value and token, eg. for gephi or networkx
+    Graphml,
+    /// An edge per line: `src,dst,txid,type,value,token`. The edges
+    /// are per token, so a tx can have a line per token between the same
+    /// addresses
+    Csv,
+}
+
+// c_flags is in a TEXT column in the older dbs, so it's cast back.
+const SELECT_EDGES: &str = "SELECT in_addr, out_addr, txid,
+    CAST(c_flags AS INTEGER), value, token FROM tx_addr_graph ORDER BY rowid";
+const SELECT_NODES: &str = "SELECT in_addr FROM tx_addr_graph
+    UNION SELECT out_addr FROM tx_addr_graph";
+
+pub fn run(args: &GraphExportArgs) -> Result<()> {
+    debug!("{:?}", args);
+
+    let quit = std::sync::Arc::new(AtomicBool::new(false));
+    crate::lang::on_signal(signal_hook::consts::SIGINT, &quit)?;
+
+    if is_postgres_url(args.db.db_url()) {
+        return Err("export graph: only sqlite dbs are supported".into());
+    }
+    let sql_store = SqliteBlockStore::new_v2_with_opts(args.db.sqlite_db_path()?, &args.db.sqlite)?;
+    let out: Box<dyn Write> = match args.out.as_str() {
+        "-" => Box::new(std::io::stdout().lock()),
+        path => Box::new(std::fs::File::create(path)?),
+    };
+    let mut w = std::io::BufWriter::new(out);
+    let count = export_graph(&sql_store.conn, args.format, &mut w, &quit)?;
+    w.flush()?;
+
+    info!("done: {} edges", count);
+    Ok(())
+}
+
+/// Streams the graph table to `w`, a row at a time, so it isn't held in
+/// memory. GraphML wants the nodes too, which are a separate distinct
+/// pass over the table before the edges. Returns the edges written.
+pub fn export_graph(
+    conn: &Connection,
+    format: GraphFormat,
+    w: &mut impl Write,
+    quit: &AtomicBool,
+) -> Result<usize> {
+    match format {
+        GraphFormat::Graphml => {
+            w.write_all(GRAPHML_HEADER.as_bytes())?;
+            let mut stmt = conn.prepare(SELECT_NODES)?;
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let addr: String = row.get(0)?;
+                writeln!(w, "    <node id=\"{}\"/>", xml_escape(&addr))?;
+            }
+        }
+        GraphFormat::Csv => writeln!(w, "src,dst,txid,type,value,token")?,
+    }
+
+    let mut stmt = conn.prepare(SELECT_EDGES)?;
+    let mut rows = stmt.query([])?;
+    let mut count = 0usize;
+    while let Some(row) = rows.next()? {
+        if quit.load(Ordering::Relaxed) {
+            info!("int: early exit");
+            break;
+        }
+        let src: String = row.get(0)?;
+        let dst: String = row.get(1)?;
+        let txid: String = row.get(2)?;
+        let edge_type = edge_type(row.get(3)?);
+        let value: f64 = row.get(4)?;
+        let token: String = row.get(5)?;
+        match format {
+            GraphFormat::Graphml => {
+                writeln!(
+                    w,
+                    "    <edge source=\"{}\" target=\"{}\">\
+                     <data key=\"txid\">{}</data>\
+                     <data key=\"type\">{}</data>\
+                     <data key=\"value\">{}</data>\
+                     <data key=\"token\">{}</data></edge>",
+                    xml_escape(&src),
+                    xml_escape(&dst),
+                    xml_escape(&txid),
+                    edge_type,
+                    value,
+                    xml_escape(&token),
+                )?;
+            }
+            GraphFormat::Csv => {
+                writeln!(
+                    w,
+                    "{},{},{},{},{},{}",
+                    src, dst, txid, edge_type, value, token
+                )?;
+            }
+        }
+        count += 1;
+        if count % 1_000_000 == 0 {
+            info!("exported: {} edges", count);
+        }
+    }
+
+    if format == GraphFormat::Graphml {
+        w.write_all(b"  </graph>\n</graphml>\n")?;
+    }
+    Ok(count)
+}
+
+const GRAPHML_HEADER: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<graphml xmlns="http://graphml.graphdrawing.org/xmlns">
+  <key id="txid" for="edge" attr.name="txid" attr.type="string"/>
+  <key id="type" for="edge" attr.name="type" attr.type="string"/>
+  <key id="value" for="edge" attr.name="value" attr.type="double"/>
+  <key id="token" for="edge" attr.name="token" attr.type="string"/>
+  <graph id="tx_addr_graph" edgedefault="directed">
+"#;
+
+/// The c_flags of an edge, see `EdgeRecord`.
+fn edge_type(c_flags: i64) -> &'static str {
+    match c_flags {
+        0 => "utxo",
+        1 => "dvm",
+        _ => "both",
+    }
+}
+
+fn xml_escape(s: &str) -> std::borrow::Cow<'_, str> {
+    if !s.contains(['&', '<', '>', '"', '\'']) {
+        return s.into();
+    }
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{BlockStore, EdgeRecord};
+
+    #[test]
+    fn test_export_graph() {
+        let store = SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let edges = [
+            ("t1", "a1", "a2", "", 0, 1.5),
+            ("t2", "a2", "a<3", "DUSD", 1, 2.0),
+        ];
+        for (txid, in_addr, out_addr, token, c_flags, value) in edges {
+            let edge = EdgeRecord {
+                txid,
+                in_addr,
+                out_addr,
+                token,
+                c_flags,
+                value,
+            };
+            store.put_edge(&edge).unwrap();
+        }
+        store.flush_edges().unwrap();
+        let quit = AtomicBool::new(false);
+
+        let mut csv = Vec::new();
+        let count = export_graph(&store.conn, GraphFormat::Csv, &mut csv, &quit).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "src,dst,txid,type,value,token\na1,a2,t1,utxo,1.5,\na2,a<3,t2,dvm,2,DUSD\n"
+        );
+
+        let mut xml = Vec::new();
+        export_graph(&store.conn, GraphFormat::Graphml, &mut xml, &quit).unwrap();
+        let xml = String::from_utf8(xml).unwrap();
+        assert!(xml.starts_with(GRAPHML_HEADER));
+        assert!(xml.ends_with("</graphml>\n"));
+        for node in ["a1", "a2", "a&lt;3"] {
+            assert_eq!(xml.matches(&format!("<node id=\"{}\"/>", node)).count(), 1);
+        }
+        assert!(xml.contains(
+            "<edge source=\"a2\" target=\"a&lt;3\"><data key=\"txid\">t2</data>\
+             <data key=\"type\">dvm</data><data key=\"value\">2</data>\
+             <data key=\"token\">DUSD</data></edge>"
+        ));
+    }
+}
@@ -51,13 +51,10 @@ pub fn run(args: &GraphWalkArgs) -> Result<()> {
     debug!("args: {:?}", args);
 
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+    lang::on_signal(signal_hook::consts::SIGINT, &quit)?;
     let user_sig = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
     #[cfg(unix)]
-    signal_hook::flag::register(
-        signal_hook::consts::SIGUSR1,
-        std::sync::Arc::clone(&user_sig),
-    )?;
+    lang::on_signal(signal_hook::consts::SIGUSR1, &user_sig)?;
 
     let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
     let (g, node_index_map) = graphutils::load_graph(&args.graph_meta_path, &args.graph_data_path)?;
@@ -24,7 +24,7 @@ pub fn run(args: &IcxAnalyze2Args) -> Result<()> {
     debug!("args: {:?}", args);
 
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+    crate::lang::on_signal(signal_hook::consts::SIGINT, &quit)?;
 
     let sql_store = SqliteBlockStore::new_v2(Some(&args.sqlite_path))?;
     let tracked_tx_types: HashSet<_> = [
@@ -0,0 +1,50 @@
+//! The ICX swaps of the defid log, in an `icx_log` table of an indexed
+//! sqlite db, so they can be joined against the txs without the log.
+
+use crate::db::SqliteBlockStore;
+use crate::lang::Result;
+use crate::models::LogEntryMap;
+use rusqlite::params;
+use tracing::info;
+
+// Only filled from the log when asked to, so it's not part of the
+// migrations.
+const SQLITE_CREATE_ICX_LOG: &str = "
+    CREATE TABLE IF NOT EXISTS icx_log (
+        claim_tx TEXT PRIMARY KEY,
+        order_tx TEXT NOT NULL,
+        offer_tx TEXT NOT NULL,
+        dfchtlc_tx TEXT NOT NULL,
+        address TEXT NOT NULL,
+        amount TEXT NOT NULL
+    )
+";
+
+/// Write the ICX data of the log into the icx_log table, a row per claim.
+/// Claims already in it are replaced, so runs over different logs add up.
+/// Returns the number of rows written.
+pub fn store_icx_log(store: &SqliteBlockStore, log_entry_map: &LogEntryMap) -> Result<usize> {
+    let icx = log_entry_map.icx_data()?;
+    let tx = store.conn.unchecked_transaction()?;
+    tx.execute(SQLITE_CREATE_ICX_LOG, [])?;
+    {
+        let mut stmt = tx.prepare(
+            "insert or replace into icx_log
+            (claim_tx, order_tx, offer_tx, dfchtlc_tx, address, amount)
+            values (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for x in &icx {
+            stmt.execute(params![
+                x.claim_tx,
+                x.order_tx,
+                x.offer_tx,
+                x.dfchtlc_tx,
+                x.address,
+                x.amount
+            ])?;
+        }
+    }
+    tx.commit()?;
+    info!("icx log: {} swaps", icx.len());
+    Ok(icx.len())
+}
@@ -0,0 +1,2018 @@
+use crate::db::{self, BlockStats, BlockStore, EdgeRecord, TxRecord};
+use crate::dfiutils;
+use crate::lang::{Error, Result};
+use crate::models::{
+    self, Block, IcxLink, IcxRole, IcxTxSet, LogEntryMap, TStr, Transaction, TxType, Vin, Vout,
+};
+use dfiutils::{
+    extract_all_dfi_addresses, is_unresolved_input, JsonCols, Network, PrevoutResolver, TokenMap,
+    UtxoCache,
+};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info, warn};
+
+pub struct IndexCtx<'a> {
+    pub network: Network,
+    pub log_entry_map: &'a LogEntryMap,
+    pub tokens: &'a TokenMap,
+    pub enable_addr_graph: bool,
+    /// Store a row per address each tx touches in the address_tx table
+    pub enable_address_table: bool,
+    /// Fallback for prior outputs that aren't in the lookup store
+    pub prevouts: Option<&'a PrevoutResolver>,
+    /// Only txs touching one of these addresses are stored
+    pub address_filter: Option<&'a HashSet<TStr>>,
+    /// Count of the txtype codes seen that have no dedicated `TxType`
+    pub unknown_tx_types: RefCell<HashMap<String, usize>>,
+    /// Count of the txs indexed by their type, see `TxTypeTally`
+    pub tx_type_tally: RefCell<TxTypeTally>,
+    /// Recent outputs, checked before the lookup store
+    pub utxo_cache: UtxoCache,
+    /// Only store the blocks, the txs are left for a later pass
+    pub blocks_only: bool,
+    /// Keep the outs to an address in the utxos table, marked spent as
+    /// the txs spending them come in
+    pub track_utxos: bool,
+    /// Store the txs as they are in the block json from the node, instead
+    /// of as they're re-serialized, so they keep their bytes
+    pub raw_tx_json: bool,
+    /// What to do with a tx that's already stored at another height
+    pub on_dup_txid: DupTxid,
+    /// What to do with a tx whose dvm message doesn't have the fields of
+    /// its type
+    pub on_bad_dvm_msg: BadDvmMsg,
+    /// Which txs are stored by their type, see `TxTypeFilter`
+    pub tx_types: TxTypeFilter,
+}
+
+/// A txid can come up again at another height, eg. a coinbase that's the
+/// same as an earlier one. Either way it's warned about with both heights.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DupTxid {
+    /// Replace the stored tx with the later one. Only the tx row moves:
+    /// the graph edges, address and utxo rows of the earlier one stay
+    /// under its height, and are left behind when that height is rolled
+    /// back or pruned
+    Overwrite,
+    /// Keep the stored tx and skip the later one
+    #[default]
+    Skip,
+}
+
+/// A malformed or unexpected dvm message, eg. a poolswap without its
+/// `fromToken`, would otherwise stop the run at that tx.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BadDvmMsg {
+    /// Warn with the txid and store the tx as `Unknown`, without the
+    /// fields from the message
+    #[default]
+    Unknown,
+    /// Stop with the error
+    Fail,
+}
+
+/// The txs indexed by the type they're stored as, for a summary at the
+/// end of a run to check the classification over the range.
+#[derive(Debug, Default)]
+pub struct TxTypeTally {
+    pub counts: HashMap<TxType, usize>,
+    /// Txs with a dvm message that's stored as `Unknown` or `Other`: a
+    /// txtype without a variant, or a message that didn't parse
+    pub unclassified_dvm: usize,
+}
+
+/// The txs to store by their type, for datasets of only some of them.
+/// The blocks and their stats are always stored, and the utxos of the
+/// skipped txs still tracked. The default keeps everything.
+#[derive(Debug, Clone, Default)]
+pub struct TxTypeFilter {
+    /// The dvm tx types to keep, all of them when `None`. `Unknown` is
+    /// the txs with a type that isn't known
+    pub dvm: Option<HashSet<TxType>>,
+    pub skip_coinbase: bool,
+    pub skip_utxo: bool,
+}
+
+impl TxTypeFilter {
+    pub fn keeps(&self, tx_type: &TxType) -> bool {
+        match tx_type {
+            TxType::Coinbase => !self.skip_coinbase,
+            TxType::Utxo => !self.skip_utxo,
+            TxType::Other(_) => self.keeps(&TxType::Unknown),
+            _ => self.dvm.as_ref().map_or(true, |x| x.contains(tx_type)),
+        }
+    }
+
+    /// Whether any txs are skipped, so their outputs have to come from
+    /// elsewhere when they're spent.
+    pub fn skips_any(&self) -> bool {
+        self.dvm.is_some() || self.skip_coinbase || self.skip_utxo
+    }
+}
+
+/// Clap parser for a `TxTypeFilter` dvm type, see `TxType::from_name`.
+pub fn parse_tx_type_name(name: &str) -> std::result::Result<TxType, String> {
+    let lower = name.to_ascii_lowercase();
+    match TxType::from_name(name) {
+        Some(x) => Ok(x),
+        None if lower == "coinbase" || lower == "utxo" => Err(format!(
+            "{} txs are kept unless skipped with --skip-{}-txs",
+            lower, lower
+        )),
+        None => Err(format!("not a dvm tx type: {}", name)),
+    }
+}
+
+impl IndexCtx<'_> {
+    /// Log the unknown txtype codes seen so far, most frequent first.
+    pub fn log_unknown_tx_types(&self) {
+        let tally = self.unknown_tx_types.borrow();
+        if tally.is_empty() {
+            return;
+        }
+        let mut tally = tally.iter().collect::<Vec<_>>();
+        tally.sort_unstable_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        info!("unknown tx types: {}", tally.len());
+        for (code, n) in tally {
+            info!("\t{}: {}", code, n);
+        }
+    }
+
+    /// Log the txs indexed so far by type, most frequent first, with the
+    /// unknown txtype codes by their code.
+    pub fn log_tx_type_tally(&self) {
+        let tally = self.tx_type_tally.borrow();
+        if tally.counts.is_empty() {
+            return;
+        }
+        let mut counts = tally
+            .counts
+            .iter()
+            .map(|(tx_type, n)| match tx_type {
+                TxType::Other(code) => (code.clone(), *n),
+                tx_type => (format!("{:?}", tx_type), *n),
+            })
+            .collect::<Vec<_>>();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        info!(
+            "tx types: {} txs",
+            counts.iter().map(|x| x.1).sum::<usize>()
+        );
+        for (name, n) in counts {
+            info!("\t{}: {}", name, n);
+        }
+        info!("\tunclassified dvm txs: {}", tally.unclassified_dvm);
+    }
+}
+
+fn unix_now() -> String {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+    now.map(|d| d.as_secs()).unwrap_or_default().to_string()
+}
+
+/// Warn if the db was built for another network, then record the
+/// settings of this run in the meta table. The network first recorded
+/// is kept, so mixing networks stays visible. The schema version is
+/// kept up to date by the stores themselves.
+/// The address table is only recorded as on while every run into the db
+/// has filled it, as lookups can only rely on it then.
+pub fn record_run_start(
+    store: &impl BlockStore,
+    network: Network,
+    enable_addr_graph: bool,
+    enable_address_table: bool,
+) -> Result<()> {
+    let recorded = store.get_meta()?;
+    let mut meta = HashMap::new();
+    match recorded.get(db::META_NETWORK) {
+        Some(v) if v != network.name() => warn!(
+            "meta: db was built for {}, indexing {} into it",
+            v,
+            network.name()
+        ),
+        Some(_) => {}
+        None => {
+            meta.insert(db::META_NETWORK.to_owned(), network.name().to_owned());
+        }
+    }
+    if !recorded.contains_key(db::META_CREATED_AT) {
+        meta.insert(db::META_CREATED_AT.to_owned(), unix_now());
+    }
+    let address_table = enable_address_table
+        && match recorded.get(db::META_ADDRESS_TABLE) {
+            Some(v) => v == "true",
+            None => store.get_height_range()?.is_none(),
+        };
+    for (k, v) in [
+        (db::META_CRATE_VERSION, env!("CARGO_PKG_VERSION").to_owned()),
+        (db::META_GRAPH_TABLE, enable_addr_graph.to_string()),
+        (db::META_ADDRESS_TABLE, address_table.to_string()),
+        (db::META_RUN_STARTED_AT, unix_now()),
+    ] {
+        meta.insert(k.to_owned(), v);
+    }
+    store.put_meta(&meta)
+}
+
+/// Record the stored height range and when the run finished.
+pub fn record_run_end(store: &impl BlockStore) -> Result<()> {
+    let mut meta = HashMap::from([(db::META_RUN_FINISHED_AT.to_owned(), unix_now())]);
+    if let Some((min, max)) = store.get_height_range()? {
+        meta.insert(db::META_MIN_HEIGHT.to_owned(), min.to_string());
+        meta.insert(db::META_MAX_HEIGHT.to_owned(), max.to_string());
+    }
+    store.put_meta(&meta)
+}
+
+/// Load the address allowlist from a file with an address per line.
+/// Blank lines and lines starting with `#` are skipped.
+pub fn load_address_filter(path: &str) -> Result<HashSet<TStr>> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| format!("address filter: {}: {}", path, e))?;
+    let addrs = content
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(TStr::from)
+        .collect::<HashSet<_>>();
+    info!("address filter: {} addresses loaded", addrs.len());
+    Ok(addrs)
+}
+
+/// Index a single block into `store`: the block itself, a row for each
+/// of its txs, the addresses each tx touches and the address graph edges. Prior outputs for the tx ins
+/// are resolved from `lookup`, which is usually the same store, but can
+/// be a different one when re-indexing from an existing db, and then
+/// from `ctx.prevouts` if they're not there either.
+/// `block_json` is the raw json `block` was parsed from, stored as is.
+/// The per block aggregates are stored once all the txs are in. With
+/// an address filter, the block and its stats are always stored but txs
+/// that don't touch the filter are skipped. With `ctx.blocks_only`, only
+/// the block is stored.
+/// Returns the number of txs in the block. Errors carry the height, and
+/// the txid when they come up in a tx.
+pub fn index_block(
+    ctx: &IndexCtx,
+    lookup: &impl BlockStore,
+    store: &impl BlockStore,
+    height: i64,
+    block_json: &str,
+    block: Block,
+) -> Result<usize> {
+    let tx_count = block.tx.len();
+    debug!("[{}] hash: {}", height, &block.hash);
+    store
+        .put_block(height, &block.hash, block_json)
+        .map_err(|e| e.at_height(height))?;
+    if ctx.blocks_only {
+        return Ok(tx_count);
+    }
+
+    let mut stats = BlockStats {
+        height,
+        tx_count: tx_count as i64,
+        size: block.size as i64,
+        ..Default::default()
+    };
+
+    let raw_txs = match ctx.raw_tx_json {
+        true => Some(raw_block_txs(block_json, tx_count).map_err(|e| e.at_height(height))?),
+        false => None,
+    };
+
+    // Nothing in the prep depends on the store or the txs before, so
+    // it's done for all of them up front, in parallel.
+    let network = ctx.network;
+    let preps = block
+        .tx
+        .par_iter()
+        .enumerate()
+        .with_min_len(TX_PREP_MIN_LEN)
+        .map(|(i, tx)| {
+            let raw = raw_txs.as_ref().map(|x| x[i].get());
+            prep_tx(tx, network, raw).map_err(|e| e.in_tx(&tx.txid))
+        })
+        .collect::<Vec<_>>();
+
+    let mut cols = JsonCols::default();
+    for (tx, prep) in block.tx.into_iter().zip(preps) {
+        let prep = prep.map_err(|e| e.at_height(height))?;
+        let txid = tx.txid.clone();
+        index_tx(ctx, lookup, store, height, tx, prep, &mut stats, &mut cols)
+            .map_err(|e| e.in_tx(&txid).at_height(height))?;
+    }
+
+    store
+        .put_block_stats(&stats)
+        .map_err(|e| e.at_height(height))?;
+    Ok(tx_count)
+}
+
+/// Index a tx of the block at `height` on its own, without the block
+/// or its stats, eg. to look at the rows of one tx. The prior outputs
+/// have to be in the lookup store or come from `ctx.prevouts`.
+pub fn index_single_tx(
+    ctx: &IndexCtx,
+    lookup: &impl BlockStore,
+    store: &impl BlockStore,
+    height: i64,
+    tx: Transaction,
+) -> Result<()> {
+    let txid = tx.txid.clone();
+    let mut stats = BlockStats::default();
+    prep_tx(&tx, ctx.network, None)
+        .and_then(|prep| {
+            let mut cols = JsonCols::default();
+            index_tx(ctx, lookup, store, height, tx, prep, &mut stats, &mut cols)
+        })
+        .map_err(|e| e.in_tx(&txid).at_height(height))
+}
+
+// Txs per parallel prep task, so small blocks aren't split up for
+// less work than the split costs.
+const TX_PREP_MIN_LEN: usize = 16;
+
+/// The parts of a tx row that only need the tx itself.
+struct TxPrep {
+    /// The tx as json, for the data column.
+    data: String,
+    /// Addresses in the dvm message. Empty for txs without one.
+    dvm_addrs: HashSet<TStr>,
+}
+
+#[derive(Deserialize)]
+struct RawBlockTxs<'a> {
+    #[serde(borrow)]
+    tx: Vec<&'a RawValue>,
+}
+
+/// The json of each tx of the block, as it is in the block json.
+fn raw_block_txs(block_json: &str, tx_count: usize) -> Result<Vec<&RawValue>> {
+    let raw: RawBlockTxs = serde_json::from_str(block_json)?;
+    if raw.tx.len() != tx_count {
+        return Err(format!("raw txs: {} of {} txs", raw.tx.len(), tx_count).into());
+    }
+    Ok(raw.tx)
+}
+
+/// `raw` is the json of the tx to store as is, else it's re-serialized.
+fn prep_tx(tx: &Transaction, network: Network, raw: Option<&str>) -> Result<TxPrep> {
+    let dvm_addrs = match &tx.vm {
+        Some(vm) if !matches!(TxType::from(&*vm.txtype), TxType::Unknown | TxType::Utxo) => {
+            extract_all_dfi_addresses(vm.msg.get(), network)
+        }
+        _ => HashSet::new(),
+    };
+    Ok(TxPrep {
+        data: match raw {
+            Some(raw) => raw.to_owned(),
+            None => serde_json::to_string(tx)?,
+        },
+        dvm_addrs,
+    })
+}
+
+/// Index a tx of the block at `height` from its `prep`, adding it to the
+/// block `stats`. The json columns are written into `cols`, that's
+/// reused across txs.
+#[allow(clippy::too_many_arguments)]
+fn index_tx(
+    ctx: &IndexCtx,
+    lookup: &impl BlockStore,
+    store: &impl BlockStore,
+    height: i64,
+    tx: Transaction,
+    prep: TxPrep,
+    stats: &mut BlockStats,
+    cols: &mut JsonCols,
+) -> Result<()> {
+    if let Some(prev) = store.get_tx_height(&tx.txid)? {
+        if prev != height {
+            let skip = ctx.on_dup_txid == DupTxid::Skip;
+            warn!(
+                height,
+                "[{}] duplicate txid {}, already stored at [{}], {}",
+                height,
+                tx.txid,
+                prev,
+                if skip { "skipped" } else { "overwritten" }
+            );
+            if skip {
+                return Ok(());
+            }
+        }
+    }
+    let vout_value = tx.vout.iter().map(|x| x.value).sum::<f64>();
+    stats.total_vout_value += vout_value;
+    let utxos = Some(&ctx.utxo_cache);
+    let tx_in_addrs = dfiutils::get_txin_addr_val_list(&tx.vin, lookup, ctx.prevouts, utxos)?;
+    let tx_out_addrs = dfiutils::get_txout_addr_val_list(&tx, &tx.vout, utxos);
+    // Inputs whose prior output couldn't be found have no address or
+    // value, see `UNRESOLVED_INPUT`. There's no fee to work out then, and
+    // they're left out of the graph and the address table.
+    let unresolved = tx_in_addrs.iter().any(|x| is_unresolved_input(&x.0));
+    let fee = match unresolved {
+        true => empty(),
+        false => tx_fee(&tx_in_addrs, &tx.vout),
+    };
+
+    let tx_in_addrs = dfiutils::fold_addr_val_map(&tx_in_addrs);
+    let resolved_in_addrs = match unresolved {
+        true => Cow::Owned(
+            tx_in_addrs
+                .iter()
+                .filter(|(addr, _)| !is_unresolved_input(addr))
+                .map(|(addr, value)| (addr.clone(), *value))
+                .collect(),
+        ),
+        false => Cow::Borrowed(&tx_in_addrs),
+    };
+    // Data outs and the ones without an address don't go into the
+    // address columns, the graph or the address table.
+    let tx_out = dfiutils::fold_addr_val_map(&dfiutils::addr_txouts(&tx_out_addrs));
+    let tx_out_types = tx_out_addrs.iter().map(|x| x.2).collect::<Vec<_>>();
+
+    let mut tx_type = tx.vm.as_ref().map(|x| TxType::from(&*x.txtype));
+
+    if tx_in_addrs.is_empty() {
+        tx_type = Some(TxType::Coinbase);
+        stats.coinbase_value += vout_value;
+        stats.coinbase_addrs = serde_json::to_string(&tx_out)?;
+    }
+    if let Some(TxType::Other(code)) = &tx_type {
+        *ctx.unknown_tx_types
+            .borrow_mut()
+            .entry(code.clone())
+            .or_default() += 1;
+    }
+
+    let dvm_addrs = match &tx_type {
+        Some(TxType::Coinbase) => HashSet::new(),
+        _ => prep.dvm_addrs,
+    };
+
+    // Before the filters, so the txs they skip still spend the outs.
+    if ctx.track_utxos {
+        for vin in tx.vin.iter() {
+            if let Vin::Standard(x) = vin {
+                store.spend_utxo(&x.txid, x.vout, &tx.txid, height)?;
+            }
+        }
+        // The outs are in the order of the vout, data outs and the ones
+        // without an address aren't kept.
+        for ((addr, value, _), vout) in tx_out_addrs.iter().zip(&tx.vout) {
+            if let Some(addr) = addr {
+                store.put_utxo(&tx.txid, vout.n, addr, *value, height)?;
+            }
+        }
+    }
+
+    if let Some(filter) = ctx.address_filter {
+        // Multi-sig addresses are joined with a +, match on any of them.
+        let relevant = tx_in_addrs
+            .keys()
+            .chain(tx_out.keys())
+            .chain(dvm_addrs.iter())
+            .any(|x| x.split('+').any(|addr| filter.contains(addr)));
+        if !relevant {
+            return Ok(());
+        }
+    }
+    let mut icx_claim_data: Option<IcxTxSet> = None;
+    let mut icx_addr = empty();
+    let mut icx_amt = empty();
+    let mut swap_from = empty();
+    let mut swap_to = empty();
+    let mut swap_amt = empty();
+    let mut swap_pools = empty();
+    let mut transfer_from = empty();
+    let mut transfer_to = empty();
+    // Symbols of the tokens the dvm message moves, for the graph edges.
+    let mut dvm_tokens = Vec::new();
+    let mut icx_link = None;
+
+    // The fields out of the dvm message, parsed up front so a message
+    // that doesn't have them leaves the tx as `Unknown`, see `BadDvmMsg`.
+    let mut parse_dvm_msg = || -> Result<()> {
+        match tx_type {
+            Some(TxType::PoolSwap) | Some(TxType::CompositeSwap) => {
+                let swap_data: models::PoolSwapMsg = dvm_msg(&tx)?;
+                swap_from = ctx.tokens.symbol_maybe(&swap_data.from_token).to_string();
+                swap_to = ctx.tokens.symbol_maybe(&swap_data.to_token).to_string();
+                swap_amt = swap_data.from_amount.to_string();
+                if !swap_data.pools.is_empty() {
+                    swap_pools = serde_json::to_string(&swap_data.pools)?;
+                }
+                dvm_tokens = vec![swap_from.clone(), swap_to.clone()];
+            }
+            Some(TxType::AccountToAccount) => {
+                let msg: models::AccountToAccountMsg = dvm_msg(&tx)?;
+                transfer_from = msg.from.to_string();
+                transfer_to = serde_json::to_string(&msg.to)?;
+                dvm_tokens = amount_tokens(ctx.tokens, msg.to.values());
+            }
+            Some(TxType::AccountToUtxos) => {
+                let msg: models::AccountToUtxosMsg = dvm_msg(&tx)?;
+                transfer_from = msg.from.to_string();
+                transfer_to = serde_json::to_string(&msg.to)?;
+                dvm_tokens = amount_tokens(ctx.tokens, msg.to.values());
+            }
+            Some(TxType::UtxosToAccount) => {
+                // Source is the utxo ins, the msg only carries the dest accounts.
+                let msg: models::UtxosToAccountMsg = dvm_msg(&tx)?;
+                let mut from = tx_in_addrs.keys().map(|x| &**x).collect::<Vec<_>>();
+                from.sort_unstable();
+                transfer_from = from.join(",");
+                transfer_to = serde_json::to_string(&msg)?;
+                dvm_tokens = amount_tokens(ctx.tokens, msg.values());
+            }
+            _ => {}
+        }
+        icx_link = icx_msg_link(&tx, tx_type.as_ref())?;
+        Ok(())
+    };
+    if let Err(e) = parse_dvm_msg() {
+        bad_dvm_msg(ctx, &tx, tx_type.as_ref(), e)?;
+        tx_type = Some(TxType::Unknown);
+    }
+    {
+        let stored_type = tx_type.clone().unwrap_or(TxType::Unknown);
+        let mut tally = ctx.tx_type_tally.borrow_mut();
+        if tx.vm.is_some() && matches!(stored_type, TxType::Unknown | TxType::Other(_)) {
+            tally.unclassified_dvm += 1;
+        }
+        *tally.counts.entry(stored_type).or_default() += 1;
+    }
+    let keep = ctx
+        .tx_types
+        .keeps(tx_type.as_ref().unwrap_or(&TxType::Unknown));
+
+    if let Some(TxType::ICXClaimDFCHTLC) = tx_type {
+        if let Some(log_entry) = ctx.log_entry_map.get(&tx.txid)? {
+            if let Some(icx_data) = &log_entry.icx_data {
+                icx_claim_data = Some(icx_data.tx_set());
+                icx_addr = icx_data.address.to_string();
+                // It's log data, a bad amount is kept as it was logged
+                // rather than failing the tx.
+                icx_amt = match models::parse_amount(&icx_data.amount) {
+                    Ok(amount) => amount.to_string(),
+                    Err(e) => {
+                        warn!("icx amount: {}: {}: {}", tx.txid, icx_data.amount, e);
+                        icx_data.amount.to_string()
+                    }
+                };
+            }
+        }
+    }
+
+    // The dvm addresses that are also tx ins were _likely_ the source.
+    let (dvm_in_addrs, _): (Vec<_>, Vec<_>) = dvm_addrs
+        .iter()
+        .cloned()
+        .partition(|addr| tx_in_addrs.iter().any(|(in_addr, _)| *in_addr == *addr));
+
+    if ctx.enable_addr_graph && keep {
+        let changeset = dfiutils::edge_changeset(
+            &resolved_in_addrs,
+            &tx_out,
+            &dvm_in_addrs,
+            &dvm_addrs,
+            ctx.tokens.symbol_maybe("0"),
+            &dvm_tokens,
+        );
+        for ([edge_in, edge_out, token], (c_flags, value)) in &changeset {
+            store.put_edge(&EdgeRecord {
+                txid: &tx.txid,
+                in_addr: edge_in,
+                out_addr: edge_out,
+                token,
+                c_flags: *c_flags,
+                value: *value,
+            })?;
+        }
+    }
+
+    // Skipped by type, the utxos are still tracked so the spends of the
+    // txs that are kept stay right.
+    if !keep {
+        return Ok(());
+    }
+
+    if ctx.enable_address_table {
+        for (addr, value) in resolved_in_addrs.iter() {
+            store.put_address_tx(addr, &tx.txid, height, "in", *value)?;
+        }
+        for (addr, value) in tx_out.iter() {
+            store.put_address_tx(addr, &tx.txid, height, "out", *value)?;
+        }
+        // Amounts in the dvm message aren't parsed out generically.
+        for addr in dvm_addrs.iter() {
+            store.put_address_tx(addr, &tx.txid, height, "dvm", 0.)?;
+        }
+    }
+
+    // Transform to final strings. Mostly empty strings for non relevant fields
+
+    let tx_type_str = tx_type.clone().unwrap_or(TxType::Unknown).to_string();
+    cols.clear();
+    let tx_in_json = cols.push((!tx_in_addrs.is_empty()).then_some(&tx_in_addrs))?;
+    let tx_out_json = cols.push((!tx_out.is_empty()).then_some(&tx_out))?;
+    let dvm_in_addrs_json = cols.push((!dvm_in_addrs.is_empty()).then_some(&dvm_in_addrs))?;
+    let dvm_addrs_json = cols.push((!dvm_addrs.is_empty()).then_some(&dvm_addrs))?;
+    let tx_out_types_json = cols.push((!tx_out_types.is_empty()).then_some(&tx_out_types))?;
+    let icx_claim_data = cols.push(icx_claim_data.as_ref())?;
+    let icx_links = icx_links(ctx, &tx, icx_link)?;
+    let icx_links = cols.push((!icx_links.is_empty()).then_some(&icx_links))?;
+
+    store.put_tx(&TxRecord {
+        txid: &tx.txid,
+        height,
+        tx_type: &tx_type_str,
+        tx_in: cols.get(tx_in_json)?,
+        tx_out: cols.get(tx_out_json)?,
+        dvm_in: cols.get(dvm_in_addrs_json)?,
+        dvm_out: cols.get(dvm_addrs_json)?,
+        data: &prep.data,
+        icx_data: cols.get(icx_claim_data)?,
+        icx_addr: &icx_addr,
+        icx_btc_exp_amt: &icx_amt,
+        swap_from: &swap_from,
+        swap_to: &swap_to,
+        swap_amt: &swap_amt,
+        swap_pools: &swap_pools,
+        transfer_from: &transfer_from,
+        transfer_to: &transfer_to,
+        fee: &fee,
+        tx_out_types: cols.get(tx_out_types_json)?,
+        icx_links: cols.get(icx_links)?,
+        vin_count: tx.vin.len() as i64,
+        vout_count: tx.vout.len() as i64,
+    })?;
+    Ok(())
+}
+
+/// The ICX swaps an ICX order, offer, dfc htlc or claim tx is part of,
+/// with its role in them. These come from the log, through the claims.
+/// Without it, only the tx before it in the swap is linked, from the
+/// message, see `icx_msg_link`.
+fn icx_links(ctx: &IndexCtx, tx: &Transaction, msg_link: Option<IcxLink>) -> Result<Vec<IcxLink>> {
+    let Some(msg_link) = msg_link else {
+        return Ok(Vec::new());
+    };
+    if let Some(log_entry) = ctx.log_entry_map.get(&tx.txid)? {
+        if !log_entry.icx_links.is_empty() {
+            return Ok(log_entry.icx_links.clone());
+        }
+    }
+    Ok(vec![msg_link])
+}
+
+/// The link of an ICX tx to the tx before it in the swap, from its
+/// message. None for the other tx types.
+fn icx_msg_link(tx: &Transaction, tx_type: Option<&TxType>) -> Result<Option<IcxLink>> {
+    let role = match tx_type {
+        Some(TxType::ICXCreateOrder) => IcxRole::Order,
+        Some(TxType::ICXMakeOffer) => IcxRole::Offer,
+        Some(TxType::ICXSubmitDFCHTLC) => IcxRole::Dfchtlc,
+        Some(TxType::ICXClaimDFCHTLC) => IcxRole::Claim,
+        _ => return Ok(None),
+    };
+    let txid = tx.txid.clone();
+    let set = match role {
+        IcxRole::Order => IcxTxSet {
+            order_tx: txid,
+            ..Default::default()
+        },
+        IcxRole::Offer => {
+            let msg: models::IcxMakeOfferMsg = dvm_msg(tx)?;
+            IcxTxSet {
+                order_tx: msg.order_tx,
+                offer_tx: txid,
+                ..Default::default()
+            }
+        }
+        IcxRole::Dfchtlc => {
+            let msg: models::IcxSubmitDfchtlcMsg = dvm_msg(tx)?;
+            IcxTxSet {
+                offer_tx: msg.offer_tx,
+                dfchtlc_tx: txid,
+                ..Default::default()
+            }
+        }
+        IcxRole::Claim => {
+            let msg: models::IcxClaimDfchtlcMsg = dvm_msg(tx)?;
+            IcxTxSet {
+                dfchtlc_tx: msg.dfchtlc_tx,
+                claim_tx: txid,
+                ..Default::default()
+            }
+        }
+    };
+    Ok(Some(IcxLink { role, set }))
+}
+
+/// The dvm message of the tx, parsed as the message of its type.
+fn dvm_msg<T: serde::de::DeserializeOwned>(tx: &Transaction) -> Result<T> {
+    let vm = tx.vm.as_ref().ok_or("no dvm message")?;
+    let msg =
+        serde_json::from_str(vm.msg.get()).map_err(|e| format!("{} message: {}", vm.txtype, e))?;
+    Ok(msg)
+}
+
+/// A tx whose dvm message doesn't have what its type needs, which is
+/// either warned about, to then be stored as `Unknown`, or the error.
+fn bad_dvm_msg(ctx: &IndexCtx, tx: &Transaction, tx_type: Option<&TxType>, e: Error) -> Result<()> {
+    match ctx.on_bad_dvm_msg {
+        BadDvmMsg::Fail => Err(e),
+        BadDvmMsg::Unknown => {
+            let tx_type = tx_type.cloned().unwrap_or(TxType::Unknown);
+            warn!("tx {}: {}: {}, stored as unknown", tx.txid, tx_type, e);
+            Ok(())
+        }
+    }
+}
+
+/// Inputs less outputs, summed as exact 8 decimal amounts so the fee
+/// doesn't pick up float error. Empty for coinbase txs, that have no
+/// inputs to pay it from.
+fn tx_fee(tx_ins: &[(TStr, f64)], tx_outs: &[Vout]) -> String {
+    if tx_ins.is_empty() {
+        return empty();
+    }
+    let ins = tx_ins
+        .iter()
+        .map(|x| models::amount_from_f64(x.1))
+        .sum::<models::Amount>();
+    let outs = tx_outs
+        .iter()
+        .map(|x| models::amount_from_f64(x.value))
+        .sum::<models::Amount>();
+    (ins - outs).with_scale(models::AMOUNT_SCALE).to_string()
+}
+
+/// Symbols of the tokens in dvm `amount@token` values, each only once.
+fn amount_tokens<'a>(tokens: &TokenMap, amounts: impl Iterator<Item = &'a TStr>) -> Vec<String> {
+    let mut res = amounts
+        .flat_map(|x| dfiutils::token_amount_ids(x))
+        .map(|id| tokens.symbol_maybe(id).to_owned())
+        .collect::<Vec<_>>();
+    res.sort_unstable();
+    res.dedup();
+    res
+}
+
+static PROGRESS_BAR: AtomicBool = AtomicBool::new(false);
+
+/// Show a live progress bar on stderr in place of the periodic progress
+/// log line, for interactive runs.
+pub fn enable_progress_bar() {
+    PROGRESS_BAR.store(true, Ordering::Relaxed);
+}
+
+/// Progress tracking for the periodic log line. The rate is over the
+/// last interval, and the ETA assumes it holds for the remaining heights.
+/// With the progress bar enabled, the bar is updated every block instead.
+pub struct Progress {
+    end_height: i64,
+    last_instant: std::time::Instant,
+    last_height: Option<i64>,
+    txs_total: usize,
+    // Set up on the first block, once the start height is known.
+    bar: Option<ProgressBar>,
+}
+
+impl Progress {
+    pub fn new(end_height: i64) -> Self {
+        Self {
+            end_height,
+            last_instant: std::time::Instant::now(),
+            last_height: None,
+            txs_total: 0,
+            bar: None,
+        }
+    }
+
+    pub fn add_block(&mut self, height: i64, txs: usize) {
+        self.txs_total += txs;
+        if !PROGRESS_BAR.load(Ordering::Relaxed) {
+            return;
+        }
+        let end_height = self.end_height;
+        let bar = self.bar.get_or_insert_with(|| {
+            let bar = ProgressBar::new((end_height - height + 1).max(0) as u64);
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "{elapsed_precise} {wide_bar} {percent:>3}% {msg} | {per_sec} | eta: {eta}",
+                )
+                .expect("progress bar template"),
+            );
+            bar
+        });
+        bar.inc(1);
+        bar.set_message(format!(
+            "[{}] / [{}] | txs: {}",
+            height, end_height, self.txs_total
+        ));
+    }
+
+    pub fn log(&mut self, height: i64) {
+        if self.bar.is_some() {
+            return;
+        }
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.last_instant).as_secs_f64();
+        let blocks = match self.last_height {
+            Some(h) => height - h,
+            None => 0,
+        };
+        let rate = if elapsed > 0. && blocks > 0 {
+            blocks as f64 / elapsed
+        } else {
+            0.
+        };
+        let eta = if rate > 0. {
+            fmt_duration(((self.end_height - height).max(0) as f64 / rate) as u64)
+        } else {
+            "-".to_owned()
+        };
+        info!(
+            height,
+            end_height = self.end_height,
+            rate,
+            txs = self.txs_total,
+            "processed: [{}] / [{}] | {:.1} blk/s | txs: {} | eta: {}",
+            height,
+            self.end_height,
+            rate,
+            self.txs_total,
+            eta
+        );
+        self.last_instant = now;
+        self.last_height = Some(height);
+    }
+}
+
+impl Drop for Progress {
+    // Left on the screen at its last state.
+    fn drop(&mut self) {
+        if let Some(bar) = &self.bar {
+            bar.abandon();
+        }
+    }
+}
+
+fn fmt_duration(secs: u64) -> String {
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{}h {:02}m {:02}s", h, m, s)
+    } else {
+        format!("{}m {:02}s", m, s)
+    }
+}
+
+// Just a short convenience alias for internal use.
+fn empty() -> String {
+    String::new()
+}
+
+/// The in-memory store and tx fixtures are shared with the tests of the
+/// store wrappers in `db` and of the runs in `cliindexer`.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::db::TxAddrData;
+    use crate::models::Transaction;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    pub(crate) struct MemStore {
+        pub(crate) blocks: RefCell<Vec<(i64, String)>>,
+        // txid -> (tx_type, data)
+        pub(crate) txs: RefCell<HashMap<String, (String, String)>>,
+        // (txid, in_addr, out_addr, c_flags, value, token)
+        pub(crate) edges: RefCell<Vec<(String, String, String, i64, f64, String)>>,
+        // (address, txid, height, direction, value)
+        pub(crate) address_txs: RefCell<Vec<(String, String, i64, String, f64)>>,
+        pub(crate) stats: RefCell<Vec<BlockStats>>,
+    }
+
+    impl BlockStore for MemStore {
+        fn get_block_from_hash(&self, _hash: &str) -> Result<Option<Block>> {
+            Ok(None)
+        }
+        fn get_block_hash(&self, _height: i64) -> Result<Option<String>> {
+            Ok(None)
+        }
+        fn get_block_hash_for_tx(&self, _tx_hash: &str) -> Result<Option<String>> {
+            Ok(None)
+        }
+        fn get_block_for_tx(&self, _tx_hash: &str) -> Result<Option<Block>> {
+            Ok(None)
+        }
+        fn get_block_from_height(&self, _height: i64) -> Result<Option<Block>> {
+            Ok(None)
+        }
+        fn get_block_json(&self, _height: i64) -> Result<Option<String>> {
+            Ok(None)
+        }
+        fn get_tx_from_hash(&self, hash: &str) -> Result<Option<Transaction>> {
+            match self.txs.borrow().get(hash) {
+                Some((_, data)) => Ok(Some(serde_json::from_str(data)?)),
+                None => Ok(None),
+            }
+        }
+        fn get_tx_addr_data_from_hash(&self, _hash: &str) -> Result<Option<TxAddrData>> {
+            Ok(None)
+        }
+        fn get_tx_height(&self, _txid: &str) -> Result<Option<i64>> {
+            Ok(None)
+        }
+        fn get_max_height(&self) -> Result<Option<i64>> {
+            Ok(self.blocks.borrow().iter().map(|(h, _)| *h).max())
+        }
+        fn get_token_symbols(&self) -> Result<HashMap<String, String>> {
+            Ok(HashMap::new())
+        }
+        fn get_height_range(&self) -> Result<Option<(i64, i64)>> {
+            let heights = self
+                .blocks
+                .borrow()
+                .iter()
+                .map(|(h, _)| *h)
+                .collect::<Vec<_>>();
+            Ok(heights
+                .iter()
+                .min()
+                .copied()
+                .zip(heights.iter().max().copied()))
+        }
+        fn get_height_gaps(&self) -> Result<Vec<(i64, i64)>> {
+            let mut heights = self
+                .blocks
+                .borrow()
+                .iter()
+                .map(|(h, _)| *h)
+                .collect::<Vec<_>>();
+            heights.sort_unstable();
+            Ok(heights
+                .windows(2)
+                .filter(|w| w[1] > w[0] + 1)
+                .map(|w| (w[0] + 1, w[1] - 1))
+                .collect())
+        }
+        fn get_orphan_tx_heights(&self) -> Result<Vec<(i64, i64)>> {
+            Ok(Vec::new())
+        }
+        fn get_meta(&self) -> Result<HashMap<String, String>> {
+            Ok(HashMap::new())
+        }
+        fn get_table_counts(&self) -> Result<Vec<(&'static str, i64)>> {
+            Ok(vec![
+                ("blocks", self.blocks.borrow().len() as i64),
+                ("txs", self.txs.borrow().len() as i64),
+            ])
+        }
+        fn put_meta(&self, _meta: &HashMap<String, String>) -> Result<()> {
+            Ok(())
+        }
+        fn put_utxo(
+            &self,
+            _txid: &str,
+            _n: u64,
+            _address: &str,
+            _value: f64,
+            _height: i64,
+        ) -> Result<()> {
+            Ok(())
+        }
+        fn spend_utxo(&self, _txid: &str, _n: u64, _spent_txid: &str, _h: i64) -> Result<()> {
+            Ok(())
+        }
+        fn put_token_symbols(&self, _symbols: &HashMap<String, String>) -> Result<()> {
+            Ok(())
+        }
+        fn delete_height(&self, height: i64) -> Result<()> {
+            self.blocks.borrow_mut().retain(|(h, _)| *h != height);
+            self.stats.borrow_mut().retain(|x| x.height != height);
+            self.address_txs.borrow_mut().retain(|x| x.2 != height);
+            Ok(())
+        }
+        fn put_block(&self, height: i64, hash: &str, _data: &str) -> Result<()> {
+            self.blocks.borrow_mut().push((height, hash.to_owned()));
+            Ok(())
+        }
+        fn put_block_stats(&self, stats: &BlockStats) -> Result<()> {
+            self.stats.borrow_mut().push(stats.clone());
+            Ok(())
+        }
+        fn put_tx(&self, tx: &TxRecord) -> Result<()> {
+            self.txs.borrow_mut().insert(
+                tx.txid.to_owned(),
+                (tx.tx_type.to_owned(), tx.data.to_owned()),
+            );
+            Ok(())
+        }
+        fn put_edge(&self, edge: &EdgeRecord) -> Result<()> {
+            self.edges.borrow_mut().push((
+                edge.txid.to_owned(),
+                edge.in_addr.to_owned(),
+                edge.out_addr.to_owned(),
+                edge.c_flags,
+                edge.value,
+                edge.token.to_owned(),
+            ));
+            Ok(())
+        }
+        fn put_address_tx(
+            &self,
+            address: &str,
+            txid: &str,
+            height: i64,
+            direction: &str,
+            value: f64,
+        ) -> Result<()> {
+            self.address_txs.borrow_mut().push((
+                address.to_owned(),
+                txid.to_owned(),
+                height,
+                direction.to_owned(),
+                value,
+            ));
+            Ok(())
+        }
+        fn begin(&self) -> Result<()> {
+            Ok(())
+        }
+        fn commit(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn block_json(height: i64, hash: &str, tx: serde_json::Value) -> String {
+        serde_json::json!({
+            "hash": hash, "height": height, "confirmations": 1,
+            "strippedsize": 0, "size": 0, "weight": 0,
+            "minter": { "id": "", "totalMinted": 0, "stakeModifier": "" },
+            "version": 1, "versionHex": "", "merkleroot": "", "time": 0,
+            "mediantime": 0, "bits": "", "difficulty": 1.0, "chainwork": "",
+            "tx": [tx], "nTx": 1,
+        })
+        .to_string()
+    }
+
+    pub(crate) fn tx_json(txid: &str, vin: serde_json::Value, out_addr: &str) -> serde_json::Value {
+        serde_json::json!({
+            "txid": txid, "hash": txid, "version": 1, "size": 0, "vsize": 0,
+            "weight": 0, "locktime": 0, "hex": "", "vin": [vin],
+            "vout": [{
+                "value": 1.5, "n": 0,
+                "scriptPubKey": {
+                    "asm": "", "hex": "", "type": "pubkeyhash",
+                    "addresses": [out_addr],
+                },
+            }],
+        })
+    }
+
+    /// A coinbase tx paying `out_addr`.
+    pub(crate) fn coinbase_json(txid: &str, out_addr: &str) -> serde_json::Value {
+        tx_json(
+            txid,
+            serde_json::json!({ "coinbase": "00", "sequence": 0 }),
+            out_addr,
+        )
+    }
+
+    /// A vin spending the out `vout` of `txid`.
+    pub(crate) fn spend_vin(txid: &str, vout: u64) -> serde_json::Value {
+        serde_json::json!({
+            "txid": txid, "vout": vout,
+            "scriptSig": { "asm": "", "hex": "" }, "sequence": 0,
+        })
+    }
+
+    /// A ctx with everything off, tests turn on what they cover with
+    /// `IndexCtx { .., ..test_ctx(..) }`.
+    pub(crate) fn test_ctx<'a>(
+        log_entry_map: &'a LogEntryMap,
+        tokens: &'a TokenMap,
+    ) -> IndexCtx<'a> {
+        IndexCtx {
+            network: Network::Mainnet,
+            log_entry_map,
+            tokens,
+            enable_addr_graph: false,
+            enable_address_table: false,
+            prevouts: None,
+            address_filter: None,
+            unknown_tx_types: Default::default(),
+            tx_type_tally: Default::default(),
+            utxo_cache: UtxoCache::new(0),
+            blocks_only: false,
+            track_utxos: false,
+            raw_tx_json: false,
+            on_dup_txid: DupTxid::Overwrite,
+            on_bad_dvm_msg: BadDvmMsg::Unknown,
+            tx_types: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_index_block_with_mem_store() {
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let spend = tx_json("t2", spend_vin("t1", 0), "a2");
+
+        for (height, hash, tx) in [(1, "h1", cb), (2, "h2", spend)] {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        assert_eq!(
+            *store.blocks.borrow(),
+            vec![(1, "h1".to_owned()), (2, "h2".to_owned())]
+        );
+        assert_eq!(store.txs.borrow()["t1"].0, TxType::Coinbase.to_string());
+        assert_eq!(store.txs.borrow()["t2"].0, TxType::Unknown.to_string());
+        assert_eq!(
+            *store.edges.borrow(),
+            vec![(
+                "t2".to_owned(),
+                "a1".to_owned(),
+                "a2".to_owned(),
+                0,
+                1.5,
+                "dfi".to_owned()
+            )]
+        );
+        let addr_tx = |addr: &str, txid: &str, height, direction: &str| {
+            (
+                addr.to_owned(),
+                txid.to_owned(),
+                height,
+                direction.to_owned(),
+                1.5,
+            )
+        };
+        assert_eq!(
+            *store.address_txs.borrow(),
+            vec![
+                addr_tx("a1", "t1", 1, "out"),
+                addr_tx("a1", "t2", 2, "in"),
+                addr_tx("a2", "t2", 2, "out"),
+            ]
+        );
+        let stats = |height, coinbase_value, coinbase_addrs: &str| BlockStats {
+            height,
+            tx_count: 1,
+            total_vout_value: 1.5,
+            coinbase_value,
+            size: 0,
+            coinbase_addrs: coinbase_addrs.to_owned(),
+        };
+        assert_eq!(
+            *store.stats.borrow(),
+            vec![stats(1, 1.5, r#"{"a1":1.5}"#), stats(2, 0., "")]
+        );
+    }
+
+    #[test]
+    fn test_index_block_data_outs() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let mut spend = tx_json("t2", spend_vin("t1", 0), "a2");
+        spend["vout"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({
+                "value": 0.0, "n": 1,
+                "scriptPubKey": { "asm": "OP_RETURN 44665478", "hex": "", "type": "nulldata" },
+            }));
+        for (height, hash, tx) in [(1, "h1", cb), (2, "h2", spend)] {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        let txs = store.get_txs_by_address("a2").unwrap();
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0].tx_out, HashMap::from([("a2".to_owned(), 1.5)]));
+        assert_eq!(txs[0].tx_out_types, vec!["p2pkh", "nulldata"]);
+        assert_eq!(txs[0].fee, "0.00000000");
+        assert!(store.get_txs_by_address("x").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_index_single_tx() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let json = block_json(1, "h1", cb);
+        index_block(
+            &ctx,
+            &store,
+            &store,
+            1,
+            &json,
+            serde_json::from_str(&json).unwrap(),
+        )
+        .unwrap();
+        let spend = tx_json("t2", spend_vin("t1", 0), "a2");
+        let tx = serde_json::from_value(spend).unwrap();
+        index_single_tx(&ctx, &store, &store, 2, tx).unwrap();
+
+        // Only the tx row, no block for it.
+        assert_eq!(store.get_max_height().unwrap(), Some(1));
+        let row = store.get_tx_data("t2").unwrap().unwrap();
+        assert_eq!(row.height, 2);
+        assert_eq!(row.tx_in, HashMap::from([("a1".to_owned(), 1.5)]));
+        assert_eq!(row.tx_out, HashMap::from([("a2".to_owned(), 1.5)]));
+    }
+
+    #[test]
+    fn test_index_block_raw_tx_json() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let mut ctx = IndexCtx {
+            raw_tx_json: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let data = |txid: &str| -> String {
+            store
+                .conn
+                .query_row("SELECT data FROM txs WHERE txid = ?1", [txid], |r| r.get(0))
+                .unwrap()
+        };
+        for (height, txid, raw) in [(1, "t1", true), (2, "t2", false)] {
+            ctx.raw_tx_json = raw;
+            // Pretty, with the keys out of the struct order.
+            let tx = serde_json::to_string_pretty(&coinbase_json(txid, "a1")).unwrap();
+            let json = block_json(height, txid, serde_json::Value::Null)
+                .replace("[null]", &format!("[{}]", tx));
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+            assert_eq!(data(txid) == tx, raw);
+        }
+    }
+
+    #[test]
+    fn test_index_block_dup_txid() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let mut ctx = IndexCtx {
+            on_dup_txid: DupTxid::Skip,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = || coinbase_json("t1", "a1");
+        for (height, policy, want) in [
+            (1, DupTxid::Skip, 1),
+            (2, DupTxid::Skip, 1),
+            (3, DupTxid::Overwrite, 3),
+            // Re-indexing the same height isn't a duplicate.
+            (3, DupTxid::Skip, 3),
+        ] {
+            ctx.on_dup_txid = policy;
+            let json = block_json(height, &format!("h{}", height), cb());
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+            assert_eq!(store.get_tx_height("t1").unwrap(), Some(want));
+        }
+    }
+
+    #[test]
+    fn test_index_block_track_utxos() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            track_utxos: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let mut spend = tx_json("t2", spend_vin("t1", 0), "a2");
+        spend["vout"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!({
+                "value": 0.0, "n": 1,
+                "scriptPubKey": { "asm": "OP_RETURN 44665478", "hex": "", "type": "nulldata" },
+            }));
+        for (height, hash, tx) in [(1, "h1", cb), (2, "h2", spend)] {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        let utxos = || {
+            let mut stmt = store
+                .conn
+                .prepare("SELECT txid, n, address, spent_txid FROM utxos ORDER BY txid, n")
+                .unwrap();
+            stmt.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, i64>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap()
+        };
+        let utxo = |txid: &str, addr: &str, spent: Option<&str>| {
+            (
+                txid.to_owned(),
+                0,
+                addr.to_owned(),
+                spent.map(str::to_owned),
+            )
+        };
+        assert_eq!(
+            utxos(),
+            vec![utxo("t1", "a1", Some("t2")), utxo("t2", "a2", None)]
+        );
+
+        store.delete_height(2).unwrap();
+        assert_eq!(utxos(), vec![utxo("t1", "a1", None)]);
+    }
+
+    #[test]
+    fn test_index_block_icx_links() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let mut log_entry_map = LogEntryMap::new();
+        let logged = IcxTxSet {
+            order_tx: "o1".into(),
+            offer_tx: "t2".into(),
+            dfchtlc_tx: "d1".into(),
+            claim_tx: "c1".into(),
+        };
+        log_entry_map.add_icx_links(&logged);
+        log_entry_map.add_icx_links(&logged);
+        let tokens = TokenMap::default();
+        let ctx = test_ctx(&log_entry_map, &tokens);
+
+        let spend = spend_vin("t1", 0);
+        let cb = coinbase_json("t1", "a1");
+        let mut offer = tx_json("t2", spend.clone(), "a2");
+        offer["vm"] = serde_json::json!({
+            "vmtype": "dvm", "txtype": "ICXMakeOffer", "msg": { "orderTx": "o1" },
+        });
+        let mut htlc = tx_json("t3", spend, "a3");
+        htlc["vm"] = serde_json::json!({
+            "vmtype": "dvm", "txtype": "ICXSubmitDFCHTLC", "msg": { "offerTx": "f1" },
+        });
+        for (height, hash, tx) in [(1, "h1", cb), (2, "h2", offer), (3, "h3", htlc)] {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        let tx = |txid| store.get_tx_data(txid).unwrap().unwrap();
+        assert!(tx("t1").icx_links.is_empty());
+        // From the log, the whole swap.
+        let links = tx("t2").icx_links;
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].role, IcxRole::Offer);
+        assert_eq!(links[0].set, logged);
+        // Not logged, only the offer from the message.
+        let links = tx("t3").icx_links;
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].role, IcxRole::Dfchtlc);
+        assert_eq!(&*links[0].set.offer_tx, "f1");
+        assert_eq!(&*links[0].set.dfchtlc_tx, "t3");
+        assert!(links[0].set.order_tx.is_empty());
+    }
+
+    #[test]
+    fn test_index_block_bad_icx_amount() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let mut log_entry_map = LogEntryMap::new();
+        let logged = models::LogIcxData {
+            order_tx: "o1".into(),
+            offer_tx: "f1".into(),
+            dfchtlc_tx: "d1".into(),
+            claim_tx: "t2".into(),
+            address: "bc1q".into(),
+            amount: "0.1x".into(),
+        };
+        log_entry_map.add(0, models::LogLineKind::Icx, models::LogLine::Icx(logged));
+        let tokens = TokenMap::default();
+        let ctx = test_ctx(&log_entry_map, &tokens);
+
+        let cb = coinbase_json("t1", "a1");
+        let spend = spend_vin("t1", 0);
+        let mut claim = tx_json("t2", spend, "a2");
+        claim["vm"] = serde_json::json!({
+            "vmtype": "dvm", "txtype": "ICXClaimDFCHTLC", "msg": { "dfchtlcTx": "d1" },
+        });
+        for (height, hash, tx) in [(1, "h1", cb), (2, "h2", claim)] {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        // Kept as it was logged.
+        let tx = store.get_tx_data("t2").unwrap().unwrap();
+        assert_eq!(tx.icx_btc_exp_amt, "0.1x");
+        assert_eq!(tx.icx_addr, "bc1q");
+    }
+
+    #[test]
+    fn test_index_block_error_context() {
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = test_ctx(&log_entry_map, &tokens);
+
+        // Spends a tx that's nowhere to be found.
+        let spend = tx_json("t2", spend_vin("t1", 0), "a2");
+        let json = block_json(2, "h2", spend);
+        let block = serde_json::from_str(&json).unwrap();
+        let err = index_block(&ctx, &store, &store, 2, &json, block).unwrap_err();
+        assert_eq!(err.height(), Some(2));
+        assert_eq!(err.txid(), Some("t2"));
+        assert_eq!(err.kind(), crate::lang::ErrorKind::Other);
+        assert_eq!(err.to_string(), "[2] tx t2: tx hash not found: t1");
+    }
+
+    #[test]
+    fn test_index_block_blocks_only() {
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            blocks_only: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let json = block_json(1, "h1", cb);
+        let block = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            index_block(&ctx, &store, &store, 1, &json, block).unwrap(),
+            1
+        );
+
+        assert_eq!(*store.blocks.borrow(), vec![(1, "h1".to_owned())]);
+        assert!(store.txs.borrow().is_empty());
+        assert!(store.edges.borrow().is_empty());
+        assert!(store.address_txs.borrow().is_empty());
+        assert!(store.stats.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_index_block_edge_values() {
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        // a0 and a1 put in 1/4 and 3/4 of the 6, 1.5 goes out to b.
+        let mut cb = coinbase_json("t0", "a0");
+        let mut vout1 = cb["vout"][0].clone();
+        vout1["n"] = 1.into();
+        vout1["value"] = 4.5.into();
+        vout1["scriptPubKey"]["addresses"] = serde_json::json!(["a1"]);
+        cb["vout"].as_array_mut().unwrap().push(vout1);
+        let vin = |n: u64| spend_vin("t0", n);
+        let mut spend = tx_json("t1", vin(0), "b");
+        spend["vin"] = serde_json::json!([vin(0), vin(1)]);
+
+        for (height, hash, tx) in [(1, "h1", cb), (2, "h2", spend)] {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        let mut edges = store.edges.borrow().clone();
+        edges.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            edges,
+            vec![
+                (
+                    "t1".to_owned(),
+                    "a0".to_owned(),
+                    "b".to_owned(),
+                    0,
+                    0.375,
+                    "dfi".to_owned()
+                ),
+                (
+                    "t1".to_owned(),
+                    "a1".to_owned(),
+                    "b".to_owned(),
+                    0,
+                    1.125,
+                    "dfi".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tx_fee() {
+        let vout = |value: f64| -> Vout {
+            let mut v = tx_json("t", serde_json::Value::Null, "b")["vout"][0].clone();
+            v["value"] = value.into();
+            serde_json::from_value(v).unwrap()
+        };
+        // 0.1 + 0.2 - 0.3 is off by a hair in f64.
+        let ins = [(TStr::from("a0"), 0.1), (TStr::from("a1"), 0.2)];
+        assert_eq!(tx_fee(&ins, &[vout(0.3)]), "0.00000000");
+        let ins = [(TStr::from("a0"), 20.2)];
+        assert_eq!(tx_fee(&ins, &[vout(20.19999), vout(0.0)]), "0.00001000");
+        assert_eq!(tx_fee(&[], &[vout(20.2)]), "");
+    }
+
+    #[test]
+    fn test_index_block_edge_tokens() {
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let from = "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R";
+        let to = "8eG9Pe1wQnWZuXD5NRr3QaxDex9RJ99fd5";
+        let cb = coinbase_json("t0", from);
+        let mut swap = tx_json("t1", spend_vin("t0", 0), from);
+        swap["vm"] = serde_json::json!({
+            "vmtype": "dvm", "txtype": "PoolSwap",
+            "msg": {
+                "fromAddress": from, "toAddress": to,
+                "fromToken": "0", "toToken": "2", "fromAmount": 1.0,
+            },
+        });
+
+        for (height, hash, tx) in [(1, "h1", cb), (2, "h2", swap)] {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        let mut edges = store
+            .edges
+            .borrow()
+            .iter()
+            .map(|(_, i, o, c_flags, _, token)| (i.clone(), o.clone(), token.clone(), *c_flags))
+            .collect::<Vec<_>>();
+        edges.sort();
+        let edge = |o: &str, token: &str, c_flags| {
+            (from.to_owned(), o.to_owned(), token.to_owned(), c_flags)
+        };
+        // The utxo change back to the sender is also the dfi side of the swap.
+        assert_eq!(
+            edges,
+            vec![
+                edge(from, "btc", 1),
+                edge(from, "dfi", 2),
+                edge(to, "btc", 1),
+                edge(to, "dfi", 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_index_block_bad_dvm_msg() {
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let mut ctx = IndexCtx {
+            on_bad_dvm_msg: BadDvmMsg::Fail,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let from = "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R";
+        let cb = coinbase_json("t0", from);
+        let json = block_json(1, "h1", cb);
+        let block = serde_json::from_str(&json).unwrap();
+        index_block(&ctx, &store, &store, 1, &json, block).unwrap();
+
+        // A poolswap without its fromToken.
+        let mut swap = tx_json("t1", spend_vin("t0", 0), from);
+        swap["vm"] = serde_json::json!({
+            "vmtype": "dvm", "txtype": "PoolSwap",
+            "msg": { "fromAddress": from, "toAddress": from, "toToken": "2", "fromAmount": 1.0 },
+        });
+        let json = block_json(2, "h2", swap);
+
+        let block = serde_json::from_str(&json).unwrap();
+        let err = index_block(&ctx, &store, &store, 2, &json, block).unwrap_err();
+        assert_eq!(err.txid(), Some("t1"));
+        assert!(err.to_string().contains("fromToken"), "{}", err);
+        assert!(!store.txs.borrow().contains_key("t1"));
+
+        ctx.on_bad_dvm_msg = BadDvmMsg::Unknown;
+        let block = serde_json::from_str(&json).unwrap();
+        index_block(&ctx, &store, &store, 2, &json, block).unwrap();
+        let tx_type = store.txs.borrow()["t1"].0.clone();
+        assert_eq!(tx_type, TxType::Unknown.to_string());
+    }
+
+    #[test]
+    fn test_index_block_tx_types() {
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            // Where the outs of the skipped txs come from when spent.
+            utxo_cache: UtxoCache::new(16),
+            on_bad_dvm_msg: BadDvmMsg::Fail,
+            tx_types: TxTypeFilter {
+                dvm: Some(HashSet::from([TxType::PoolSwap])),
+                skip_coinbase: true,
+                skip_utxo: true,
+            },
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let from = "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R";
+        let spend = |txid: &str, prev: &str, vm: serde_json::Value| {
+            let vin = spend_vin(prev, 0);
+            let mut tx = tx_json(txid, vin, from);
+            tx["vm"] = vm;
+            tx
+        };
+        let cb = coinbase_json("t0", from);
+        let swap = spend(
+            "t1",
+            "t0",
+            serde_json::json!({
+                "vmtype": "dvm", "txtype": "PoolSwap",
+                "msg": {
+                    "fromAddress": from, "fromToken": "0", "fromAmount": 1.0,
+                    "toAddress": from, "toToken": "2",
+                },
+            }),
+        );
+        let utxo = spend(
+            "t2",
+            "t1",
+            serde_json::json!({ "vmtype": "utxo", "txtype": "utxo", "msg": {} }),
+        );
+        let blocks = [(1, "h1", cb), (2, "h2", swap), (3, "h3", utxo)];
+        for (height, hash, tx) in blocks {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        assert_eq!(store.blocks.borrow().len(), 3);
+        assert_eq!(store.stats.borrow().len(), 3);
+        let txs = store.txs.borrow();
+        assert_eq!(txs.keys().collect::<Vec<_>>(), vec!["t1"]);
+        assert_eq!(txs["t1"].0, TxType::PoolSwap.to_string());
+        assert!(store.edges.borrow().iter().all(|x| x.0 == "t1"));
+        assert!(store.address_txs.borrow().iter().all(|x| x.1 == "t1"));
+
+        assert!(TxTypeFilter::default().keeps(&TxType::Other("x".into())));
+        assert!(!ctx.tx_types.keeps(&TxType::Other("x".into())));
+        assert_eq!(
+            parse_tx_type_name("icxclaimdfchtlc"),
+            Ok(TxType::ICXClaimDFCHTLC)
+        );
+        assert!(parse_tx_type_name("Coinbase")
+            .unwrap_err()
+            .contains("--skip-coinbase-txs"));
+        assert!(parse_tx_type_name("swap").is_err());
+    }
+
+    #[test]
+    fn test_index_block_resolves_missing_prevouts() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stand in for defi-cli that serves `t0` and counts the calls.
+        let dir = std::env::temp_dir().join("dindexer-test-prevouts");
+        std::fs::create_dir_all(&dir).unwrap();
+        let calls = dir.join("calls");
+        let _ = std::fs::remove_file(&calls);
+        let mut t0 = coinbase_json("t0", "a0");
+        let mut vout1 = t0["vout"][0].clone();
+        vout1["n"] = 1.into();
+        vout1["scriptPubKey"]["addresses"] = serde_json::json!(["a1"]);
+        t0["vout"].as_array_mut().unwrap().push(vout1);
+        let cli = dir.join("defi-cli");
+        std::fs::write(
+            &cli,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\ncat <<'EOF'\n{}\nEOF\n",
+                calls.display(),
+                t0
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&cli, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let prevouts = PrevoutResolver::new(
+            dfiutils::CliDriver::with_cli_path(cli.display().to_string()),
+            &Default::default(),
+        );
+        let ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            prevouts: Some(&prevouts),
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        for (height, txid, n) in [(10, "t1", 0), (11, "t2", 1)] {
+            let tx = tx_json(txid, spend_vin("t0", n), "b");
+            let json = block_json(height, txid, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        assert_eq!(
+            std::fs::read_to_string(&calls).unwrap(),
+            "getrawtransaction t0 true\n"
+        );
+        assert_eq!(prevouts.cached_len(), 0);
+        assert_eq!(
+            *store.edges.borrow(),
+            vec![
+                (
+                    "t1".to_owned(),
+                    "a0".to_owned(),
+                    "b".to_owned(),
+                    0,
+                    1.5,
+                    "dfi".to_owned()
+                ),
+                (
+                    "t2".to_owned(),
+                    "a1".to_owned(),
+                    "b".to_owned(),
+                    0,
+                    1.5,
+                    "dfi".to_owned()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_index_block_unresolved_prevouts() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Stand in for defi-cli on a pruned node, where `t0:0` is spent.
+        let dir = std::env::temp_dir().join("dindexer-test-unresolved");
+        std::fs::create_dir_all(&dir).unwrap();
+        let calls = dir.join("calls");
+        let _ = std::fs::remove_file(&calls);
+        let cli = dir.join("defi-cli");
+        let script = format!("#!/bin/sh\necho \"$@\" >> '{}'\n", calls.display());
+        std::fs::write(&cli, script).unwrap();
+        std::fs::set_permissions(&cli, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let mut opts = dfiutils::PrevoutOpts {
+            prevout_source: dfiutils::PrevoutSource::TxOut,
+            allow_unresolved_prevouts: false,
+            ..Default::default()
+        };
+        let driver = || dfiutils::CliDriver::with_cli_path(cli.display().to_string());
+        let strict = PrevoutResolver::new(driver(), &opts);
+        opts.allow_unresolved_prevouts = true;
+        let prevouts = PrevoutResolver::new(driver(), &opts);
+        let mut ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            prevouts: Some(&strict),
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let tx = tx_json("t1", spend_vin("t0", 0), "b");
+        let json = block_json(10, "h10", tx);
+        let block = serde_json::from_str(&json).unwrap();
+        let err = index_block(&ctx, &store, &store, 10, &json, block).unwrap_err();
+        assert_eq!(err.to_string(), "[10] tx t1: tx vout not found: t0");
+
+        ctx.prevouts = Some(&prevouts);
+        let block = serde_json::from_str(&json).unwrap();
+        index_block(&ctx, &store, &store, 10, &json, block).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&calls).unwrap(),
+            "gettxout t0 0\ngettxout t0 0\n"
+        );
+        let tx = store.get_tx_data("t1").unwrap().unwrap();
+        assert_eq!(
+            tx.tx_in,
+            HashMap::from([("unresolved:t0:0".to_owned(), 0.)])
+        );
+        assert_eq!(tx.fee, "");
+        let count = |table: &str| -> i64 {
+            let q = format!("SELECT count(*) FROM {} WHERE txid = 't1'", table);
+            store.conn.query_row(&q, [], |row| row.get(0)).unwrap()
+        };
+        // Only the out, the input has no address to go with.
+        assert_eq!(count("address_tx"), 1);
+        assert_eq!(count("tx_addr_graph"), 0);
+    }
+
+    #[test]
+    fn test_index_block_tallies_unknown_tx_types() {
+        let store = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let ctx = test_ctx(&log_entry_map, &tokens);
+
+        let cb = coinbase_json("t1", "a1");
+        let json = block_json(1, "h1", cb);
+        let block = serde_json::from_str(&json).unwrap();
+        index_block(&ctx, &store, &store, 1, &json, block).unwrap();
+
+        for (height, txid, code) in [(2, "t2", "SetGovVariable"), (3, "t3", "SetGovVariable")] {
+            let mut tx = tx_json(txid, spend_vin("t1", 0), "a2");
+            tx["vm"] = serde_json::json!({ "vmtype": "dvm", "txtype": code, "msg": {} });
+            let json = block_json(height, &format!("h{}", height), tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        assert_eq!(store.txs.borrow()["t2"].0, "SetGovVariable");
+        assert_eq!(
+            *ctx.unknown_tx_types.borrow(),
+            HashMap::from([("SetGovVariable".to_owned(), 2)])
+        );
+        let tally = ctx.tx_type_tally.borrow();
+        assert_eq!(
+            tally.counts,
+            HashMap::from([
+                (TxType::Coinbase, 1),
+                (TxType::Other("SetGovVariable".to_owned()), 2)
+            ])
+        );
+        assert_eq!(tally.unclassified_dvm, 2);
+        assert!(store.address_txs.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_index_block_with_address_filter() {
+        let src = MemStore::default();
+        let dest = MemStore::default();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let filter = HashSet::from([TStr::from("a2")]);
+        let mut ctx = IndexCtx {
+            enable_addr_graph: true,
+            enable_address_table: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        let cb = coinbase_json("t1", "a1");
+        let spend = tx_json("t2", spend_vin("t1", 0), "a2");
+        let blocks = [(1, "h1", cb), (2, "h2", spend)]
+            .map(|(height, hash, tx)| (height, block_json(height, hash, tx)));
+
+        // Full index to resolve the prevouts from, then the filtered one.
+        for (store, filter) in [(&src, None), (&dest, Some(&filter))] {
+            ctx.address_filter = filter;
+            for (height, json) in &blocks {
+                let block = serde_json::from_str(json).unwrap();
+                index_block(&ctx, &src, store, *height, json, block).unwrap();
+            }
+        }
+
+        assert_eq!(
+            *dest.blocks.borrow(),
+            vec![(1, "h1".to_owned()), (2, "h2".to_owned())]
+        );
+        assert_eq!(dest.txs.borrow().keys().collect::<Vec<_>>(), vec!["t2"]);
+        assert_eq!(
+            *dest.edges.borrow(),
+            vec![(
+                "t2".to_owned(),
+                "a1".to_owned(),
+                "a2".to_owned(),
+                0,
+                1.5,
+                "dfi".to_owned()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_index_block_address_filter_tracks_utxos() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let log_entry_map = LogEntryMap::new();
+        let tokens = TokenMap::default();
+        let filter = HashSet::from([TStr::from("a1")]);
+        let ctx = IndexCtx {
+            address_filter: Some(&filter),
+            track_utxos: true,
+            ..test_ctx(&log_entry_map, &tokens)
+        };
+
+        // t3 doesn't touch a1, but spends the out of t2 that's tracked.
+        let blocks = [
+            (1, "h1", coinbase_json("t1", "a1")),
+            (2, "h2", tx_json("t2", spend_vin("t1", 0), "a2")),
+            (3, "h3", tx_json("t3", spend_vin("t2", 0), "a3")),
+        ];
+        for (height, hash, tx) in blocks {
+            let json = block_json(height, hash, tx);
+            let block = serde_json::from_str(&json).unwrap();
+            index_block(&ctx, &store, &store, height, &json, block).unwrap();
+        }
+
+        assert_eq!(store.get_tx_height("t3").unwrap(), None);
+        let utxos = store
+            .conn
+            .prepare("SELECT txid, spent_txid FROM utxos ORDER BY txid")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(String, Option<String>)>>>()
+            .unwrap();
+        let utxo = |txid: &str, spent: Option<&str>| (txid.to_owned(), spent.map(str::to_owned));
+        assert_eq!(
+            utxos,
+            vec![
+                utxo("t1", Some("t2")),
+                utxo("t2", Some("t3")),
+                utxo("t3", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_run_meta() {
+        let store = crate::db::SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        record_run_start(&store, Network::Testnet, false, false).unwrap();
+        store.put_block(5, "h5", "{}").unwrap();
+        store.put_block(7, "h7", "{}").unwrap();
+        record_run_end(&store).unwrap();
+        // Another network only warns, the first one stays recorded.
+        // Heights indexed without the address table leave it incomplete.
+        record_run_start(&store, Network::Mainnet, true, true).unwrap();
+
+        let meta = store.get_meta().unwrap();
+        assert_eq!(meta[db::META_NETWORK], "testnet");
+        assert_eq!(
+            meta[db::META_SCHEMA_VERSION],
+            db::SCHEMA_VERSION.to_string()
+        );
+        assert_eq!(meta[db::META_CRATE_VERSION], env!("CARGO_PKG_VERSION"));
+        assert_eq!(meta[db::META_GRAPH_TABLE], "true");
+        assert_eq!(meta[db::META_ADDRESS_TABLE], "false");
+        assert_eq!(meta[db::META_MIN_HEIGHT], "5");
+        assert_eq!(meta[db::META_MAX_HEIGHT], "7");
+        for k in [
+            db::META_CREATED_AT,
+            db::META_RUN_STARTED_AT,
+            db::META_RUN_FINISHED_AT,
+        ] {
+            assert!(meta[k].parse::<u64>().unwrap() > 0);
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use crate::args::Args;
 use crate::db::{encode_height, rocks_open_db, RocksBlockStore};
-use crate::dfiutils::extract_all_dfi_addresses;
+use crate::dfiutils::{extract_all_dfi_addresses, Network};
 use crate::lang::{Error, Result};
 use crate::models::TxType;
 use petgraph::graph::NodeIndex;
@@ -103,7 +103,7 @@ pub fn graph_it(args: Args) -> Result<()> {
             ) {
                 let t = tx_type.clone().unwrap();
                 let dvm_data = x.vm.as_ref().map(|x| x.msg.to_string()).unwrap();
-                dvm_addrs = extract_all_dfi_addresses(&dvm_data)
+                dvm_addrs = extract_all_dfi_addresses(&dvm_data, Network::Mainnet)
                     .into_iter()
                     .collect::<Vec<_>>();
 
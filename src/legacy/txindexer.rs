@@ -1,5 +1,7 @@
 use crate::db::{encode_height, rocks_open_db, RocksBlockStore};
-use crate::dfiutils::{fold_addr_val_map, get_txin_addr_val_list, get_txout_addr_val_list};
+use crate::dfiutils::{
+    addr_txouts, fold_addr_val_map, get_txin_addr_val_list, get_txout_addr_val_list,
+};
 use crate::lang::{Error, Result};
 use rust_rocksdb::WriteBatch;
 use tracing::{error, info};
@@ -47,9 +49,10 @@ pub fn index_tx_data() -> Result<()> {
                 batch_tx.put_cf(&cf_tx, format!("{}/t", tx.txid), tx_type);
             } else {
                 // info!(height = i,txid = &tx.txid);
-                let tx_ins = get_txin_addr_val_list(&tx.vin, &block_store).inspect_err(|_| {
-                    error!("tx_in err: {} // {}", &tx.txid, &block.hash);
-                })?;
+                let tx_ins = get_txin_addr_val_list(&tx.vin, &block_store, None, None)
+                    .inspect_err(|_| {
+                        error!("tx_in err: {} // {}", &tx.txid, &block.hash);
+                    })?;
                 let tx_ins = fold_addr_val_map(&tx_ins);
                 batch_tx.put_cf(
                     &cf_tx,
@@ -58,8 +61,8 @@ pub fn index_tx_data() -> Result<()> {
                 );
                 // info!("in: {:?}", tx_ins);
 
-                let tx_outs = get_txout_addr_val_list(&tx, &tx.vout);
-                let tx_outs = fold_addr_val_map(&tx_outs);
+                let tx_outs = get_txout_addr_val_list(&tx, &tx.vout, None);
+                let tx_outs = fold_addr_val_map(&addr_txouts(&tx_outs));
                 batch_tx.put_cf(
                     &cf_tx,
                     format!("{}/out", tx.txid),
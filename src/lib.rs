@@ -0,0 +1,39 @@
+//! DeFiChain indexer and the analysis over what it indexes.
+//!
+//! Besides the `chain-analyzer` binary, the crate can be used as a
+//! dependency to read a db it built: see `query` for the typed reads,
+//! and `models` and `db` for the types they return.
+
+#![feature(error_generic_member_access)]
+
+pub mod args;
+mod balances;
+pub mod cliindexer;
+pub mod csvexport;
+pub mod db;
+pub mod dfiutils;
+pub mod dotreducer;
+pub mod gpath;
+pub mod graphbuild;
+pub mod graphdot;
+pub mod graphexport;
+mod graphutils;
+pub mod graphwalk;
+pub mod icx1;
+pub mod icx2;
+mod icxlog;
+pub mod icxseq;
+pub mod indexer;
+pub mod lang;
+pub mod logparse;
+mod metrics;
+pub mod models;
+#[cfg(feature = "parquet")]
+pub mod pqexport;
+pub mod prune;
+pub mod query;
+pub mod spath;
+pub mod sqliteindex;
+mod verify;
+
+use crate::lang::Result;
@@ -1,33 +1,109 @@
 use crate::lang;
-use crate::models;
-use crate::models::LogEntry;
 use crate::models::LogEntryMap;
-use crate::models::LogIcxCalcData;
-use crate::models::LogSwapData;
+use crate::models::LogIndex;
+use crate::models::LogLine;
+use crate::models::LogLineKind;
 use clap::Parser;
 use lang::Result;
-use models::LogIcxData;
+use std::cmp::Reverse;
 use std::io::BufRead;
+use tracing::debug;
 use tracing::info;
 use tracing::trace;
+use tracing::warn;
 
 #[derive(Parser, Debug)]
 pub struct LogParseArgs {
-    // The path to the debug.log file from defid.
-    // This can be both gzipped or raw file. If the file is gzipped
-    // it will automatically be decompressed on the fly.
+    /// The path to the debug.log file from defid.
+    /// This can be both gzipped or raw file. If the file is gzipped, by
+    /// its extension or magic bytes, it will automatically be decompressed
+    /// on the fly. A glob, eg. `logs/debug.log*`, takes all the files it
+    /// matches, oldest first by their logrotate suffix: `debug.log.2.gz`,
+    /// `debug.log.1`, then `debug.log`. Later files win for the txs
+    /// logged again
     #[arg(long, default_value = "data/debug.log.gz")]
     pub defid_log_path: String,
-    #[arg(long, default_value = "ICX:")]
-    pub log_icx_matcher: String,
-    #[arg(long, default_value = "ICXCalc:")]
-    pub log_icx_calc_matcher: String,
-    #[arg(long, default_value = "SwapResult:")]
-    pub log_swap_matcher: String,
+    #[command(flatten)]
+    pub log_match: LogMatchArgs,
+}
+
+/// What picks out each kind of line in the defid log, to take the json
+/// from. A line is matched if any of the substrings or the regex hits.
+/// Each kind falls back to its default substring when it's given neither.
+#[derive(clap::Args, Debug, Clone)]
+pub struct LogMatchArgs {
+    /// Substring of the ICX lines, can be given more than once.
+    /// Default: `ICX:`
+    #[arg(long)]
+    pub log_icx_matcher: Vec<String>,
+    #[arg(long)]
+    pub log_icx_regex: Option<String>,
+    /// Substring of the ICX calc lines, can be given more than once.
+    /// Default: `ICXCalc:`
+    #[arg(long)]
+    pub log_icx_calc_matcher: Vec<String>,
+    #[arg(long)]
+    pub log_icx_calc_regex: Option<String>,
+    /// Substring of the swap result lines, can be given more than once.
+    /// Default: `SwapResult:`
+    #[arg(long)]
+    pub log_swap_matcher: Vec<String>,
+    #[arg(long)]
+    pub log_swap_regex: Option<String>,
+}
+
+impl LogMatchArgs {
+    pub fn matchers(&self) -> Result<LogMatchers> {
+        Ok(LogMatchers {
+            icx: LineMatcher::new(&self.log_icx_matcher, self.log_icx_regex.as_deref(), "ICX:")?,
+            icx_calc: LineMatcher::new(
+                &self.log_icx_calc_matcher,
+                self.log_icx_calc_regex.as_deref(),
+                "ICXCalc:",
+            )?,
+            swap: LineMatcher::new(
+                &self.log_swap_matcher,
+                self.log_swap_regex.as_deref(),
+                "SwapResult:",
+            )?,
+        })
+    }
+}
+
+pub struct LogMatchers {
+    pub icx: LineMatcher,
+    pub icx_calc: LineMatcher,
+    pub swap: LineMatcher,
+}
+
+pub struct LineMatcher {
+    substrs: Vec<String>,
+    regex: Option<regex::Regex>,
+}
+
+impl LineMatcher {
+    fn new(substrs: &[String], regex: Option<&str>, default: &str) -> Result<Self> {
+        let regex = match regex {
+            Some(re) => {
+                Some(regex::Regex::new(re).map_err(|e| format!("log regex: {}: {}", re, e))?)
+            }
+            None => None,
+        };
+        let substrs = match substrs.is_empty() && regex.is_none() {
+            true => vec![default.to_owned()],
+            false => substrs.to_vec(),
+        };
+        Ok(Self { substrs, regex })
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        self.substrs.iter().any(|x| line.contains(x.as_str()))
+            || self.regex.as_ref().is_some_and(|re| re.is_match(line))
+    }
 }
 
 pub fn run(args: &LogParseArgs) -> Result<()> {
-    info!("{:?}", args);
+    debug!("{:?}", args);
 
     let defid_log_path = match args.defid_log_path.is_empty() {
         true => return Err(lang::Error::from("defid log path is empty")),
@@ -35,17 +111,16 @@ pub fn run(args: &LogParseArgs) -> Result<()> {
     };
 
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+    lang::on_signal(signal_hook::consts::SIGINT, &quit)?;
 
     let mut log_entry_map = LogEntryMap::new();
 
     info!("ingesting log file: {}", defid_log_path);
 
-    process_log_file(
+    process_log_files(
         defid_log_path,
-        args.log_icx_matcher.as_str(),
-        args.log_icx_calc_matcher.as_str(),
-        args.log_swap_matcher.as_str(),
+        &args.log_match.matchers()?,
+        false,
         &mut log_entry_map,
     )?;
 
@@ -55,7 +130,7 @@ pub fn run(args: &LogParseArgs) -> Result<()> {
         \tTotal ICX entries:      {}\n\
         \tTotal ICX calc entries: {}\n\
         \tTotal Swap entries:     {}",
-        log_entry_map.data.len(),
+        log_entry_map.tx_count(),
         log_entry_map.icx_count,
         log_entry_map.icx_calc_count,
         log_entry_map.swap_count,
@@ -64,73 +139,294 @@ pub fn run(args: &LogParseArgs) -> Result<()> {
     Ok(())
 }
 
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Ingest all the log files the path matches, if it's a glob, oldest
+/// first, see `log_rotation_key`, so later files win for the txs logged
+/// again. Plain paths are taken as is. A lazy log has to be a single file.
+pub fn process_log_files(
+    defid_log_path: &str,
+    matchers: &LogMatchers,
+    lazy: bool,
+    combined_data: &mut LogEntryMap,
+) -> Result<()> {
+    let paths = log_paths(defid_log_path)?;
+    if lazy && paths.len() > 1 {
+        return Err(lang::Error::from("lazy defid log needs a single file"));
+    }
+    for path in &paths {
+        if paths.len() > 1 {
+            info!("ingesting log file: {}", path);
+        }
+        process_log_file(path, matchers, lazy, combined_data)?;
+    }
+    if combined_data.dup_icx_count > 0 {
+        warn!(
+            "log: {} ICX claims logged more than once, kept the latest",
+            combined_data.dup_icx_count
+        );
+    }
+    Ok(())
+}
+
+fn log_paths(pattern: &str) -> Result<Vec<String>> {
+    if !pattern.contains(['*', '?', '[']) {
+        return Ok(vec![pattern.to_owned()]);
+    }
+    let mut paths = glob::glob(pattern)
+        .map_err(|e| format!("defid log glob: {}: {}", pattern, e))?
+        .map(|x| -> Result<String> {
+            let path = x.map_err(|e| format!("defid log glob: {}: {}", pattern, e))?;
+            Ok(path.to_string_lossy().into_owned())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if paths.is_empty() {
+        return Err(format!("defid log glob: {}: no files matched", pattern).into());
+    }
+    paths.sort_by(|a, b| log_rotation_key(a).cmp(&log_rotation_key(b)));
+    Ok(paths)
+}
+
+/// Sort key of a log file, oldest first. logrotate numbers the rotated
+/// files up from `.1` for the newest, so `debug.log.10` is older than
+/// `debug.log.2.gz`, which is older than `debug.log.1`, then `debug.log`.
+fn log_rotation_key(path: &str) -> (&str, Reverse<u64>) {
+    let name = path.strip_suffix(".gz").unwrap_or(path);
+    match name.rsplit_once('.') {
+        Some((base, n)) if !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()) => {
+            (base, Reverse(n.parse().unwrap_or(u64::MAX)))
+        }
+        _ => (name, Reverse(0)),
+    }
+}
+
+/// With `lazy`, only where the lines of each tx are is kept, and they're
+/// read back from the file as the txs are indexed. This needs the raw,
+/// uncompressed log.
 pub fn process_log_file(
     defid_log_path: &str,
-    log_icx_matcher: &str,
-    log_icx_calc_matcher: &str,
-    log_swap_matcher: &str,
+    matchers: &LogMatchers,
+    lazy: bool,
     combined_data: &mut LogEntryMap,
 ) -> Result<()> {
-    let file = std::fs::File::open(defid_log_path)?;
-    let mut reader: Box<dyn BufRead> = if defid_log_path.ends_with(".gz") {
+    let mut file = std::io::BufReader::new(std::fs::File::open(defid_log_path)?);
+    // Compressed logs are found by their magic bytes too, since archived
+    // ones don't always keep the extension.
+    let gz = defid_log_path.ends_with(".gz") || file.fill_buf()?.starts_with(&GZIP_MAGIC);
+    if lazy {
+        if gz {
+            return Err(lang::Error::from(
+                "lazy defid log needs an uncompressed file",
+            ));
+        }
+        combined_data.index = Some(LogIndex::open(defid_log_path)?);
+    }
+    let mut reader: Box<dyn BufRead> = if gz {
         Box::new(std::io::BufReader::new(flate2::read::GzDecoder::new(file)))
     } else {
-        Box::new(std::io::BufReader::new(file))
+        Box::new(file)
     };
 
     let mut line_buffer = String::new();
+    let mut offset = 0;
 
-    fn parse_json_line<T>(line: &str) -> Option<T>
-    where
-        T: serde::de::DeserializeOwned,
-    {
-        line.find('{')
-            .map(|start| &line[start..])
-            .and_then(|json_str| match serde_json::from_str(json_str) {
-                Ok(data) => Some(data),
-                Err(_) => {
-                    trace!("json parse failure: {}", json_str);
-                    None
-                }
-            })
-    }
-
-    while reader.read_line(&mut line_buffer)? != 0 {
-        match () {
-            _ if line_buffer.contains(log_icx_matcher) => {
-                if let Some(data) = parse_json_line::<LogIcxData>(&line_buffer) {
-                    let entry = combined_data
-                        .data
-                        .entry(data.claim_tx.clone())
-                        .or_insert_with(LogEntry::new);
-                    entry.icx_data = Some(data);
-                    combined_data.icx_count += 1;
-                }
-            }
-            _ if line_buffer.contains(log_icx_calc_matcher) => {
-                if let Some(data) = parse_json_line::<LogIcxCalcData>(&line_buffer) {
-                    let entry = combined_data
-                        .data
-                        .entry(data.calc_tx.clone())
-                        .or_insert_with(LogEntry::new);
-                    entry.icx_calc_data = Some(data);
-                    combined_data.icx_calc_count += 1;
-                }
-            }
-            _ if line_buffer.contains(log_swap_matcher) => {
-                if let Some(data) = parse_json_line::<LogSwapData>(&line_buffer) {
-                    let entry = combined_data
-                        .data
-                        .entry(data.txid.clone())
-                        .or_insert_with(LogEntry::new);
-                    entry.swap_data = Some(data);
-                    combined_data.swap_count += 1;
-                }
+    loop {
+        let n = reader.read_line(&mut line_buffer)?;
+        if n == 0 {
+            break;
+        }
+        let kind = match () {
+            _ if matchers.icx.is_match(&line_buffer) => Some(LogLineKind::Icx),
+            _ if matchers.icx_calc.is_match(&line_buffer) => Some(LogLineKind::IcxCalc),
+            _ if matchers.swap.is_match(&line_buffer) => Some(LogLineKind::Swap),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            match LogLine::parse(kind, &line_buffer) {
+                Some(line) => combined_data.add(offset, kind, line),
+                None => trace!("json parse failure: {}", line_buffer.trim_end()),
             }
-            _ => {}
         }
+        offset += n as u64;
         line_buffer.clear();
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Parser)]
+    struct TestArgs {
+        #[command(flatten)]
+        log_match: LogMatchArgs,
+    }
+
+    #[test]
+    fn test_process_log_file_matchers() {
+        let path = std::env::temp_dir().join("dindexer-test-matchers.log");
+        std::fs::write(
+            &path,
+            "2024-11-05T09:31:59Z SwapResult: {\"txid\":\"s1\",\"destination\":\"a\",\"result\":\"1@0\"}\n\
+            2024-11-05T09:32:00Z swap_result {\"txid\":\"s2\",\"destination\":\"a\",\"result\":\"1@0\"}\n\
+            2024-11-05T09:32:01Z SwapRes v2: {\"txid\":\"s3\",\"destination\":\"a\",\"result\":\"1@0\"}\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let ingest = |argv: &[&str]| {
+            let args = TestArgs::parse_from([&["test"][..], argv].concat());
+            let mut map = LogEntryMap::new();
+            process_log_file(path, &args.log_match.matchers().unwrap(), false, &mut map).unwrap();
+            map.swap_count
+        };
+
+        assert_eq!(ingest(&[]), 1);
+        assert_eq!(ingest(&["--log-swap-matcher", "swap_result"]), 1);
+        assert_eq!(
+            ingest(&[
+                "--log-swap-matcher",
+                "SwapResult:",
+                "--log-swap-matcher",
+                "swap_result"
+            ]),
+            2
+        );
+        assert_eq!(ingest(&["--log-swap-regex", r"SwapRes(ult:| v\d+:)"]), 2);
+        assert!(TestArgs::parse_from(["test", "--log-swap-regex", "("])
+            .log_match
+            .matchers()
+            .is_err());
+    }
+
+    #[test]
+    fn test_process_log_file_lazy() {
+        let path = std::env::temp_dir().join("dindexer-test-lazy.log");
+        std::fs::write(
+            &path,
+            "2024-11-05T09:31:58Z ICX: {\"order_tx\":\"o1\",\"offer_tx\":\"f1\",\"dfchtlc_tx\":\"h1\",\"claim_tx\":\"c1\",\"address\":\"bc1q\",\"amount\":\"0.1\"}\n\
+            2024-11-05T09:31:59Z SwapResult: {\"txid\":\"s1\",\"destination\":\"a\",\"result\":\"1@0\"}\n\
+            2024-11-05T09:32:00Z SwapResult: {broken\n\
+            2024-11-05T09:32:01Z SwapResult: {\"txid\":\"s2\",\"destination\":\"b\",\"result\":\"2@0\"}\n",
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let matchers = TestArgs::parse_from(["test"]).log_match.matchers().unwrap();
+        let ingest = |lazy| {
+            let mut map = LogEntryMap::new();
+            process_log_file(path, &matchers, lazy, &mut map).unwrap();
+            map
+        };
+        let eager = ingest(false);
+        let lazy = ingest(true);
+
+        assert!(lazy.data.is_empty());
+        assert_eq!(lazy.tx_count(), 6);
+        assert_eq!((lazy.icx_count, lazy.swap_count), (1, 2));
+        for txid in ["o1", "f1", "h1", "c1", "s1", "s2"] {
+            let want = eager.get(txid).unwrap().unwrap();
+            let got = lazy.get(txid).unwrap().unwrap();
+            assert_eq!(got.icx_links, want.icx_links);
+            assert_eq!(
+                got.icx_data.as_ref().map(|x| x.tx_set()),
+                want.icx_data.as_ref().map(|x| x.tx_set())
+            );
+            assert_eq!(
+                got.swap_data.as_ref().map(|x| &x.result),
+                want.swap_data.as_ref().map(|x| &x.result)
+            );
+        }
+        assert!(lazy.get("x1").unwrap().is_none());
+        let icx = |map: &LogEntryMap| -> Vec<_> {
+            let icx = map.icx_data().unwrap();
+            icx.iter().map(|x| (x.tx_set(), x.amount.clone())).collect()
+        };
+        assert_eq!(icx(&lazy), icx(&eager));
+        assert_eq!(icx(&lazy).len(), 1);
+    }
+
+    #[test]
+    fn test_process_log_file_gzip() {
+        use std::io::Write;
+
+        // No `.gz`, so it's only known by the magic bytes.
+        let path = std::env::temp_dir().join("dindexer-test-gzip.log");
+        let mut enc = flate2::write::GzEncoder::new(
+            std::fs::File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        );
+        enc.write_all(
+            b"2024-11-05T09:31:59Z SwapResult: {\"txid\":\"s1\",\"destination\":\"a\",\"result\":\"1@0\"}\n",
+        )
+        .unwrap();
+        enc.finish().unwrap();
+        let path = path.to_str().unwrap();
+        let matchers = TestArgs::parse_from(["test"]).log_match.matchers().unwrap();
+
+        let mut map = LogEntryMap::new();
+        process_log_file(path, &matchers, false, &mut map).unwrap();
+        assert_eq!(map.swap_count, 1);
+        assert!(map.get("s1").unwrap().unwrap().swap_data.is_some());
+
+        let mut map = LogEntryMap::new();
+        assert!(process_log_file(path, &matchers, true, &mut map).is_err());
+    }
+
+    #[test]
+    fn test_log_rotation_key() {
+        let mut paths = [
+            "logs/debug.log",
+            "logs/debug.log.1",
+            "logs/debug.log.10",
+            "logs/debug.log.2.gz",
+            "logs/debug.log.3",
+        ];
+        paths.sort_by(|a, b| log_rotation_key(a).cmp(&log_rotation_key(b)));
+        assert_eq!(
+            paths,
+            [
+                "logs/debug.log.10",
+                "logs/debug.log.3",
+                "logs/debug.log.2.gz",
+                "logs/debug.log.1",
+                "logs/debug.log",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_log_files_glob() {
+        let dir = std::env::temp_dir().join("dindexer-test-glob");
+        std::fs::create_dir_all(&dir).unwrap();
+        let icx = |address: &str| {
+            format!(
+                "2024-11-05T09:31:58Z ICX: {{\"order_tx\":\"o1\",\"offer_tx\":\"f1\",\"dfchtlc_tx\":\"h1\",\"claim_tx\":\"c1\",\"address\":\"{}\",\"amount\":\"0.1\"}}\n",
+                address
+            )
+        };
+        // logrotate's .2 is older than its .1.
+        std::fs::write(dir.join("debug.log.2"), icx("bc1qold")).unwrap();
+        std::fs::write(
+            dir.join("debug.log.1"),
+            icx("bc1qnew")
+                + "2024-11-05T09:31:59Z SwapResult: {\"txid\":\"s1\",\"destination\":\"a\",\"result\":\"1@0\"}\n",
+        )
+        .unwrap();
+        let pattern = dir.join("debug.log.*");
+        let pattern = pattern.to_str().unwrap();
+        let matchers = TestArgs::parse_from(["test"]).log_match.matchers().unwrap();
+
+        let mut map = LogEntryMap::new();
+        process_log_files(pattern, &matchers, false, &mut map).unwrap();
+        assert_eq!((map.icx_count, map.swap_count), (2, 1));
+        assert_eq!(map.dup_icx_count, 1);
+        let entry = map.get("c1").unwrap().unwrap();
+        assert_eq!(&*entry.icx_data.as_ref().unwrap().address, "bc1qnew");
+
+        let mut map = LogEntryMap::new();
+        assert!(process_log_files(pattern, &matchers, true, &mut map).is_err());
+        let missing = dir.join("none.log.*");
+        assert!(process_log_files(missing.to_str().unwrap(), &matchers, false, &mut map).is_err());
+    }
+}
@@ -1,42 +1,90 @@
 #![feature(error_generic_member_access)]
 
-mod args;
-mod cliindexer;
-mod db;
-mod dfiutils;
-mod dotreducer;
-mod gpath;
-mod graphbuild;
-mod graphdot;
-mod graphutils;
-mod graphwalk;
-mod icx1;
-mod icx2;
-mod icxseq;
-mod lang;
-mod logparse;
-mod models;
-mod spath;
-mod sqliteindex;
-
-use crate::lang::Result;
-use args::{get_args, verbosity_to_level, Cmd};
+use chain_analyzer::args::{
+    get_args, verbosity_to_level, Args, Cmd, ExportCmd, LogFormat, LogRotation,
+};
+use chain_analyzer::lang::Result;
+#[cfg(feature = "parquet")]
+use chain_analyzer::pqexport;
+use chain_analyzer::{
+    cliindexer, csvexport, dotreducer, gpath, graphbuild, graphdot, graphexport, graphwalk, icx1,
+    icx2, icxseq, indexer, logparse, prune, spath, sqliteindex,
+};
 use std::error::request_ref;
 use tracing::error;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
 
-fn main_fallible() -> Result<()> {
-    std::env::set_var("RUST_BACKTRACE", "1");
-    let args = get_args();
-    let emit_ansi = atty::is(atty::Stream::Stdout);
+/// The guard of the log file, if any, has to be held until the end for
+/// what's buffered to get flushed.
+fn init_logging(args: &Args) -> Result<Option<WorkerGuard>> {
+    // Keep stdout clean when the ndjson feed, an export or query results
+    // go there.
+    let log_to_stderr = match &args.command {
+        Cmd::CliIndex(a) => a.ndjson_out.as_deref() == Some("-"),
+        Cmd::Follow(a) => a.index.ndjson_out.as_deref() == Some("-"),
+        Cmd::Export(ExportCmd::Csv(a)) => a.out == "-",
+        Cmd::Export(ExportCmd::Graph(a)) => a.out == "-",
+        Cmd::Query(_) | Cmd::Status(_) | Cmd::ReprocessTx(_) => true,
+        _ => false,
+    };
+    let (console, emit_ansi) = match log_to_stderr {
+        true => (
+            BoxMakeWriter::new(std::io::stderr),
+            atty::is(atty::Stream::Stderr),
+        ),
+        false => (
+            BoxMakeWriter::new(std::io::stdout),
+            atty::is(atty::Stream::Stdout),
+        ),
+    };
+    let (writer, emit_ansi, log_file_guard) = match &args.log_file {
+        Some(path) => {
+            let (file, guard) =
+                tracing_appender::non_blocking(log_file_appender(path, args.log_rotation)?);
+            let writer = match args.no_console_log {
+                true => BoxMakeWriter::new(file),
+                false => BoxMakeWriter::new(console.and(file)),
+            };
+            // No escape codes in the file.
+            (writer, false, Some(guard))
+        }
+        None => (console, emit_ansi, None),
+    };
 
-    tracing_subscriber::fmt::fmt()
-        .with_max_level(verbosity_to_level(args.verbosity, Some(2)))
-        .with_ansi(emit_ansi)
-        .compact()
-        .init();
+    if !args.no_progress_bar
+        && args.quiet == 0
+        && args.log_file.is_none()
+        && args.log_format == LogFormat::Text
+        && atty::is(atty::Stream::Stderr)
+    {
+        indexer::enable_progress_bar();
+    }
 
+    let subscriber = tracing_subscriber::fmt::fmt()
+        .with_max_level(verbosity_to_level(args.verbosity, Some(2), args.quiet))
+        .with_writer(writer);
+    match args.log_format {
+        LogFormat::Text => subscriber.with_ansi(emit_ansi).compact().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+    Ok(log_file_guard)
+}
+
+fn main_fallible(args: &Args) -> Result<()> {
     match &args.command {
         Cmd::CliIndex(a) => cliindexer::run(a)?,
+        Cmd::Follow(a) => cliindexer::run_follow(a)?,
+        Cmd::Verify(a) => cliindexer::run_verify(a)?,
+        Cmd::Status(a) => cliindexer::run_status(a)?,
+        Cmd::Balances(a) => cliindexer::run_balances(a)?,
+        Cmd::Query(a) => cliindexer::run_query(a)?,
+        Cmd::ReprocessTx(a) => cliindexer::run_reprocess_tx(a)?,
+        Cmd::Export(ExportCmd::Csv(a)) => csvexport::run(a)?,
+        #[cfg(feature = "parquet")]
+        Cmd::Export(ExportCmd::Parquet(a)) => pqexport::run(a)?,
+        Cmd::Export(ExportCmd::Graph(a)) => graphexport::run(a)?,
         Cmd::DotReduce { in_file } => {
             dotreducer::run(in_file)?;
         }
@@ -48,19 +96,50 @@ fn main_fallible() -> Result<()> {
         Cmd::IcxAnalyze2(a) => icx2::run(a)?,
         Cmd::IcxSequence(a) => icxseq::run(a)?,
         Cmd::LogParseCheck(a) => logparse::run(a)?,
+        Cmd::Prune(a) => prune::run(a)?,
         Cmd::ShortestPath(a) => spath::run(a)?,
         Cmd::SqliteIndex(a) => sqliteindex::run(a)?,
     }
     Ok(())
 }
 
+fn log_file_appender(path: &str, rotation: LogRotation) -> Result<RollingFileAppender> {
+    let path = std::path::Path::new(path);
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("log file: not a file path: {}", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let rotation = match rotation {
+        LogRotation::Hourly => Rotation::HOURLY,
+        LogRotation::Daily => Rotation::DAILY,
+        LogRotation::Never => Rotation::NEVER,
+    };
+    Ok(RollingFileAppender::new(rotation, dir, name))
+}
+
 fn main() {
-    let res = main_fallible();
+    std::env::set_var("RUST_BACKTRACE", "1");
+    let args = get_args();
+    // Held past the error below, so it makes it to the log file too.
+    let log_file_guard = match init_logging(args) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let res = main_fallible(args);
     if let Err(e) = res {
-        error!("{e}");
+        error!(error = %e, "{e}");
         let bt = request_ref::<std::backtrace::Backtrace>(&e);
         if let Some(bt) = bt {
             error!("{bt}");
         }
+        // Held till here, `exit` doesn't run the drops.
+        drop(log_file_guard);
+        std::process::exit(1);
     }
 }
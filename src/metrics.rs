@@ -0,0 +1,188 @@
+//! Optional prometheus metrics, served over plain http on their own
+//! thread. Without the `metrics` feature, the recorders are no-ops and
+//! asking for the server is an error.
+
+use crate::lang::{Error, Result};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use crate::lang::Result;
+    use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntGauge, Registry};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, OnceLock};
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+    use tracing::{info, warn};
+
+    pub struct Metrics {
+        registry: Registry,
+        pub blocks_indexed: IntCounter,
+        pub txs_indexed: IntCounter,
+        pub rpc_errors: IntCounter,
+        pub kafka_errors: IntCounter,
+        pub current_height: IntGauge,
+        pub block_seconds: Histogram,
+    }
+
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+    pub fn get() -> Option<&'static Metrics> {
+        METRICS.get()
+    }
+
+    fn init() -> Result<&'static Metrics> {
+        if let Some(m) = METRICS.get() {
+            return Ok(m);
+        }
+        let registry = Registry::new();
+        let m = Metrics {
+            blocks_indexed: IntCounter::new("blocks_indexed_total", "Blocks indexed")?,
+            txs_indexed: IntCounter::new("txs_indexed_total", "Transactions indexed")?,
+            rpc_errors: IntCounter::new("rpc_errors_total", "Failed node calls, incl. retries")?,
+            kafka_errors: IntCounter::new("kafka_errors_total", "Txs that failed to publish")?,
+            current_height: IntGauge::new("current_height", "Last indexed height")?,
+            block_seconds: Histogram::with_opts(
+                HistogramOpts::new("block_processing_seconds", "Time to index a block")
+                    .buckets(vec![0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1., 5.]),
+            )?,
+            registry,
+        };
+        m.registry.register(Box::new(m.blocks_indexed.clone()))?;
+        m.registry.register(Box::new(m.txs_indexed.clone()))?;
+        m.registry.register(Box::new(m.rpc_errors.clone()))?;
+        m.registry.register(Box::new(m.kafka_errors.clone()))?;
+        m.registry.register(Box::new(m.current_height.clone()))?;
+        m.registry.register(Box::new(m.block_seconds.clone()))?;
+        Ok(METRICS.get_or_init(|| m))
+    }
+
+    pub struct Server {
+        stop: Arc<AtomicBool>,
+        handle: Option<JoinHandle<()>>,
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::Relaxed);
+            if let Some(h) = self.handle.take() {
+                let _ = h.join();
+            }
+        }
+    }
+
+    pub fn serve(addr: &str, quit: &Arc<AtomicBool>) -> Result<Server> {
+        let metrics = init()?;
+        let listener = TcpListener::bind(addr)?;
+        // Polled, so we notice quit without needing a connection to wake us.
+        listener.set_nonblocking(true)?;
+        info!("metrics: listening on http://{}/metrics", addr);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = Arc::clone(&stop);
+            let quit = Arc::clone(quit);
+            std::thread::Builder::new()
+                .name("metrics".to_owned())
+                .spawn(move || {
+                    while !stop.load(Ordering::Relaxed) && !quit.load(Ordering::Relaxed) {
+                        match listener.accept() {
+                            Ok((stream, _)) => {
+                                if let Err(e) = respond(stream, metrics) {
+                                    warn!("metrics: {}", e);
+                                }
+                            }
+                            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                                std::thread::sleep(Duration::from_millis(100));
+                            }
+                            Err(e) => warn!("metrics: accept: {}", e),
+                        }
+                    }
+                    info!("metrics: stopped");
+                })?
+        };
+
+        Ok(Server {
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    // Every request gets the metrics, there's nothing else to serve.
+    fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut req_line = String::new();
+        BufReader::new(&stream).read_line(&mut req_line)?;
+
+        let mut body = Vec::new();
+        let encoder = prometheus::TextEncoder::new();
+        encoder.encode(&metrics.registry.gather(), &mut body)?;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            encoder.format_type(),
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        Ok(())
+    }
+}
+
+/// Keeps the metrics server running. Dropping it stops the server.
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+pub struct MetricsServer {
+    #[cfg(feature = "metrics")]
+    _inner: imp::Server,
+}
+
+/// Start the metrics server if an address is given. It stops on its own
+/// on `quit`, or when the returned handle is dropped.
+pub fn serve(addr: Option<&str>, quit: &Arc<AtomicBool>) -> Result<Option<MetricsServer>> {
+    match addr {
+        Some(addr) => Ok(Some(serve_on(addr, quit)?)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn serve_on(addr: &str, quit: &Arc<AtomicBool>) -> Result<MetricsServer> {
+    Ok(MetricsServer {
+        _inner: imp::serve(addr, quit)?,
+    })
+}
+
+#[cfg(not(feature = "metrics"))]
+fn serve_on(_addr: &str, _quit: &Arc<AtomicBool>) -> Result<MetricsServer> {
+    Err("metrics support not enabled, rebuild with `--features metrics`".into())
+}
+
+pub fn observe_block(height: i64, tx_count: usize, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    if let Some(m) = imp::get() {
+        m.blocks_indexed.inc();
+        m.txs_indexed.inc_by(tx_count as u64);
+        m.current_height.set(height);
+        m.block_seconds.observe(elapsed.as_secs_f64());
+    }
+    #[cfg(not(feature = "metrics"))]
+    let _ = (height, tx_count, elapsed);
+}
+
+pub fn inc_rpc_errors(_e: &Error) {
+    #[cfg(feature = "metrics")]
+    if let Some(m) = imp::get() {
+        m.rpc_errors.inc();
+    }
+}
+
+pub fn inc_kafka_errors() {
+    #[cfg(feature = "metrics")]
+    if let Some(m) = imp::get() {
+        m.kafka_errors.inc();
+    }
+}
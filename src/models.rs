@@ -1,10 +1,15 @@
 #![allow(dead_code)]
 
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::sync::Arc;
 
-pub type TStr = Rc<str>;
+// Shared, so the txs of a block can be worked on in parallel.
+pub type TStr = Arc<str>;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -62,7 +67,9 @@ pub struct Transaction {
 pub struct VMInfo {
     pub vmtype: TStr,
     pub txtype: TStr,
-    pub msg: serde_json::Value,
+    /// Kept as the json text from the node, so the amounts in it don't
+    /// go through an f64 before they're parsed, see `deserialize_amount`.
+    pub msg: Box<serde_json::value::RawValue>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -160,6 +167,8 @@ pub enum TxType {
     ICXClaimDFCHTLC,
     ICXCloseOrder,
     ICXCloseOffer,
+    /// A txtype code without a dedicated variant, kept as is so it's
+    /// stored verbatim and can be tallied.
     Other(String),
 }
 
@@ -269,10 +278,87 @@ impl TxType {
             other => TxType::Other(other.to_owned()),
         }
     }
+
+    /// A dvm tx type by its txtype name from the node, case insensitive,
+    /// eg. `poolswap`. `unknown` is the txs whose type isn't known.
+    pub fn from_name(name: &str) -> Option<Self> {
+        const NAMES: &[&str] = &[
+            "CreateMasternode",
+            "ResignMasternode",
+            "PoolSwap",
+            "CompositeSwap",
+            "AddPoolLiquidity",
+            "RemovePoolLiquidity",
+            "UtxosToAccount",
+            "AccountToUtxos",
+            "AccountToAccount",
+            "WithdrawFromVault",
+            "SetOracleData",
+            "DepositToVault",
+            "PaybackLoan",
+            "TakeLoan",
+            "AutoAuth",
+            "Vault",
+            "AnyAccountsToAccounts",
+            "ICXCreateOrder",
+            "ICXMakeOffer",
+            "ICXSubmitDFCHTLC",
+            "ICXSubmitEXTHTLC",
+            "ICXClaimDFCHTLC",
+            "ICXCloseOrder",
+            "ICXCloseOffer",
+        ];
+        if name.eq_ignore_ascii_case("unknown") {
+            return Some(TxType::Unknown);
+        }
+        NAMES
+            .iter()
+            .find(|x| x.eq_ignore_ascii_case(name))
+            .map(|x| TxType::from(*x))
+    }
 }
 
 type TokenAmount = TStr;
 
+/// On chain amounts are fixed point with 8 decimals. Kept as a decimal
+/// so that stored amounts are exact, f64 drifts on large values.
+pub type Amount = bigdecimal::BigDecimal;
+pub const AMOUNT_SCALE: i64 = 8;
+
+pub fn parse_amount(s: &str) -> Result<Amount, bigdecimal::ParseBigDecimalError> {
+    let v = <Amount as std::str::FromStr>::from_str(s.trim())?;
+    Ok(v.with_scale_round(AMOUNT_SCALE, bigdecimal::RoundingMode::HalfEven))
+}
+
+/// The exact amount behind a coin value we only have as an f64, the
+/// same way `deserialize_amount` gets it.
+pub fn amount_from_f64(v: f64) -> Amount {
+    parse_amount(&v.to_string()).unwrap_or_default()
+}
+
+// Json numbers are taken as the text defid printed, an f64 only holds
+// about 15 significant digits. They're then pinned to 8 decimals.
+// Strings are accepted as well. The text is only there when parsing
+// from json text, from a `serde_json::Value` it's been through an f64.
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Amount, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let raw = Box::<serde_json::value::RawValue>::deserialize(deserializer)?;
+    let s = match serde_json::from_str::<String>(raw.get()) {
+        Ok(s) => s,
+        Err(_) => raw.get().to_owned(),
+    };
+    parse_amount(&s).map_err(|e| serde::de::Error::custom(format!("invalid amount: {}: {}", s, e)))
+}
+
+fn serialize_amount<S>(v: &Amount, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_str(&v.to_string())
+}
+
 // vm":{"vmtype":"dvm","txtype":"UtxosToAccount","msg":{"8RbpgySS2qkXQG2UosQCqADtS7zRAr8bx5":"60000.00000000@0"}}}
 pub type UtxosToAccountMsg = HashMap<TStr, TokenAmount>;
 
@@ -297,18 +383,78 @@ pub struct AccountToUtxosMsg {
     pub to: HashMap<TStr, TokenAmount>,
 }
 
+// "vm":{"vmtype":"dvm","txtype":"ICXMakeOffer","msg":{"orderTx":"8f17836797c93e13b80c36dbade8ac0e8b4b7a4a390aa6769a64705bd3683f07","amount":0.00015,"ownerAddress":"df1q...","receivePubkey":"03...","expiry":10}}
+// Only the linking txids are parsed out of the ICX messages.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct IcxMakeOfferMsg {
+    pub order_tx: TStr,
+}
+
+// "vm":{"vmtype":"dvm","txtype":"ICXSubmitDFCHTLC","msg":{"offerTx":"5a6b...","amount":1.2,"hash":"957f...","timeout":1440}}
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct IcxSubmitDfchtlcMsg {
+    pub offer_tx: TStr,
+}
+
+// "vm":{"vmtype":"dvm","txtype":"ICXClaimDFCHTLC","msg":{"dfchtlcTx":"b2c4...","seed":"f75a..."}}
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct IcxClaimDfchtlcMsg {
+    pub dfchtlc_tx: TStr,
+}
+
 // "vm":{"vmtype":"dvm","txtype":"PoolSwap","msg":{"fromAddress":"8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R","fromAmount":9.0,"fromToken":"0","maxPrice":0.00002531,"maxPriceHighPrecision":"0.00002531","toAddress":"8eG9Pe1wQnWZuXD5NRr3QaxDex9RJ99fd5","toToken":"2"}}}
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, PartialOrd)]
 #[serde(rename_all = "camelCase")]
 pub struct PoolSwapMsg {
     pub from_address: TStr,
     pub to_address: TStr,
-    pub from_amount: f64,
+    #[serde(
+        deserialize_with = "deserialize_amount",
+        serialize_with = "serialize_amount"
+    )]
+    pub from_amount: Amount,
     pub from_token: TStr,
     pub to_token: TStr,
+    // CompositeSwap only: the pool ids routed through, in order.
+    // defid emits these as `"compositeDex": "4/5/10"`.
+    #[serde(
+        default,
+        rename = "compositeDex",
+        deserialize_with = "deserialize_pool_path",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub pools: Vec<TStr>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+fn deserialize_pool_path<'de, D>(deserializer: D) -> Result<Vec<TStr>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    // Be lenient on the shape: "4/5/10", ["4", "5"] or [4, 5]
+    let v = serde_json::Value::deserialize(deserializer)?;
+    let pools = match v {
+        serde_json::Value::String(s) => s
+            .split('/')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(TStr::from)
+            .collect(),
+        serde_json::Value::Array(arr) => arr
+            .into_iter()
+            .map(|x| match x {
+                serde_json::Value::String(s) => TStr::from(s),
+                other => TStr::from(other.to_string()),
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(pools)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogIcxData {
     pub order_tx: TStr,
     pub offer_tx: TStr,
@@ -318,8 +464,19 @@ pub struct LogIcxData {
     pub amount: TStr,
 }
 
+impl LogIcxData {
+    pub fn tx_set(&self) -> IcxTxSet {
+        IcxTxSet {
+            order_tx: self.order_tx.clone(),
+            offer_tx: self.offer_tx.clone(),
+            dfchtlc_tx: self.dfchtlc_tx.clone(),
+            claim_tx: self.claim_tx.clone(),
+        }
+    }
+}
+
 //  {"calc_type":"CICXMakeOfferMessage","calc_tx":"8f17836797c93e13b80c36dbade8ac0e8b4b7a4a390aa6769a64705bd3683f07","calc_start_amount":"0.00015000","calc_fee_per_btc":"0.00300000","calc_pool_dfi_per_btc":"12294.82047387","calc_taker_fee_in_btc":"0.00000045","calc_taker_fee_in_dfi":"0.00553266"}
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogIcxCalcData {
     pub calc_type: TStr,
     pub calc_tx: TStr,
@@ -331,14 +488,14 @@ pub struct LogIcxCalcData {
 }
 
 // 2024-11-05T09:31:59Z SwapResult: {"height":894004,"txid":"99c64a70b2632a30d4ce20d9bcce3962945c8a6afd8fa547bf26a290b268bcde","destination":"dbdztbUBcNhDzG2NCiCVKXBZgQXybi8xvj","result":"0.02239840@1"}
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LogSwapData {
     pub txid: TStr,
     pub destination: TStr,
     pub result: TStr,
 }
 
-#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct IcxTxSet {
     pub order_tx: TStr,
     pub offer_tx: TStr,
@@ -346,17 +503,112 @@ pub struct IcxTxSet {
     pub claim_tx: TStr,
 }
 
+/// Which of the txs of an ICX swap a tx is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IcxRole {
+    Order,
+    Offer,
+    Dfchtlc,
+    Claim,
+}
+
+/// A swap a tx is part of, and its role in it. An order can be filled by
+/// more than one swap, the other txs are only ever in the one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IcxLink {
+    pub role: IcxRole,
+    #[serde(flatten)]
+    pub set: IcxTxSet,
+}
+
+impl IcxTxSet {
+    /// The link for each of the txs of the swap, keyed by their txid.
+    pub fn links(&self) -> [(TStr, IcxLink); 4] {
+        [
+            (self.order_tx.clone(), IcxRole::Order),
+            (self.offer_tx.clone(), IcxRole::Offer),
+            (self.dfchtlc_tx.clone(), IcxRole::Dfchtlc),
+            (self.claim_tx.clone(), IcxRole::Claim),
+        ]
+        .map(|(txid, role)| {
+            let set = self.clone();
+            (txid, IcxLink { role, set })
+        })
+    }
+}
+
+/// The kinds of defid log lines we take data from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogLineKind {
+    Icx,
+    IcxCalc,
+    Swap,
+}
+
+pub enum LogLine {
+    Icx(LogIcxData),
+    IcxCalc(LogIcxCalcData),
+    Swap(LogSwapData),
+}
+
+impl LogLine {
+    /// The json from the first `{` of the line, as `kind`. `None` when
+    /// it's not in that shape.
+    pub fn parse(kind: LogLineKind, line: &str) -> Option<Self> {
+        let json = &line[line.find('{')?..];
+        let res = match kind {
+            LogLineKind::Icx => LogLine::Icx(serde_json::from_str(json).ok()?),
+            LogLineKind::IcxCalc => LogLine::IcxCalc(serde_json::from_str(json).ok()?),
+            LogLineKind::Swap => LogLine::Swap(serde_json::from_str(json).ok()?),
+        };
+        Some(res)
+    }
+
+    /// The txs the line has data for.
+    pub fn txids(&self) -> Vec<TStr> {
+        match self {
+            LogLine::Icx(x) => x.tx_set().links().map(|(txid, _)| txid).to_vec(),
+            LogLine::IcxCalc(x) => vec![x.calc_tx.clone()],
+            LogLine::Swap(x) => vec![x.txid.clone()],
+        }
+    }
+}
+
+/// Where the lines for each tx are in an uncompressed log file, so they
+/// can be read when the tx comes up instead of being held in memory.
+pub struct LogIndex {
+    file: RefCell<BufReader<File>>,
+    offsets: HashMap<TStr, Vec<(u64, LogLineKind)>>,
+}
+
+impl LogIndex {
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            file: RefCell::new(BufReader::new(File::open(path)?)),
+            offsets: HashMap::new(),
+        })
+    }
+}
+
 pub struct LogEntryMap {
     pub data: HashMap<TStr, LogEntry>,
     pub icx_count: usize,
     pub icx_calc_count: usize,
     pub swap_count: usize,
+    /// ICX lines for a claim that already had one, the latest is kept.
+    pub dup_icx_count: usize,
+    /// When set, lines are only indexed by offset and `data` stays empty.
+    pub index: Option<LogIndex>,
 }
 
+#[derive(Clone)]
 pub struct LogEntry {
     pub icx_data: Option<LogIcxData>,
     pub icx_calc_data: Option<LogIcxCalcData>,
     pub swap_data: Option<LogSwapData>,
+    /// The ICX swaps the tx is part of, from the `icx_data` of their claims.
+    pub icx_links: Vec<IcxLink>,
 }
 
 impl LogEntryMap {
@@ -366,6 +618,129 @@ impl LogEntryMap {
             icx_count: 0,
             icx_calc_count: 0,
             swap_count: 0,
+            dup_icx_count: 0,
+            index: None,
+        }
+    }
+
+    /// Lines only go into `data` without an `index`.
+    pub fn add(&mut self, offset: u64, kind: LogLineKind, line: LogLine) {
+        match &line {
+            LogLine::Icx(_) => self.icx_count += 1,
+            LogLine::IcxCalc(_) => self.icx_calc_count += 1,
+            LogLine::Swap(_) => self.swap_count += 1,
+        }
+        if let Some(index) = &mut self.index {
+            for txid in line.txids() {
+                index.offsets.entry(txid).or_default().push((offset, kind));
+            }
+            return;
+        }
+        match line {
+            LogLine::Icx(data) => {
+                self.add_icx_links(&data.tx_set());
+                let entry = self
+                    .data
+                    .entry(data.claim_tx.clone())
+                    .or_insert_with(LogEntry::new);
+                if entry.icx_data.is_some() {
+                    self.dup_icx_count += 1;
+                }
+                entry.icx_data = Some(data);
+            }
+            LogLine::IcxCalc(data) => {
+                let entry = self
+                    .data
+                    .entry(data.calc_tx.clone())
+                    .or_insert_with(LogEntry::new);
+                entry.icx_calc_data = Some(data);
+            }
+            LogLine::Swap(data) => {
+                let entry = self
+                    .data
+                    .entry(data.txid.clone())
+                    .or_insert_with(LogEntry::new);
+                entry.swap_data = Some(data);
+            }
+        }
+    }
+
+    /// Number of txs there's log data for.
+    pub fn tx_count(&self) -> usize {
+        match &self.index {
+            Some(index) => index.offsets.len(),
+            None => self.data.len(),
+        }
+    }
+
+    /// The log data for the tx. With an `index`, it's read back from the
+    /// file.
+    pub fn get(&self, txid: &str) -> std::io::Result<Option<Cow<'_, LogEntry>>> {
+        let Some(index) = &self.index else {
+            return Ok(self.data.get(txid).map(Cow::Borrowed));
+        };
+        let Some(lines) = index.offsets.get(txid) else {
+            return Ok(None);
+        };
+        let mut entries = LogEntryMap::new();
+        let mut file = index.file.borrow_mut();
+        let mut buf = String::new();
+        for (offset, kind) in lines {
+            file.seek(SeekFrom::Start(*offset))?;
+            buf.clear();
+            file.read_line(&mut buf)?;
+            if let Some(line) = LogLine::parse(*kind, &buf) {
+                entries.add(*offset, *kind, line);
+            }
+        }
+        Ok(entries.data.remove(txid).map(Cow::Owned))
+    }
+
+    /// The ICX data of every swap, the latest line for each claim, ordered
+    /// by claim. With an `index`, it's read back from the file.
+    pub fn icx_data(&self) -> std::io::Result<Vec<LogIcxData>> {
+        let mut res: HashMap<TStr, LogIcxData> = HashMap::new();
+        match &self.index {
+            None => {
+                let icx = self.data.values().filter_map(|x| x.icx_data.as_ref());
+                res.extend(icx.map(|x| (x.claim_tx.clone(), x.clone())));
+            }
+            Some(index) => {
+                // Each line is under the 4 txs of the swap.
+                let mut offsets: Vec<u64> = index
+                    .offsets
+                    .values()
+                    .flatten()
+                    .filter(|(_, kind)| *kind == LogLineKind::Icx)
+                    .map(|(offset, _)| *offset)
+                    .collect();
+                offsets.sort_unstable();
+                offsets.dedup();
+                let mut file = index.file.borrow_mut();
+                let mut buf = String::new();
+                for offset in offsets {
+                    file.seek(SeekFrom::Start(offset))?;
+                    buf.clear();
+                    file.read_line(&mut buf)?;
+                    if let Some(LogLine::Icx(x)) = LogLine::parse(LogLineKind::Icx, &buf) {
+                        res.insert(x.claim_tx.clone(), x);
+                    }
+                }
+            }
+        }
+        let mut res: Vec<_> = res.into_values().collect();
+        res.sort_by(|a, b| a.claim_tx.cmp(&b.claim_tx));
+        Ok(res)
+    }
+
+    /// Link every tx of the swap to the others, not just the claim it's
+    /// logged for. Swaps seen again aren't added twice.
+    pub fn add_icx_links(&mut self, set: &IcxTxSet) {
+        for (txid, link) in set.links() {
+            let entry = self.data.entry(txid).or_insert_with(LogEntry::new);
+            if !entry.icx_links.contains(&link) {
+                entry.icx_links.push(link);
+            }
         }
     }
 }
@@ -376,6 +751,35 @@ impl LogEntry {
             icx_data: None,
             icx_calc_data: None,
             swap_data: None,
+            icx_links: Vec::new(),
         }
     }
 }
+
+#[test]
+fn test_pool_swap_amount_is_exact() {
+    let msg: PoolSwapMsg = serde_json::from_str(
+        r#"{"fromAddress":"a","toAddress":"b","fromAmount":92233720.12345678,"fromToken":"0","toToken":"2"}"#,
+    )
+    .unwrap();
+    assert_eq!(msg.from_amount.to_string(), "92233720.12345678");
+
+    let msg: PoolSwapMsg = serde_json::from_str(
+        r#"{"fromAddress":"a","toAddress":"b","fromAmount":0.00001,"fromToken":"0","toToken":"2"}"#,
+    )
+    .unwrap();
+    assert_eq!(msg.from_amount.to_string(), "0.00001000");
+
+    // 17 significant digits, past what an f64 holds, by way of the tx.
+    let vm: VMInfo = serde_json::from_str(
+        r#"{"vmtype":"dvm","txtype":"PoolSwap","msg":{"fromAddress":"a","toAddress":"b","fromAmount":123456789.12345678,"fromToken":"0","toToken":"2"}}"#,
+    )
+    .unwrap();
+    let msg: PoolSwapMsg = serde_json::from_str(vm.msg.get()).unwrap();
+    assert_eq!(msg.from_amount.to_string(), "123456789.12345678");
+    let msg: PoolSwapMsg = serde_json::from_str(
+        r#"{"fromAddress":"a","toAddress":"b","fromAmount":"123456789.12345678","fromToken":"0","toToken":"2"}"#,
+    )
+    .unwrap();
+    assert_eq!(msg.from_amount.to_string(), "123456789.12345678");
+}
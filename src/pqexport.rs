@@ -0,0 +1,253 @@
+use crate::cliindexer::DbArgs;
+use crate::db::{is_postgres_url, sqlite_json_text, SqliteBlockStore};
+use crate::lang::Result;
+use crate::models::{parse_amount, AMOUNT_SCALE};
+use arrow_array::builder::{ArrayBuilder, Decimal128Builder, Int64Builder, StringBuilder};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{DataType, Field, Schema};
+use bigdecimal::ToPrimitive;
+use clap::Parser;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use rusqlite::types::ValueRef;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct ParquetExportArgs {
+    #[command(flatten)]
+    pub db: DbArgs,
+    /// Output dir. Each table goes into a sub dir of the same name,
+    /// partitioned by height as `height_from=<n>/data.parquet`
+    #[arg(short = 'o', long, default_value = "data/parquet")]
+    pub out_dir: String,
+    #[arg(short = 's', long, default_value_t = 0)]
+    pub start_height: i64,
+    #[arg(short = 'e', long, default_value_t = i64::MAX)]
+    pub end_height: i64,
+    /// Number of heights per partition
+    #[arg(long, default_value_t = 100_000)]
+    pub partition_size: i64,
+    #[arg(long, default_value_t = 64 * 1024)]
+    pub row_group_size: usize,
+}
+
+// Amount columns land as decimals, everything else that's not an integer
+// is kept as a string, json included.
+const DECIMAL_COLS: [&str; 2] = ["swap_amt", "icx_btc_exp_amt"];
+const INT_COLS: [&str; 1] = ["height"];
+// Enough for the 21B max supply at 8 decimals.
+const DECIMAL_PRECISION: u8 = 20;
+
+pub fn run(args: &ParquetExportArgs) -> Result<()> {
+    debug!("{:?}", args);
+
+    let quit = Arc::new(AtomicBool::new(false));
+    crate::lang::on_signal(signal_hook::consts::SIGINT, &quit)?;
+
+    if is_postgres_url(args.db.db_url()) {
+        return Err("export parquet: only sqlite dbs are supported".into());
+    }
+    let sql_store = SqliteBlockStore::new_v2_with_opts(args.db.sqlite_db_path()?, &args.db.sqlite)?;
+    for table in ["blocks", "txs"] {
+        if quit.load(Ordering::Relaxed) {
+            break;
+        }
+        export_table(&sql_store, table, args, &quit)?;
+    }
+
+    info!("done");
+    Ok(())
+}
+
+fn export_table(
+    sql_store: &SqliteBlockStore,
+    table: &str,
+    args: &ParquetExportArgs,
+    quit: &AtomicBool,
+) -> Result<()> {
+    let partition_size = std::cmp::max(args.partition_size, 1);
+    let row_group_size = std::cmp::max(args.row_group_size, 1);
+
+    let q = format!(
+        "SELECT * FROM {} WHERE height BETWEEN ?1 AND ?2 ORDER BY height",
+        table
+    );
+    let mut stmt = sql_store.conn.prepare(&q)?;
+    let cols = stmt
+        .column_names()
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<_>>();
+    let height_idx = cols
+        .iter()
+        .position(|c| c == "height")
+        .ok_or("height column missing")?;
+    let schema = Arc::new(Schema::new(
+        cols.iter()
+            .map(|c| Field::new(c, column_type(c), true))
+            .collect::<Vec<_>>(),
+    ));
+
+    let mut builders = cols
+        .iter()
+        .map(|c| ColumnBuilder::new(c))
+        .collect::<Vec<_>>();
+    let mut writer: Option<ArrowWriter<std::fs::File>> = None;
+    let mut partition = None;
+    let mut rows_total = 0usize;
+
+    let mut rows = stmt.query(rusqlite::params![args.start_height, args.end_height])?;
+    while let Some(row) = rows.next()? {
+        if quit.load(Ordering::Relaxed) {
+            info!("int: early exit");
+            break;
+        }
+
+        let height = row.get::<_, i64>(height_idx)?;
+        let p = height / partition_size * partition_size;
+        if partition != Some(p) {
+            if let Some(w) = writer.take() {
+                close_partition(w, &schema, &mut builders)?;
+            }
+            let dir = Path::new(&args.out_dir)
+                .join(table)
+                .join(format!("height_from={}", p));
+            writer = Some(open_partition(&dir, &schema, row_group_size)?);
+            partition = Some(p);
+        }
+
+        for (i, b) in builders.iter_mut().enumerate() {
+            b.append(row.get_ref(i)?)?;
+        }
+        rows_total += 1;
+
+        if builders[0].len() >= row_group_size {
+            let w = writer.as_mut().ok_or("no open partition")?;
+            w.write(&finish_batch(&schema, &mut builders)?)?;
+        }
+    }
+
+    if let Some(w) = writer.take() {
+        close_partition(w, &schema, &mut builders)?;
+    }
+
+    info!("{}: exported {} rows", table, rows_total);
+    Ok(())
+}
+
+fn column_type(col: &str) -> DataType {
+    if INT_COLS.contains(&col) {
+        DataType::Int64
+    } else if DECIMAL_COLS.contains(&col) {
+        DataType::Decimal128(DECIMAL_PRECISION, AMOUNT_SCALE as i8)
+    } else {
+        DataType::Utf8
+    }
+}
+
+fn open_partition(
+    dir: &Path,
+    schema: &Arc<Schema>,
+    row_group_size: usize,
+) -> Result<ArrowWriter<std::fs::File>> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join("data.parquet");
+    info!("writing: {}", path.display());
+    let props = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .set_max_row_group_size(row_group_size)
+        .build();
+    let file = std::fs::File::create(path)?;
+    Ok(ArrowWriter::try_new(file, Arc::clone(schema), Some(props))?)
+}
+
+// Flush what's pending and write out the footer.
+fn close_partition(
+    mut w: ArrowWriter<std::fs::File>,
+    schema: &Arc<Schema>,
+    builders: &mut [ColumnBuilder],
+) -> Result<()> {
+    if builders.first().map(|b| b.len()).unwrap_or(0) > 0 {
+        w.write(&finish_batch(schema, builders)?)?;
+    }
+    w.close()?;
+    Ok(())
+}
+
+fn finish_batch(schema: &Arc<Schema>, builders: &mut [ColumnBuilder]) -> Result<RecordBatch> {
+    let arrays = builders
+        .iter_mut()
+        .map(|b| b.finish())
+        .collect::<Vec<ArrayRef>>();
+    Ok(RecordBatch::try_new(Arc::clone(schema), arrays)?)
+}
+
+enum ColumnBuilder {
+    Int(Int64Builder),
+    Decimal(Decimal128Builder),
+    Str(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(col: &str) -> Self {
+        match column_type(col) {
+            DataType::Int64 => ColumnBuilder::Int(Int64Builder::new()),
+            DataType::Decimal128(p, s) => ColumnBuilder::Decimal(
+                Decimal128Builder::new()
+                    .with_precision_and_scale(p, s)
+                    .expect("valid decimal precision"),
+            ),
+            _ => ColumnBuilder::Str(StringBuilder::new()),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ColumnBuilder::Int(b) => b.len(),
+            ColumnBuilder::Decimal(b) => b.len(),
+            ColumnBuilder::Str(b) => b.len(),
+        }
+    }
+
+    fn append(&mut self, v: ValueRef) -> Result<()> {
+        match self {
+            ColumnBuilder::Int(b) => match v {
+                ValueRef::Integer(i) => b.append_value(i),
+                _ => b.append_null(),
+            },
+            ColumnBuilder::Decimal(b) => {
+                let s = v.as_str().unwrap_or_default();
+                if s.is_empty() {
+                    b.append_null();
+                } else {
+                    let amt = parse_amount(s).map_err(|e| format!("amount: {}: {}", s, e))?;
+                    let (mantissa, _) = amt.as_bigint_and_exponent();
+                    let mantissa = mantissa
+                        .to_i128()
+                        .ok_or_else(|| format!("amount out of range: {}", s))?;
+                    b.append_value(mantissa);
+                }
+            }
+            ColumnBuilder::Str(b) => match v {
+                ValueRef::Null => b.append_null(),
+                ValueRef::Text(t) => b.append_value(String::from_utf8_lossy(t)),
+                ValueRef::Blob(_) => b.append_value(sqlite_json_text(v)?),
+                ValueRef::Integer(i) => b.append_value(i.to_string()),
+                ValueRef::Real(f) => b.append_value(f.to_string()),
+            },
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            ColumnBuilder::Int(b) => Arc::new(b.finish()) as ArrayRef,
+            ColumnBuilder::Decimal(b) => Arc::new(b.finish()) as ArrayRef,
+            ColumnBuilder::Str(b) => Arc::new(b.finish()) as ArrayRef,
+        }
+    }
+}
@@ -0,0 +1,41 @@
+use crate::cliindexer::DbArgs;
+use crate::db::{is_postgres_url, BlockStore, SqliteBlockStore};
+use crate::lang::Result;
+use clap::Parser;
+use tracing::{debug, info};
+
+#[derive(Parser, Debug)]
+pub struct PruneArgs {
+    #[command(flatten)]
+    pub db: DbArgs,
+    #[arg(long)]
+    pub prune_from: i64,
+    #[arg(long)]
+    pub prune_to: i64,
+}
+
+pub fn run(args: &PruneArgs) -> Result<()> {
+    debug!("{:?}", args);
+    if args.prune_from > args.prune_to {
+        return Err(format!(
+            "prune: from {} is past to {}",
+            args.prune_from, args.prune_to
+        )
+        .into());
+    }
+
+    if is_postgres_url(args.db.db_url()) {
+        return Err("prune: only sqlite dbs are supported".into());
+    }
+    let sql_store = SqliteBlockStore::new_v2_with_opts(args.db.sqlite_db_path()?, &args.db.sqlite)?;
+    // All or nothing, so a failure doesn't leave a half pruned range.
+    sql_store.begin()?;
+    let removed = sql_store.delete_range(args.prune_from, args.prune_to)?;
+    sql_store.commit()?;
+
+    for (table, n) in removed {
+        info!("prune: {}: {} rows removed", table, n);
+    }
+    info!("done");
+    Ok(())
+}
@@ -0,0 +1,79 @@
+//! Typed reads of a sqlite db built by the indexer, for programs that
+//! use the crate as a library, so they don't need to know the column
+//! layout. The txs come back as `TxRow`s, with the json columns parsed.
+
+pub use crate::db::{SqliteBlockStore, TxRow};
+pub use crate::models::{Block, Transaction};
+
+use crate::db::BlockStore;
+use crate::lang::Result;
+
+/// Open the sqlite db at `path`. It's migrated to the current schema if
+/// it's from an older build, and refused if from a newer one.
+pub fn open(path: &str) -> Result<SqliteBlockStore> {
+    SqliteBlockStore::new_v2(Some(path))
+}
+
+/// The block at `height`, with its txs as the node returned them.
+pub fn get_block(store: &SqliteBlockStore, height: i64) -> Result<Option<Block>> {
+    store.get_block_from_height(height)
+}
+
+pub fn get_tx(store: &SqliteBlockStore, txid: &str) -> Result<Option<TxRow>> {
+    store.get_tx_data(txid)
+}
+
+/// Calls `f` with each of the txs from `from` to `to`, inclusive, in
+/// height order. Stops at the first error `f` returns.
+pub fn iter_txs_by_height_range<F>(store: &SqliteBlockStore, from: i64, to: i64, f: F) -> Result<()>
+where
+    F: FnMut(Result<TxRow>) -> Result<()>,
+{
+    let range = format!(
+        "WHERE height >= {} AND height <= {} ORDER BY height, txid",
+        from, to
+    );
+    store.iter_txs(Some(&range), f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::TxRecord;
+
+    #[test]
+    fn test_query_reads() {
+        let store = SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let block = include_str!("../tests/fixtures/block_coinbase.json");
+        store.put_block(894001, "h1", block).unwrap();
+        let txs = [("t3", 3), ("t1", 1), ("t2", 2), ("t0", 2)];
+        for (txid, height) in txs {
+            let tx = TxRecord {
+                txid,
+                height,
+                tx_type: "utxo",
+                tx_out: r#"{"a1":1.5}"#,
+                vout_count: 1,
+                ..Default::default()
+            };
+            store.put_tx(&tx).unwrap();
+        }
+
+        let block = get_block(&store, 894001).unwrap().unwrap();
+        assert_eq!(block.height, 894001);
+        assert!(get_block(&store, 2).unwrap().is_none());
+        let tx = get_tx(&store, "t1").unwrap().unwrap();
+        assert_eq!(tx.height, 1);
+        assert_eq!(tx.tx_out["a1"], 1.5);
+        assert_eq!(tx.vout_count, 1);
+        assert!(get_tx(&store, "t4").unwrap().is_none());
+
+        let mut txids = Vec::new();
+        iter_txs_by_height_range(&store, 2, 3, |tx| {
+            txids.push(tx?.txid);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(txids, vec!["t0", "t2", "t3"]);
+    }
+}
@@ -49,7 +49,7 @@ pub fn run(args: &ShortestPathArgs) -> Result<()> {
     debug!("args: {:?}", args);
 
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+    lang::on_signal(signal_hook::consts::SIGINT, &quit)?;
 
     let (src_addrs, dest_addrs, ignore_addrs) = (
         process_list_args_with_file_paths(&args.src)?,
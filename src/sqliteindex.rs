@@ -1,24 +1,19 @@
 use crate::db;
-use crate::dfiutils;
+use crate::dfiutils::{Network, TokenMap, UtxoCache};
+use crate::indexer::{
+    index_block, load_address_filter, record_run_end, record_run_start, IndexCtx, Progress,
+};
 use crate::lang;
-use crate::logparse::process_log_file;
-use crate::models;
-use crate::models::LogEntryMap;
+use crate::logparse::{process_log_files, LogMatchArgs};
+use crate::models::{Block, LogEntryMap};
 use anyhow::Context;
 use clap::Parser;
 use db::{
-    sqlite_begin_tx, sqlite_commit_and_begin_tx, sqlite_commit_tx, sqlite_create_index_factory_v2,
-    sqlite_get_stmts_v2, SqliteBlockStore,
+    sqlite_create_index_factory_v2, sqlite_index_exists, sqlite_json_text, sqlite_optimize,
+    BlockStore, SqliteBlockStore, SqliteOpts,
 };
-use dfiutils::{extract_all_dfi_addresses, token_id_to_symbol_maybe};
-use lang::OptionExt;
-use lang::Result;
-use models::{Block, IcxTxSet, TxType};
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::rc::Rc;
-use tracing::debug;
-use tracing::info;
+use lang::{Error, Result};
+use tracing::{debug, error, info, warn};
 
 #[derive(Parser, Debug)]
 pub struct SqliteIndexArgs {
@@ -26,23 +21,68 @@ pub struct SqliteIndexArgs {
     pub sqlite_src_path: String,
     #[arg(long, default_value = "data/index2.sqlite")]
     pub sqlite_dest_path: String,
-    // The path to the debug.log file from defid.
-    // This can be both gzipped or raw file. If the file is gzipped
-    // it will automatically be decompressed on the fly.
+    /// The path to the debug.log file from defid.
+    /// This can be both gzipped or raw file. If the file is gzipped, by
+    /// its extension or magic bytes, it will automatically be decompressed
+    /// on the fly. A glob, eg. `logs/debug.log*`, takes all the files it
+    /// matches, oldest first by their logrotate suffix: `debug.log.2.gz`,
+    /// `debug.log.1`, then `debug.log`. Later files win for the txs
+    /// logged again
     #[arg(long, default_value = "data/debug.log.gz")]
     pub defid_log_path: String,
-    #[arg(long, default_value = "ICX:")]
-    pub log_icx_matcher: String,
-    #[arg(long, default_value = "ICXCalc:")]
-    pub log_icx_calc_matcher: String,
-    #[arg(long, default_value = "SwapResult:")]
-    pub log_swap_matcher: String,
+    /// Read the log lines for each tx from the file as it's indexed,
+    /// instead of holding them all in memory. Needs an uncompressed log.
+    #[arg(long)]
+    pub defid_log_lazy: bool,
+    #[command(flatten)]
+    pub log_match: LogMatchArgs,
     #[arg(short = 's', long, default_value_t = 0)]
     pub start_height: i64,
     #[arg(short = 'e', long, default_value_t = 2_000_000)]
     pub end_height: i64,
+    /// Network the source db was indexed from. Picks the address
+    /// formats matched in dvm messages
+    #[arg(long, value_enum, default_value_t = Network::Mainnet)]
+    pub network: Network,
     #[arg(long, default_value_t = true)]
     pub enable_graph_table: bool,
+    /// Store a row per address each tx touches in the address_tx table,
+    /// for fast address lookups. Without it, lookups scan the txs
+    #[arg(long)]
+    pub enable_address_table: bool,
+    /// Keep the outs to an address in the utxos table, see `cindex`
+    #[arg(long)]
+    pub track_utxos: bool,
+    /// Only index txs touching one of the addresses in this file, one
+    /// per line. Blocks are still all stored
+    #[arg(long)]
+    pub address_filter: Option<String>,
+    /// Max number of recent outputs kept in memory, so spends close to
+    /// them don't need a db read. 0 disables the cache
+    #[arg(long, default_value_t = 1_000_000)]
+    pub utxo_cache_size: usize,
+    /// Commit and log progress every n blocks. 0 commits only at the end.
+    /// Outside of sqlite WAL, readers only get in on the commits
+    #[arg(long, default_value_t = 10000)]
+    pub commit_interval: i64,
+    /// Drop the indexes before indexing and rebuild them at the end.
+    /// Always done when the db is empty, otherwise existing indexes are
+    /// kept and only the missing ones are created
+    #[arg(long)]
+    pub drop_indexes: bool,
+    /// Run ANALYZE once indexing is done and the indexes are in place
+    #[arg(long)]
+    pub optimize: bool,
+    /// Also VACUUM the db with `--optimize`. Rewrites the whole file
+    #[arg(long, requires = "optimize")]
+    pub vacuum: bool,
+    /// Log blocks that fail to parse or index and carry on with the next
+    /// height, instead of stopping. Whatever got written of them is
+    /// removed. The count is reported at the end
+    #[arg(long)]
+    pub skip_bad_blocks: bool,
+    #[command(flatten)]
+    pub sqlite: SqliteOpts,
 }
 
 pub fn run(args: &SqliteIndexArgs) -> Result<()> {
@@ -61,22 +101,29 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
     let enable_addr_graph = args.enable_graph_table;
     let start_height = args.start_height;
     let end_height = args.end_height;
+    let commit_interval = args.commit_interval;
 
-    info!("{:?}", args);
+    debug!("{:?}", args);
+    if start_height > end_height {
+        return Err(format!(
+            "start height {} is past the end height {}",
+            start_height, end_height
+        )
+        .into());
+    }
 
     let quit = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
-    signal_hook::flag::register(signal_hook::consts::SIGINT, std::sync::Arc::clone(&quit))?;
+    lang::on_signal(signal_hook::consts::SIGINT, &quit)?;
 
     let mut log_entry_map = LogEntryMap::new();
 
     if let Some(defid_log_path) = defid_log_path {
         info!("ingesting log file: {}", defid_log_path);
 
-        process_log_file(
+        process_log_files(
             defid_log_path,
-            args.log_icx_matcher.as_str(),
-            args.log_icx_calc_matcher.as_str(),
-            args.log_swap_matcher.as_str(),
+            &args.log_match.matchers()?,
+            args.defid_log_lazy,
             &mut log_entry_map,
         )?;
 
@@ -86,7 +133,7 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
             \tTotal ICX entries:      {}\n\
             \tTotal ICX calc entries: {}\n\
             \tTotal Swap entries:     {}",
-            log_entry_map.data.len(),
+            log_entry_map.tx_count(),
             log_entry_map.icx_count,
             log_entry_map.icx_calc_count,
             log_entry_map.swap_count,
@@ -94,21 +141,61 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
     }
 
     let sql_store = SqliteBlockStore::new_v2(db_path_src)?;
-    let sql_store_dest = SqliteBlockStore::new_v2(db_path_dest)?;
+    let sql_store_dest = SqliteBlockStore::new_v2_with_opts(db_path_dest, &args.sqlite)?;
+    args.sqlite.warn_reader_lockout(commit_interval);
 
     let sconn = &sql_store_dest.conn;
-    for (name, _) in sqlite_create_index_factory_v2(sconn) {
-        if quit.load(std::sync::atomic::Ordering::Relaxed) {
-            info!("int: early exit indexes");
-            break;
+    // Inserting into a fresh db is a lot faster without the indexes,
+    // on incremental runs it's cheaper to keep them.
+    let drop_indexes = args.drop_indexes || sql_store_dest.get_max_height()?.is_none();
+    if drop_indexes {
+        for (name, _) in sqlite_create_index_factory_v2(sconn) {
+            if quit.load(std::sync::atomic::Ordering::Relaxed) {
+                info!("int: early exit indexes");
+                break;
+            }
+            info!("drop index: {}..", name);
+            let q = format!("DROP INDEX IF EXISTS {}", name);
+            sconn.execute(&q, [])?;
         }
-        info!("drop index: {}..", name);
-        let q = format!("DROP INDEX IF EXISTS {}", name);
-        sconn.execute(&q, [])?;
     }
 
-    let mut stmts = sqlite_get_stmts_v2(sconn)?;
-    sqlite_begin_tx(sconn)?;
+    // No node to ask here, so use what the source db has cached.
+    let symbols = sql_store.get_token_symbols()?;
+    sql_store_dest.put_token_symbols(&symbols)?;
+    let tokens = TokenMap::new(symbols, args.network);
+    let address_filter = match &args.address_filter {
+        Some(path) => Some(load_address_filter(path)?),
+        None => None,
+    };
+    let ctx = IndexCtx {
+        network: args.network,
+        log_entry_map: &log_entry_map,
+        tokens: &tokens,
+        enable_addr_graph,
+        enable_address_table: args.enable_address_table,
+        prevouts: None,
+        address_filter: address_filter.as_ref(),
+        unknown_tx_types: Default::default(),
+        tx_type_tally: Default::default(),
+        utxo_cache: UtxoCache::new(args.utxo_cache_size),
+        blocks_only: false,
+        track_utxos: args.track_utxos,
+        raw_tx_json: false,
+        on_dup_txid: Default::default(),
+        on_bad_dvm_msg: Default::default(),
+        tx_types: Default::default(),
+    };
+
+    record_run_start(
+        &sql_store_dest,
+        args.network,
+        args.enable_graph_table,
+        args.enable_address_table,
+    )?;
+    sql_store_dest.begin()?;
+    let mut progress = Progress::new(end_height);
+    let mut skipped = 0;
 
     let res = sql_store.iter_blocks_raw(
         Some(&format!(
@@ -122,168 +209,35 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
             }
             let row = r?;
             let height = row.get_ref(0)?.as_i64().context("height str")?;
-            let hash = row.get_ref(1)?.as_str().context("hash str")?;
-            let block_json = row.get_ref(2)?.as_str().context("block str")?;
-            let block = serde_json::from_str::<Block>(block_json)?;
-
-            debug!("[{}] hash: {}", height, &hash);
-            {
-                stmts[0].execute(rusqlite::params![height, &hash, block_json])?;
-            }
-
-            for tx in block.tx {
-                let tx_in_addrs = dfiutils::get_txin_addr_val_list(&tx.vin, &sql_store)?;
-                let tx_out_addrs = dfiutils::get_txout_addr_val_list(&tx, &tx.vout);
-
-                let tx_in_addrs = dfiutils::fold_addr_val_map(&tx_in_addrs);
-                let tx_out = dfiutils::fold_addr_val_map(&tx_out_addrs)
-                    .into_iter()
-                    .filter(|x| *x.0 != *"x") // strip coinbase out
-                    .collect::<HashMap<_, _>>();
-
-                let mut tx_type = tx.vm.as_ref().map(|x| TxType::from(&*x.txtype));
-                let mut dvm_addrs = HashSet::new();
-
-                if tx_in_addrs.is_empty() {
-                    tx_type = Some(TxType::Coinbase);
-                }
-
-                if !matches!(
-                    &tx_type,
-                    Some(TxType::Coinbase) | Some(TxType::Unknown) | Some(TxType::Utxo) | None
-                ) {
-                    let dvm_data = tx.vm.as_ref().map(|x| x.msg.to_string()).unwrap();
-                    dvm_addrs = extract_all_dfi_addresses(&dvm_data);
-                }
-                let mut icx_claim_data: Option<IcxTxSet> = None;
-                let mut icx_addr = empty();
-                let mut icx_amt = empty();
-                let mut swap_from = empty();
-                let mut swap_to = empty();
-                let mut swap_amt = empty();
-
-                match tx_type {
-                    Some(TxType::PoolSwap) | Some(TxType::CompositeSwap) => {
-                        let swap_data = &tx.vm.as_ref().ok_or_err()?.msg;
-                        let swap_data: models::PoolSwapMsg =
-                            serde_json::from_value(swap_data.clone())?;
-                        swap_from = token_id_to_symbol_maybe(&swap_data.from_token).to_string();
-                        swap_to = token_id_to_symbol_maybe(&swap_data.to_token).to_string();
-                        swap_amt = format!("{:.9}", &swap_data.from_amount);
-                    }
-                    Some(TxType::ICXClaimDFCHTLC) => {
-                        if let Some(log_entry) = &log_entry_map.data.get(&tx.txid) {
-                            if let Some(icx_data) = &log_entry.icx_data {
-                                icx_claim_data = Some(IcxTxSet {
-                                    order_tx: icx_data.order_tx.clone(),
-                                    claim_tx: icx_data.claim_tx.clone(),
-                                    offer_tx: icx_data.offer_tx.clone(),
-                                    dfchtlc_tx: icx_data.dfchtlc_tx.clone(),
-                                });
-                                icx_addr = icx_data.address.to_string();
-                                icx_amt = icx_data.amount.to_string();
-                            }
-                        }
-                    }
-                    _ => {}
-                }
-
-                let (dvm_in_addrs, _): (Vec<_>, Vec<_>) = dvm_addrs
-                    .iter()
-                    .cloned()
-                    .partition(|addr| tx_in_addrs.iter().any(|(in_addr, _)| *in_addr == *addr));
-
-                if enable_addr_graph {
-                    // DVM addresses are parsed for all matching addresses inside the
-                    // DVM data. There is no clean in and out: this requires specific
-                    // knowledge of each message and there's no clear convention of this.
-                    // So instead, we workaround this as we know that if tx in and dvm addr
-                    // is the same, they were _likely_ source.
-                    // We partition these out first. For out, we take the whole list
-                    // to err on the side of caution to add more edges.
-
-                    let mut changeset = HashMap::<[Rc<str>; 2], i64>::new();
-
-                    for (out_addr, _) in tx_out.iter() {
-                        for (in_addr, _) in tx_in_addrs.iter() {
-                            let k = [in_addr.clone(), (*out_addr).clone()];
-                            changeset.insert(k, 0);
-                        }
-                    }
-
-                    for out_addr in dvm_addrs.iter() {
-                        for in_addr in dvm_in_addrs.iter() {
-                            let k = [in_addr.clone(), out_addr.clone()];
-                            let v = changeset.get_mut(&k);
-                            if let Some(v) = v {
-                                // we set to DVM + UTXO
-                                if *v == 0 {
-                                    *v = 2;
-                                }
-                            } else {
-                                // we set this with DVM only
-                                changeset.insert(k, 1);
-                            }
-                        }
-                    }
-
-                    for ([edge_in, edge_out], c_flags) in &changeset {
-                        stmts[2]
-                            .execute(rusqlite::params![&tx.txid, &edge_in, &edge_out, c_flags])?;
-                    }
+            let block_json = sqlite_json_text(row.get_ref(2)?)?;
+            let res = serde_json::from_str::<Block>(&block_json)
+                .map_err(|e| Error::from(e).at_height(height))
+                .and_then(|block| {
+                    index_block(
+                        &ctx,
+                        &sql_store,
+                        &sql_store_dest,
+                        height,
+                        &block_json,
+                        block,
+                    )
+                });
+            let tx_count = match res {
+                Ok(n) => n,
+                Err(e) if args.skip_bad_blocks => {
+                    error!(height, error = %e, "[{}] skipping bad block: {}: {}", height, e, block_json);
+                    skipped += 1;
+                    sql_store_dest.delete_height(height)?;
+                    return Ok(());
                 }
-
-                // Transform to final strings. Mostly empty strings for non relevant fields
-
-                let tx_type_str = tx_type.clone().unwrap_or(TxType::Unknown).to_string();
-                let dvm_in_addrs_json = if dvm_in_addrs.is_empty() {
-                    empty()
-                } else {
-                    serde_json::to_string(&dvm_in_addrs)?
-                };
-                let dvm_addrs_json = if dvm_addrs.is_empty() {
-                    empty()
-                } else {
-                    serde_json::to_string(&dvm_addrs)?
-                };
-                let tx_in_json = if tx_in_addrs.is_empty() {
-                    empty()
-                } else {
-                    serde_json::to_string(&tx_in_addrs)?
-                };
-                let tx_out_json = if tx_out.is_empty() {
-                    empty()
-                } else {
-                    serde_json::to_string(&tx_out)?
-                };
-                let tx_json = serde_json::to_string(&tx)?;
-                let icx_claim_data = if icx_claim_data.is_none() {
-                    empty()
-                } else {
-                    serde_json::to_string(&icx_claim_data.unwrap())?
-                };
-
-                stmts[1].execute(rusqlite::params![
-                    &tx.txid,
-                    height,
-                    &tx_type_str,
-                    &tx_in_json,
-                    &tx_out_json,
-                    &dvm_in_addrs_json,
-                    &dvm_addrs_json,
-                    &tx_json,
-                    &icx_claim_data,
-                    &icx_addr,
-                    &icx_amt,
-                    &swap_from,
-                    &swap_to,
-                    &swap_amt,
-                ])?;
-            }
-
-            if height % 10000 == 0 {
-                sqlite_commit_and_begin_tx(sconn)?;
-                info!("processed: [{}] / [{}]", height, end_height);
+                Err(e) => return Err(e),
+            };
+            progress.add_block(height, tx_count);
+
+            if commit_interval > 0 && height % commit_interval == 0 {
+                sql_store_dest.commit()?;
+                sql_store_dest.begin()?;
+                progress.log(height);
             }
 
             Ok(())
@@ -291,7 +245,18 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
     );
 
     info!("flushing db");
-    sqlite_commit_tx(sconn)?;
+    sql_store_dest.commit()?;
+    record_run_end(&sql_store_dest)?;
+    ctx.log_tx_type_tally();
+    ctx.log_unknown_tx_types();
+    ctx.utxo_cache.log_stats();
+
+    if args.skip_bad_blocks {
+        match skipped {
+            0 => info!("bad blocks skipped: 0"),
+            n => warn!("bad blocks skipped: {}", n),
+        }
+    }
 
     if res.is_ok() {
         for (name, indexer) in sqlite_create_index_factory_v2(sconn) {
@@ -299,16 +264,18 @@ pub fn run(args: &SqliteIndexArgs) -> Result<()> {
                 info!("int: early exit indexes");
                 break;
             }
+            if sqlite_index_exists(sconn, name)? {
+                info!("index: {}: skipping, exists", name);
+                continue;
+            }
             info!("creating index: {}..", name);
             indexer()?;
         }
+        if args.optimize {
+            sqlite_optimize(sconn, args.vacuum, &quit)?;
+        }
         info!("done");
     }
 
     res
 }
-
-// Just a short convenience alias for internal use.
-fn empty() -> String {
-    String::new()
-}
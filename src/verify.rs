@@ -0,0 +1,271 @@
+//! Consistency checks over an indexed db, to know whether it can be
+//! trusted after an interrupted run.
+
+use crate::db::{
+    BlockStore, META_CREATED_AT, META_NETWORK, META_RUN_FINISHED_AT, META_RUN_STARTED_AT,
+    META_SCHEMA_VERSION, META_STRIDE,
+};
+use crate::dfiutils::CliDriver;
+use crate::lang::Result;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+/// Check that there are no missing heights between the lowest and the
+/// highest stored block, and that every tx belongs to a stored block.
+/// With `cli`, the stored block hashes are also checked against the
+/// node's. Problems are all logged, and reported as an error at the end.
+pub fn verify_store(
+    store: &impl BlockStore,
+    cli: Option<&mut CliDriver>,
+    quit: &AtomicBool,
+) -> Result<()> {
+    let Some((min, max)) = store.get_height_range()? else {
+        info!("verify: no blocks stored, nothing to check");
+        return Ok(());
+    };
+    info!("verify: stored heights [{}, {}]", min, max);
+    let mut issues = 0;
+
+    let gaps = missing_heights(store, true)?;
+    match gaps.first() {
+        None => info!("verify: gaps: none"),
+        Some((from, to)) => {
+            let missing = gaps.iter().map(|(a, b)| b - a + 1).sum::<i64>();
+            warn!(
+                "verify: gaps: {} heights missing in {} ranges, first at [{}, {}]",
+                missing,
+                gaps.len(),
+                from,
+                to
+            );
+            issues += 1;
+        }
+    }
+
+    let orphans = store.get_orphan_tx_heights()?;
+    match orphans.first() {
+        None => info!("verify: txs without a block: none"),
+        Some((height, _)) => {
+            let txs = orphans.iter().map(|(_, n)| n).sum::<i64>();
+            warn!(
+                "verify: txs without a block: {} at {} heights, first at [{}]",
+                txs,
+                orphans.len(),
+                height
+            );
+            issues += 1;
+        }
+    }
+
+    if let Some(cli) = cli {
+        let (checked, mismatched) = verify_hashes(store, cli, min, max, quit)?;
+        match mismatched.first() {
+            None => info!("verify: hashes: {} checked, all match the node", checked),
+            Some(height) => {
+                warn!(
+                    "verify: hashes: {} of {} checked differ from the node, first at [{}]",
+                    mismatched.len(),
+                    checked,
+                    height
+                );
+                issues += 1;
+            }
+        }
+    }
+
+    match issues {
+        0 => {
+            info!("verify: ok");
+            Ok(())
+        }
+        n => Err(format!("verify: {} checks failed", n).into()),
+    }
+}
+
+/// A quick look at the db for monitoring: the stored height range, the
+/// rows per table, when it was last indexed and whether the heights are
+/// contiguous. Only the cheap checks of `verify_store`, so it's fit for
+/// a healthcheck. Gaps or txs without a block are an error.
+pub fn write_status(store: &impl BlockStore, out: &mut impl Write) -> Result<()> {
+    let range = store.get_height_range()?;
+    match range {
+        Some((min, max)) => writeln!(out, "heights: [{}, {}]", min, max)?,
+        None => writeln!(out, "heights: none")?,
+    }
+    for (table, n) in store.get_table_counts()? {
+        writeln!(out, "rows: {}: {}", table, n)?;
+    }
+    let meta = store.get_meta()?;
+    for key in [
+        META_NETWORK,
+        META_SCHEMA_VERSION,
+        META_CREATED_AT,
+        META_RUN_STARTED_AT,
+        META_RUN_FINISHED_AT,
+        META_STRIDE,
+    ] {
+        let value = meta.get(key).map(String::as_str).unwrap_or("-");
+        writeln!(out, "meta: {}: {}", key, value)?;
+    }
+
+    let missing = missing_heights(store, false)?
+        .iter()
+        .map(|(a, b)| b - a + 1)
+        .sum::<i64>();
+    let orphans = store
+        .get_orphan_tx_heights()?
+        .iter()
+        .map(|(_, n)| n)
+        .sum::<i64>();
+    writeln!(out, "missing heights: {}", missing)?;
+    writeln!(out, "txs without a block: {}", orphans)?;
+    let contiguous = missing == 0 && orphans == 0;
+    writeln!(out, "contiguous: {}", contiguous)?;
+    match contiguous {
+        true => Ok(()),
+        false => Err("status: db isn't contiguous".into()),
+    }
+}
+
+/// The gaps in the stored heights. For a sample, see `--stride`, only
+/// the ones that aren't the heights between two samples.
+fn missing_heights(store: &impl BlockStore, log: bool) -> Result<Vec<(i64, i64)>> {
+    let gaps = store.get_height_gaps()?;
+    let stride = match store.get_meta()?.get(META_STRIDE) {
+        Some(v) => v
+            .parse::<i64>()
+            .map_err(|e| format!("meta: stride: {}", e))?,
+        None => return Ok(gaps),
+    };
+    if log {
+        info!(
+            "verify: sample of every {} heights, gaps between them are expected",
+            stride
+        );
+    }
+    Ok(gaps
+        .into_iter()
+        .filter(|(a, b)| b - a + 1 != stride - 1)
+        .collect())
+}
+
+/// Returns the number of heights checked, and the ones where the stored
+/// hash isn't what the node has.
+fn verify_hashes(
+    store: &impl BlockStore,
+    cli: &mut CliDriver,
+    min: i64,
+    max: i64,
+    quit: &AtomicBool,
+) -> Result<(usize, Vec<i64>)> {
+    let mut checked = 0;
+    let mut mismatched = Vec::new();
+    for height in min..=max {
+        if quit.load(Ordering::Relaxed) {
+            info!("int: early exit verify");
+            break;
+        }
+        // Gaps are already reported.
+        let Some(stored) = store.get_block_hash(height)? else {
+            continue;
+        };
+        if *cli.get_block_hash(height)? != *stored {
+            warn!(
+                "verify: [{}] stored hash {} isn't on the chain",
+                height, stored
+            );
+            mismatched.push(height);
+        }
+        checked += 1;
+        if checked % 100_000 == 0 {
+            info!("verify: hashes: checked up to [{}]", height);
+        }
+    }
+    Ok((checked, mismatched))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{SqliteBlockStore, TxRecord};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_verify_store() {
+        let store = SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let quit = AtomicBool::new(false);
+        assert!(verify_store(&store, None, &quit).is_ok());
+
+        for h in [1, 2, 5, 6, 9] {
+            store.put_block(h, &format!("h{}", h), "{}").unwrap();
+        }
+        let tx = TxRecord {
+            txid: "t1",
+            height: 3,
+            ..Default::default()
+        };
+        store.put_tx(&tx).unwrap();
+
+        assert_eq!(store.get_height_range().unwrap(), Some((1, 9)));
+        assert_eq!(store.get_height_gaps().unwrap(), vec![(3, 4), (7, 8)]);
+        assert_eq!(store.get_orphan_tx_heights().unwrap(), vec![(3, 1)]);
+        assert!(verify_store(&store, None, &quit).is_err());
+
+        for h in [3, 4, 7, 8] {
+            store.put_block(h, &format!("h{}", h), "{}").unwrap();
+        }
+        assert!(store.get_height_gaps().unwrap().is_empty());
+        assert!(store.get_orphan_tx_heights().unwrap().is_empty());
+        assert!(verify_store(&store, None, &quit).is_ok());
+    }
+
+    #[test]
+    fn test_verify_store_sample() {
+        let store = SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let quit = AtomicBool::new(false);
+        let meta = HashMap::from([(META_STRIDE.to_owned(), "3".to_owned())]);
+        store.put_meta(&meta).unwrap();
+        for h in [1, 4, 7] {
+            store.put_block(h, &format!("h{}", h), "{}").unwrap();
+        }
+        assert!(verify_store(&store, None, &quit).is_ok());
+        let mut out = Vec::new();
+        assert!(write_status(&store, &mut out).is_ok());
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains("meta: stride: 3\n"));
+
+        // A sample missed.
+        store.put_block(13, "h13", "{}").unwrap();
+        assert!(verify_store(&store, None, &quit).is_err());
+    }
+
+    #[test]
+    fn test_write_status() {
+        let store = SqliteBlockStore::new_v2(Some(":memory:")).unwrap();
+        let status = |store: &SqliteBlockStore| {
+            let mut out = Vec::new();
+            let res = write_status(store, &mut out);
+            (res.is_ok(), String::from_utf8(out).unwrap())
+        };
+        let (ok, out) = status(&store);
+        assert!(ok);
+        assert!(out.contains("heights: none\n"));
+
+        for h in [1, 2, 4] {
+            store.put_block(h, &format!("h{}", h), "{}").unwrap();
+        }
+        let (ok, out) = status(&store);
+        assert!(!ok);
+        assert!(out.contains("heights: [1, 4]\n"));
+        assert!(out.contains("rows: blocks: 3\n"));
+        assert!(out.contains("missing heights: 1\n"));
+        assert!(out.contains("contiguous: false\n"));
+
+        store.put_block(3, "h3", "{}").unwrap();
+        let (ok, out) = status(&store);
+        assert!(ok);
+        assert!(out.contains("contiguous: true\n"));
+    }
+}
@@ -0,0 +1,124 @@
+//! Deserialization of real block shapes into the models, against the
+//! fixtures in `tests/fixtures`.
+
+#[path = "../src/models.rs"]
+mod models;
+
+use models::{AccountToAccountMsg, Block, PoolSwapMsg, TxType, Vin};
+
+fn load_block(json: &str) -> Block {
+    serde_json::from_str(json).unwrap()
+}
+
+fn tx_type(block: &Block, idx: usize) -> TxType {
+    let vm = block.tx[idx].vm.as_ref().unwrap();
+    TxType::from(&*vm.txtype)
+}
+
+#[test]
+fn test_block_coinbase() {
+    let block = load_block(include_str!("fixtures/block_coinbase.json"));
+    assert_eq!(block.height, 894001);
+    assert_eq!(block.n_tx, 1);
+    assert_eq!(block.tx.len(), 1);
+    assert!(block.previousblockhash.is_some());
+
+    let cb = &block.tx[0];
+    assert!(matches!(cb.vin[..], [Vin::Coinbase(_)]));
+    assert!(cb.vm.is_none());
+    assert_eq!(cb.vout[0].value, 20.2);
+    assert_eq!(
+        cb.vout[0].script_pub_key.addresses.as_deref().unwrap()[0].as_ref(),
+        "8KRsoeCRKHUFFmAGGJbRBAgraXiUPUVuXn"
+    );
+    // Witness commitment, no address
+    assert!(cb.vout[1].script_pub_key.addresses.is_none());
+}
+
+#[test]
+fn test_block_poolswap() {
+    let block = load_block(include_str!("fixtures/block_poolswap.json"));
+    assert_eq!(block.tx.len(), 2);
+    assert_eq!(tx_type(&block, 1), TxType::PoolSwap);
+
+    let tx = &block.tx[1];
+    match &tx.vin[..] {
+        [Vin::Standard(vin)] => assert_eq!(vin.vout, 1),
+        _ => panic!("expected a single standard vin"),
+    }
+    let msg: PoolSwapMsg = serde_json::from_str(tx.vm.as_ref().unwrap().msg.get()).unwrap();
+    assert_eq!(
+        msg.from_address.as_ref(),
+        "8J6KKxHQAWDJDR1PQfC46ocgmxTvtLLc6R"
+    );
+    assert_eq!(
+        msg.to_address.as_ref(),
+        "8eG9Pe1wQnWZuXD5NRr3QaxDex9RJ99fd5"
+    );
+    assert_eq!(msg.from_token.as_ref(), "0");
+    assert_eq!(msg.to_token.as_ref(), "2");
+    assert_eq!(msg.from_amount.to_string(), "9.00000000");
+    assert!(msg.pools.is_empty());
+}
+
+#[test]
+fn test_block_icx_claim() {
+    let block = load_block(include_str!("fixtures/block_icx_claim.json"));
+    let t = tx_type(&block, 1);
+    assert_eq!(t, TxType::ICXClaimDFCHTLC);
+    assert_eq!(t.to_string(), "icx-claim");
+}
+
+#[test]
+fn test_block_account_to_account() {
+    let block = load_block(include_str!("fixtures/block_account_to_account.json"));
+    assert_eq!(tx_type(&block, 1), TxType::AccountToAccount);
+
+    let msg = &block.tx[1].vm.as_ref().unwrap().msg;
+    let msg: AccountToAccountMsg = serde_json::from_str(msg.get()).unwrap();
+    assert_eq!(msg.from.as_ref(), "dK13qHWrbSdtFkxnfg3UVEvNrsxa9i45pd");
+    assert_eq!(msg.to.len(), 2);
+    assert_eq!(
+        msg.to["dc432ofNoMBg3Y6eubzx5dS1iRLMKXsBWE"].as_ref(),
+        "2.00000000@128"
+    );
+    assert_eq!(
+        msg.to["df1qqvaqshw0hrjzakxms27xrk6npfef4sx6cqaejv"].as_ref(),
+        "0.50000000@0"
+    );
+}
+
+#[test]
+fn test_tx_type_display_round_trip() {
+    let fixtures = [
+        include_str!("fixtures/block_poolswap.json"),
+        include_str!("fixtures/block_icx_claim.json"),
+        include_str!("fixtures/block_account_to_account.json"),
+    ];
+    for json in fixtures {
+        let t = tx_type(&load_block(json), 1);
+        assert!(!matches!(t, TxType::Other(_)));
+        assert_eq!(TxType::from_display(&t.to_string()), t);
+    }
+}
+
+// Amounts come in as json floats, these have to land on the exact
+// 8 decimal value defid printed.
+#[test]
+fn test_poolswap_amounts() {
+    let cases: Vec<serde_json::Value> =
+        serde_json::from_str(include_str!("fixtures/poolswap_amounts.json")).unwrap();
+    for case in cases {
+        let msg = serde_json::json!({
+            "fromAddress": "a", "toAddress": "b", "fromToken": "0", "toToken": "2",
+            "fromAmount": case["fromAmount"],
+        });
+        let msg: PoolSwapMsg = serde_json::from_value(msg).unwrap();
+        assert_eq!(
+            msg.from_amount.to_string(),
+            case["expected"].as_str().unwrap(),
+            "fromAmount: {}",
+            case["fromAmount"]
+        );
+    }
+}
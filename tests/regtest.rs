@@ -0,0 +1,228 @@
+//! End to end run of the indexer against a defid in regtest: a handful
+//! of blocks with a poolswap and an account transfer are generated, then
+//! indexed over rpc into a sqlite db that's checked for their rows.
+//!
+//! Needs a defid binary, so it's ignored by default:
+//!
+//! ```sh
+//! DEFID_PATH=/path/to/defid cargo test --test regtest -- --ignored
+//! ```
+
+use chain_analyzer::cliindexer::{self, CliIndexArgs};
+use chain_analyzer::models::TxType;
+use chain_analyzer::query;
+use clap::Parser;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+// The first of the genesis masternodes of regtest. Its owner is a
+// foundation member, so it can create tokens and pools, and blocks are
+// minted to its operator with `-dummypos`.
+const OWNER_ADDR: &str = "mwsZw8nF7pKxWH8eoKL9tPxTpaFkz7QeLU";
+const OWNER_KEY: &str = "cRiRQ9cHmy5evDqNDdEV8f6zfbK6epi3e4rCaHXAB5LFA2aHXFnf";
+const OPERATOR_ADDR: &str = "mswsMVsyGMj1FzDMbbxw2QW3KvQAv2FKiy";
+const OPERATOR_KEY: &str = "cSCmN1tjcR2yR1eaQo9WmjTMR85SjEoNPqMPWGAApQiTLJH8JF7W";
+
+const RPC_USER: &str = "dindexer";
+const RPC_PASSWORD: &str = "dindexer";
+
+/// A defid in regtest on a fresh datadir, stopped when dropped.
+struct Regtest {
+    child: Child,
+    datadir: PathBuf,
+    url: String,
+    client: reqwest::blocking::Client,
+}
+
+impl Regtest {
+    fn start(defid: &str) -> Self {
+        let datadir = std::env::temp_dir().join("dindexer-test-regtest");
+        let _ = std::fs::remove_dir_all(&datadir);
+        std::fs::create_dir_all(&datadir).unwrap();
+        // Free for now, defid takes it over below.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let child = Command::new(defid)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-rpcport={}", port))
+            .arg(format!("-rpcuser={}", RPC_USER))
+            .arg(format!("-rpcpassword={}", RPC_PASSWORD))
+            .args(["-listen=0", "-txindex=1", "-dummypos=1", "-txnotokens=0"])
+            .args([
+                "-amkheight=50",
+                "-bayfrontheight=51",
+                "-bayfrontgardensheight=52",
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap_or_else(|e| panic!("defid: {}: {}", defid, e));
+        let node = Regtest {
+            child,
+            datadir,
+            url: format!("http://127.0.0.1:{}", port),
+            client: reqwest::blocking::Client::new(),
+        };
+        node.wait_ready();
+        node
+    }
+
+    fn wait_ready(&self) {
+        let start = Instant::now();
+        while self.try_rpc("getblockcount", json!([])).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(60),
+                "defid didn't come up"
+            );
+            std::thread::sleep(Duration::from_millis(250));
+        }
+    }
+
+    fn try_rpc(&self, method: &str, params: Value) -> Result<Value, String> {
+        let body = json!({ "jsonrpc": "1.0", "id": "t", "method": method, "params": params });
+        let res = self
+            .client
+            .post(&self.url)
+            .basic_auth(RPC_USER, Some(RPC_PASSWORD))
+            .body(body.to_string())
+            .send()
+            .and_then(|x| x.text())
+            .map_err(|e| e.to_string())?;
+        let mut res: Value = serde_json::from_str(&res).map_err(|e| format!("{}: {}", e, res))?;
+        match res["error"].take() {
+            Value::Null => Ok(res["result"].take()),
+            e => Err(e.to_string()),
+        }
+    }
+
+    fn rpc(&self, method: &str, params: Value) -> Value {
+        self.try_rpc(method, params)
+            .unwrap_or_else(|e| panic!("{}: {}", method, e))
+    }
+
+    fn generate(&self, n: u64) {
+        self.rpc("generatetoaddress", json!([n, OPERATOR_ADDR]));
+    }
+
+    /// Send a tx with `method`, and mine it.
+    fn send(&self, method: &str, params: Value) -> String {
+        let txid = self.rpc(method, params);
+        self.generate(1);
+        txid.as_str().unwrap().to_owned()
+    }
+}
+
+impl Drop for Regtest {
+    fn drop(&mut self) {
+        let _ = self.try_rpc("stop", json!([]));
+        let start = Instant::now();
+        while let Ok(None) = self.child.try_wait() {
+            if start.elapsed() > Duration::from_secs(30) {
+                let _ = self.child.kill();
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(250));
+        }
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.datadir);
+    }
+}
+
+#[test]
+#[ignore = "needs a defid binary in DEFID_PATH"]
+fn test_regtest_index() {
+    let defid = std::env::var("DEFID_PATH").expect("DEFID_PATH to a defid binary");
+    let node = Regtest::start(&defid);
+    node.rpc("importprivkey", json!([OWNER_KEY]));
+    node.rpc("importprivkey", json!([OPERATOR_KEY]));
+    // The coinbase outs need 100 blocks before they can be spent, which
+    // is also past the forks with the tokens and pools.
+    node.generate(110);
+
+    let token = json!({
+        "symbol": "BTC", "name": "BTC", "isDAT": true,
+        "collateralAddress": OWNER_ADDR,
+    });
+    node.send("createtoken", json!([token]));
+    node.send("minttokens", json!(["100@BTC"]));
+    let utxos_to_account = node.send("utxostoaccount", json!([{ OWNER_ADDR: "100@DFI" }]));
+    let pool = json!({
+        "tokenA": "BTC", "tokenB": "DFI", "commission": 0.001,
+        "status": true, "ownerAddress": OWNER_ADDR,
+    });
+    node.send("createpoolpair", json!([pool]));
+    node.send(
+        "addpoolliquidity",
+        json!([{ OWNER_ADDR: ["10@BTC", "10@DFI"] }, OWNER_ADDR]),
+    );
+    let swap = json!({
+        "from": OWNER_ADDR, "tokenFrom": "BTC", "amountFrom": 1,
+        "to": OWNER_ADDR, "tokenTo": "DFI",
+    });
+    let poolswap = node.send("poolswap", json!([swap]));
+    let to_addr = node.rpc("getnewaddress", json!(["", "legacy"]));
+    let to_addr = to_addr.as_str().unwrap();
+    let account_to_account = node.send(
+        "accounttoaccount",
+        json!([OWNER_ADDR, { to_addr: "1@DFI" }]),
+    );
+    let tip = node.rpc("getblockcount", json!([])).as_i64().unwrap();
+
+    let db_path = std::env::temp_dir().join("dindexer-test-regtest.sqlite");
+    let _ = std::fs::remove_file(&db_path);
+    let db_path = db_path.to_str().unwrap();
+    let tip_arg = tip.to_string();
+    let args = CliIndexArgs::try_parse_from([
+        "cindex",
+        "--driver=rpc",
+        "--rpc-url",
+        &node.url,
+        "--rpc-user",
+        RPC_USER,
+        "--rpc-password",
+        RPC_PASSWORD,
+        "--sqlite-path",
+        db_path,
+        "--defid-log-path=",
+        "--network=regtest",
+        "--enable-address-table",
+        "--end-height",
+        &tip_arg,
+    ])
+    .unwrap();
+    cliindexer::run(&args).unwrap();
+
+    let store = query::open(db_path).unwrap();
+    for height in [0, tip] {
+        let block = query::get_block(&store, height).unwrap().unwrap();
+        assert_eq!(block.height as i64, height);
+    }
+    let tx = query::get_tx(&store, &utxos_to_account).unwrap().unwrap();
+    assert_eq!(tx.tx_type, TxType::UtxosToAccount.to_string());
+    let tx = query::get_tx(&store, &poolswap).unwrap().unwrap();
+    assert_eq!(tx.tx_type, TxType::PoolSwap.to_string());
+    assert_eq!((&*tx.swap_from, &*tx.swap_to), ("BTC", "DFI"));
+    assert_eq!(tx.swap_amt.parse::<f64>().unwrap(), 1.0);
+    let tx = query::get_tx(&store, &account_to_account).unwrap().unwrap();
+    assert_eq!(tx.tx_type, TxType::AccountToAccount.to_string());
+    assert_eq!(tx.transfer_from, OWNER_ADDR);
+    assert!(tx.transfer_to.contains_key(to_addr));
+
+    let edges: i64 = store
+        .conn
+        .query_row(
+            "SELECT count(*) FROM tx_addr_graph WHERE txid = ?1",
+            [&account_to_account],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert!(edges > 0);
+    let rows = store.get_txs_by_address(to_addr).unwrap();
+    assert!(rows.iter().any(|x| x.txid == account_to_account));
+}